@@ -0,0 +1,434 @@
+//! Encoding of ACPI Machine Language for the DSDT.
+//!
+//! Each function returns the encoding of a single term. Terms that contain
+//! others (scopes, devices, methods, ...) are given the already encoded
+//! terms of their body.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const LOCAL0_OP: u8 = 0x60;
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const ADD_OP: u8 = 0x72;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const OR_OP: u8 = 0x7d;
+const NOTIFY_OP: u8 = 0x86;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8f;
+const LEQUAL_OP: u8 = 0x93;
+const LLESS_OP: u8 = 0x95;
+const IF_OP: u8 = 0xa0;
+const WHILE_OP: u8 = 0xa2;
+const RETURN_OP: u8 = 0xa4;
+const ONES_OP: u8 = 0xff;
+
+// Following EXT_OP_PREFIX.
+const MUTEX_OP: u8 = 0x01;
+const ACQUIRE_OP: u8 = 0x23;
+const RELEASE_OP: u8 = 0x27;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+
+/// Operation region address space.
+pub const SYSTEM_IO: u8 = 1;
+
+/// Field access types.
+pub const BYTE_ACC: u8 = 1;
+pub const DWORD_ACC: u8 = 3;
+/// Field update rules, combined with the access type.
+pub const PRESERVE: u8 = 0;
+pub const WRITE_AS_ZEROS: u8 = 2 << 5;
+
+/// The value notified to a device that should be checked for insertion or
+/// removal.
+pub const NOTIFY_DEVICE_CHECK: u64 = 1;
+
+const IO_PORT_DESC: u8 = 0x47;
+const IO_DECODE_16: u8 = 1;
+const END_TAG: u8 = 0x79;
+const EXTENDED_INTERRUPT_DESC: u8 = 0x89;
+const INTERRUPT_CONSUMER: u8 = 1 << 0;
+const INTERRUPT_EDGE: u8 = 1 << 1;
+const QWORD_ADDRESS_DESC: u8 = 0x8a;
+const ADDRESS_MEMORY: u8 = 0;
+const ADDRESS_MIN_FIXED: u8 = 1 << 2;
+const ADDRESS_MAX_FIXED: u8 = 1 << 3;
+const MEMORY_READ_WRITE: u8 = 1 << 0;
+const MEMORY_CACHEABLE: u8 = 1 << 1;
+
+/// Byte offsets of the fields of a QWord address space descriptor.
+pub const QWORD_MIN_OFFSET: u64 = 14;
+pub const QWORD_MAX_OFFSET: u64 = 22;
+pub const QWORD_LEN_OFFSET: u64 = 38;
+
+/// An entry in the field list of `field`.
+pub enum FieldUnit<'a> {
+    /// A named field of the given number of bits.
+    Named(&'a str, usize),
+    /// Skip the given number of bits.
+    Reserved(usize),
+}
+
+/// The length of a package whose contents are `len` bytes, as it precedes
+/// the contents.
+fn pkg_length(len: usize) -> Vec<u8> {
+    if len + 1 < 1 << 6 {
+        return vec![(len + 1) as u8];
+    }
+    // Each following byte adds 8 bits to the 4 in the lead byte.
+    let n = (2..=4)
+        .find(|n| len + n < 1 << (4 + 8 * (n - 1)))
+        .expect("package too large");
+    encoded_length(len + n, n)
+}
+
+/// A length encoded in `n` bytes as in a PkgLength, without counting the
+/// encoding itself.
+fn encoded_length(len: usize, n: usize) -> Vec<u8> {
+    let mut bytes = vec![((n - 1) << 6) as u8 | (len & 0xf) as u8];
+    bytes.extend((0..n - 1).map(|i| (len >> (4 + 8 * i)) as u8));
+    bytes
+}
+
+/// The bit length of a field unit, which is encoded like a PkgLength but
+/// doesn't count itself.
+fn field_length(bits: usize) -> Vec<u8> {
+    if bits < 1 << 6 {
+        return vec![bits as u8];
+    }
+    let n = (2..=4)
+        .find(|n| bits < 1 << (4 + 8 * (n - 1)))
+        .expect("field too large");
+    encoded_length(bits, n)
+}
+
+/// Prefix `op` and the package length to the concatenated `terms`.
+fn package(op: &[u8], terms: &[Vec<u8>]) -> Vec<u8> {
+    let contents = terms.concat();
+    let mut bytes = op.to_vec();
+    bytes.extend(pkg_length(contents.len()));
+    bytes.extend(contents);
+    bytes
+}
+
+/// Encode a name such as `MSEL`, `\_SB.MHPC` or `^MP00`. Name segments
+/// shorter than four characters are padded with underscores.
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let prefixes = path.len() - path.trim_start_matches(&['\\', '^'][..]).len();
+    bytes.extend_from_slice(&path.as_bytes()[..prefixes]);
+    let segs: Vec<&str> = path[prefixes..]
+        .split('.')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match segs.len() {
+        0 => bytes.push(ZERO_OP),
+        1 => (),
+        2 => bytes.push(DUAL_NAME_PREFIX),
+        n => bytes.extend_from_slice(&[MULTI_NAME_PREFIX, n as u8]),
+    }
+    for seg in segs {
+        assert!(seg.len() <= 4, "invalid name segment {}", seg);
+        bytes.extend_from_slice(seg.as_bytes());
+        bytes.resize(bytes.len() + 4 - seg.len(), b'_');
+    }
+    bytes
+}
+
+/// An integer, using the shortest encoding.
+pub fn int(val: u64) -> Vec<u8> {
+    match val {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        0xffff_ffff_ffff_ffff => vec![ONES_OP],
+        v if v <= 0xff => vec![BYTE_PREFIX, v as u8],
+        v if v <= 0xffff => {
+            let mut bytes = vec![WORD_PREFIX];
+            bytes.extend_from_slice(&(v as u16).to_le_bytes());
+            bytes
+        }
+        v if v <= 0xffff_ffff => {
+            let mut bytes = vec![DWORD_PREFIX];
+            bytes.extend_from_slice(&(v as u32).to_le_bytes());
+            bytes
+        }
+        v => {
+            let mut bytes = vec![QWORD_PREFIX];
+            bytes.extend_from_slice(&v.to_le_bytes());
+            bytes
+        }
+    }
+}
+
+pub fn string(s: &str) -> Vec<u8> {
+    let mut bytes = vec![STRING_PREFIX];
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// A compressed EISA id such as `PNP0C80`, as produced by ASL's `EISAID`.
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let id = id.as_bytes();
+    assert_eq!(7, id.len(), "invalid EISA id");
+    let letter = |c: u8| u32::from(c - b'@') & 0x1f;
+    let product = std::str::from_utf8(&id[3..])
+        .ok()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .expect("invalid EISA id");
+    let id = letter(id[0]) << 26 | letter(id[1]) << 21 | letter(id[2]) << 16 | product;
+    int(u64::from(id.swap_bytes()))
+}
+
+pub fn local(n: u8) -> Vec<u8> {
+    vec![LOCAL0_OP + n]
+}
+
+pub fn arg(n: u8) -> Vec<u8> {
+    vec![ARG0_OP + n]
+}
+
+pub fn name(path: &str, value: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![NAME_OP];
+    bytes.extend(name_string(path));
+    bytes.extend(value);
+    bytes
+}
+
+pub fn scope(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = vec![name_string(path)];
+    contents.extend_from_slice(terms);
+    package(&[SCOPE_OP], &contents)
+}
+
+pub fn device(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = vec![name_string(path)];
+    contents.extend_from_slice(terms);
+    package(&[EXT_OP_PREFIX, DEVICE_OP], &contents)
+}
+
+/// A method taking `args` arguments. Serialized methods may create named
+/// objects, as only one call runs at a time.
+pub fn method(path: &str, args: u8, serialized: bool, terms: &[Vec<u8>]) -> Vec<u8> {
+    let flags = args | if serialized { 1 << 3 } else { 0 };
+    let mut contents = vec![name_string(path), vec![flags]];
+    contents.extend_from_slice(terms);
+    package(&[METHOD_OP], &contents)
+}
+
+/// Call the method at `path`, which must take exactly the given arguments.
+pub fn call(path: &str, args: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = name_string(path);
+    bytes.extend(args.concat());
+    bytes
+}
+
+pub fn op_region(path: &str, space: u8, offset: u64, len: u64) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, OP_REGION_OP];
+    bytes.extend(name_string(path));
+    bytes.push(space);
+    bytes.extend(int(offset));
+    bytes.extend(int(len));
+    bytes
+}
+
+/// Fields of the operation region at `region`, starting from its beginning.
+/// `flags` is an access type combined with an update rule.
+pub fn field(region: &str, flags: u8, units: &[FieldUnit]) -> Vec<u8> {
+    let mut contents = vec![name_string(region), vec![flags]];
+    for unit in units {
+        let mut bytes = Vec::new();
+        match unit {
+            FieldUnit::Named(name, bits) => {
+                bytes.extend(name_string(name));
+                bytes.extend(field_length(*bits));
+            }
+            FieldUnit::Reserved(bits) => {
+                bytes.push(0);
+                bytes.extend(field_length(*bits));
+            }
+        }
+        contents.push(bytes);
+    }
+    package(&[EXT_OP_PREFIX, FIELD_OP], &contents)
+}
+
+pub fn mutex(path: &str, sync_level: u8) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, MUTEX_OP];
+    bytes.extend(name_string(path));
+    bytes.push(sync_level);
+    bytes
+}
+
+/// Acquire the mutex at `path`, waiting for as long as it takes.
+pub fn acquire(path: &str) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, ACQUIRE_OP];
+    bytes.extend(name_string(path));
+    bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+    bytes
+}
+
+pub fn release(path: &str) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, RELEASE_OP];
+    bytes.extend(name_string(path));
+    bytes
+}
+
+fn op(op: u8, operands: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = vec![op];
+    bytes.extend(operands.concat());
+    bytes
+}
+
+pub fn store(value: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(STORE_OP, &[value, target])
+}
+
+pub fn add(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(ADD_OP, &[a, b, target])
+}
+
+pub fn subtract(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(SUBTRACT_OP, &[a, b, target])
+}
+
+pub fn shift_left(a: Vec<u8>, count: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(SHIFT_LEFT_OP, &[a, count, target])
+}
+
+pub fn or(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(OR_OP, &[a, b, target])
+}
+
+pub fn increment(target: Vec<u8>) -> Vec<u8> {
+    op(INCREMENT_OP, &[target])
+}
+
+pub fn lequal(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
+    op(LEQUAL_OP, &[a, b])
+}
+
+pub fn lless(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
+    op(LLESS_OP, &[a, b])
+}
+
+pub fn notify(object: Vec<u8>, value: Vec<u8>) -> Vec<u8> {
+    op(NOTIFY_OP, &[object, value])
+}
+
+pub fn ret(value: Vec<u8>) -> Vec<u8> {
+    op(RETURN_OP, &[value])
+}
+
+pub fn if_then(predicate: Vec<u8>, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = vec![predicate];
+    contents.extend_from_slice(terms);
+    package(&[IF_OP], &contents)
+}
+
+pub fn while_loop(predicate: Vec<u8>, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = vec![predicate];
+    contents.extend_from_slice(terms);
+    package(&[WHILE_OP], &contents)
+}
+
+/// Name the 8 bytes at `index` in the buffer `buf`.
+pub fn create_qword_field(buf: Vec<u8>, index: u64, name: &str) -> Vec<u8> {
+    op(CREATE_QWORD_FIELD_OP, &[buf, int(index), name_string(name)])
+}
+
+/// A buffer of resource descriptors, terminated by an end tag.
+pub fn resource_template(descriptors: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = descriptors.concat();
+    // A zero checksum is always accepted.
+    data.extend_from_slice(&[END_TAG, 0]);
+    let contents = [int(data.len() as u64), data];
+    package(&[BUFFER_OP], &contents)
+}
+
+/// A range of io ports decoded with all 16 address bits.
+pub fn io_port(base: u16, len: u8) -> Vec<u8> {
+    let mut bytes = vec![IO_PORT_DESC, IO_DECODE_16];
+    bytes.extend_from_slice(&base.to_le_bytes());
+    bytes.extend_from_slice(&base.to_le_bytes());
+    bytes.push(1); // alignment
+    bytes.push(len);
+    bytes
+}
+
+/// An exclusive, edge triggered, active high interrupt consumed by the
+/// device.
+pub fn interrupt(irq: u32) -> Vec<u8> {
+    let mut bytes = vec![EXTENDED_INTERRUPT_DESC];
+    bytes.extend_from_slice(&6u16.to_le_bytes());
+    bytes.push(INTERRUPT_CONSUMER | INTERRUPT_EDGE);
+    bytes.push(1); // number of interrupts
+    bytes.extend_from_slice(&irq.to_le_bytes());
+    bytes
+}
+
+/// A fixed range of cacheable, read-write memory of `len` bytes at `min`.
+pub fn qword_memory(min: u64, len: u64) -> Vec<u8> {
+    let mut bytes = vec![QWORD_ADDRESS_DESC];
+    bytes.extend_from_slice(&43u16.to_le_bytes());
+    bytes.push(ADDRESS_MEMORY);
+    bytes.push(ADDRESS_MIN_FIXED | ADDRESS_MAX_FIXED);
+    bytes.push(MEMORY_CACHEABLE | MEMORY_READ_WRITE);
+    let max = min.wrapping_add(len).wrapping_sub(1);
+    for val in [0, min, max, 0, len].iter() {
+        // Granularity, minimum, maximum, translation offset and length.
+        bytes.extend_from_slice(&val.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkg_lengths() {
+        assert_eq!(vec![0x3f], pkg_length(0x3e));
+        // 0x3f bytes of contents need a second length byte.
+        assert_eq!(vec![0x41, 0x04], pkg_length(0x3f));
+        assert_eq!(vec![0x4f, 0xff], pkg_length(0xffd));
+        assert_eq!(vec![0x81, 0x00, 0x01], pkg_length(0xffe));
+        assert_eq!(vec![0x20], field_length(32));
+        assert_eq!(vec![0x40, 0x0a], field_length(0xa0));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(b"MSEL".to_vec(), name_string("MSEL"));
+        assert_eq!(b"GED_".to_vec(), name_string("GED"));
+        assert_eq!(b"\\_SB_".to_vec(), name_string("\\_SB"));
+        assert_eq!(b"\\\x2e_SB_MHPC".to_vec(), name_string("\\_SB.MHPC"));
+        assert_eq!(
+            b"\\\x2f\x03_SB_MHPCMSCN".to_vec(),
+            name_string("\\_SB.MHPC.MSCN")
+        );
+        assert_eq!(vec![b'\\', 0], name_string("\\"));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(vec![0x0a, 0x0f], int(0xf));
+        assert_eq!(vec![0x0b, 0x00, 0x0a], int(0xa00));
+        assert_eq!(vec![0x0e, 0, 0, 0, 0, 1, 0, 0, 0], int(1 << 32));
+        assert_eq!(int(0x800c_d041), eisa_id("PNP0C80"));
+    }
+}
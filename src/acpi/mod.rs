@@ -0,0 +1,559 @@
+//! ACPI tables describing the machine to the guest.
+//!
+//! The tables are written to guest memory in the BIOS area below 1MiB, with
+//! the RSDP at `RSDP_ADDR` where the guest's scan for it will find it. The
+//! XSDT points at the remaining tables:
+//!
+//!   FADT    a hardware-reduced ACPI machine, with the sleep registers of
+//!           `device::sleep` and the i8042 reset, and the DSDT
+//!   MADT    the local APIC of each vcpu, and the IOAPIC
+//!
+//! The DSDT declares the S5 sleep state, so the guest can power off, and the
+//! devices of `device::memhp` for memory hotplug. Being hardware-reduced, the
+//! machine signals hotplug through a generic event device (GED) rather than
+//! GPEs.
+
+mod aml;
+
+use crate::device::{i8042, memhp, sleep};
+use crate::memory::{Addressable, Error, MemoryAddr, Result};
+use aml::FieldUnit::{Named, Reserved};
+
+pub const RSDP_ADDR: usize = 0x000e_0000;
+/// The RSDP is followed by the other tables.
+const TABLES_ADDR: usize = RSDP_ADDR + 0x40;
+
+/// The system control interrupt, raised for ACPI events such as memory
+/// hotplug. It's the interrupt of the GED, as nothing else uses the SCI on a
+/// hardware-reduced machine.
+pub const SCI_IRQ: u32 = 9;
+
+const LAPIC_ADDR: u32 = 0xfee0_0000;
+const IOAPIC_ADDR: u32 = 0xfec0_0000;
+
+const OEM_ID: &[u8; 6] = b"SUBMRN";
+const OEM_TABLE_ID: &[u8; 8] = b"SUBMARIN";
+
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_ACCESS_BYTE: u8 = 1;
+
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LAPIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+const LAPIC_ENABLED: u32 = 1 << 0;
+const OVERRIDE_ACTIVE_HIGH: u16 = 0x1;
+const OVERRIDE_EDGE: u16 = 0x1 << 2;
+
+/// A system description table, built up field by field after the standard
+/// header.
+struct Table {
+    bytes: Vec<u8>,
+}
+
+impl Table {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut table = Table { bytes: Vec::new() };
+        table.bytes.extend_from_slice(signature);
+        // Length and checksum are filled in by `finish`.
+        table.u32(0);
+        table.u8(revision);
+        table.u8(0);
+        table.bytes.extend_from_slice(OEM_ID);
+        table.bytes.extend_from_slice(OEM_TABLE_ID);
+        table.u32(1); // OEM revision
+        table.u32(u32::from_le_bytes(*b"SUBM")); // creator id
+        table.u32(1); // creator revision
+        table
+    }
+
+    fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// A generic address structure for a byte wide io port, or zero for no
+    /// register.
+    fn io_port(&mut self, port: u16) {
+        if port == 0 {
+            self.bytes.extend_from_slice(&[0; 12]);
+            return;
+        }
+        self.u8(GAS_SYSTEM_IO);
+        self.u8(8); // bit width
+        self.u8(0); // bit offset
+        self.u8(GAS_ACCESS_BYTE);
+        self.u64(u64::from(port));
+    }
+
+    fn zeros(&mut self, len: usize) {
+        self.bytes.resize(self.bytes.len() + len, 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.bytes.len() as u32;
+        self.bytes[4..8].copy_from_slice(&len.to_le_bytes());
+        self.bytes[9] = checksum(&self.bytes);
+        self.bytes
+    }
+}
+
+/// The byte that makes all bytes of `bytes` sum to zero, given the current
+/// value of that byte is zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+fn dsdt() -> Vec<u8> {
+    let mut t = Table::new(b"DSDT", 2);
+    // Name (_S5, Package () { S5_SLEEP_TYPE, 0 })
+    t.bytes
+        .extend_from_slice(&[0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0a]);
+    t.u8(sleep::S5_SLEEP_TYPE);
+    t.u8(0x00);
+    t.bytes
+        .extend(aml::scope("\\_SB", &[memory_hotplug(), ged()]));
+    t.finish()
+}
+
+/// The memory hotplug controller, and a PNP0C80 memory device for each of
+/// its slots. Scanning the controller notifies the devices of any memory
+/// plugged into their slots.
+fn memory_hotplug() -> Vec<u8> {
+    use aml::*;
+
+    let base = memhp::REGISTER_BASE as u64;
+    let len = memhp::REGISTER_LEN as u64;
+    let slots = memhp::MAX_DEVICES as u64;
+    let mut terms = vec![
+        name("_HID", eisa_id("PNP0A06")),
+        name("_UID", string("Memory hotplug resources")),
+        name(
+            "_CRS",
+            resource_template(&[io_port(base as u16, len as u8)]),
+        ),
+        op_region("HPMR", SYSTEM_IO, base, len),
+        // The selected slot's registers.
+        field(
+            "HPMR",
+            DWORD_ACC | PRESERVE,
+            &[
+                Named("MRBL", 32),
+                Named("MRBH", 32),
+                Named("MRLL", 32),
+                Named("MRLH", 32),
+            ],
+        ),
+        field(
+            "HPMR",
+            BYTE_ACC | WRITE_AS_ZEROS,
+            &[Reserved(0x14 * 8), Named("MES", 1), Named("MINS", 1)],
+        ),
+        field(
+            "HPMR",
+            DWORD_ACC | PRESERVE,
+            &[Named("MSEL", 32), Named("MOEV", 32), Named("MOSC", 32)],
+        ),
+        mutex("MLCK", 0),
+        method(
+            "MSCN",
+            0,
+            false,
+            &[
+                acquire("MLCK"),
+                store(int(0), local(0)),
+                while_loop(
+                    lless(local(0), int(slots)),
+                    &[
+                        store(local(0), name_string("MSEL")),
+                        if_then(
+                            lequal(name_string("MINS"), int(1)),
+                            &[
+                                call("MTFY", &[local(0), int(NOTIFY_DEVICE_CHECK)]),
+                                // Clear the insert event.
+                                store(int(1), name_string("MINS")),
+                            ],
+                        ),
+                        increment(local(0)),
+                    ],
+                ),
+                release("MLCK"),
+            ],
+        ),
+        method(
+            "MTFY",
+            2,
+            false,
+            &(0..slots)
+                .map(|i| {
+                    if_then(
+                        lequal(arg(0), int(i)),
+                        &[notify(name_string(&slot_name(i)), arg(1))],
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ),
+        // _STA of a slot: present, enabled and functioning once plugged.
+        method(
+            "MRST",
+            1,
+            false,
+            &[
+                acquire("MLCK"),
+                store(arg(0), name_string("MSEL")),
+                store(int(0), local(0)),
+                if_then(
+                    lequal(name_string("MES"), int(1)),
+                    &[store(int(0xf), local(0))],
+                ),
+                release("MLCK"),
+                ret(local(0)),
+            ],
+        ),
+        // _CRS of a slot: the plugged memory's range.
+        method(
+            "MCRS",
+            1,
+            true,
+            &[
+                acquire("MLCK"),
+                store(arg(0), name_string("MSEL")),
+                name("MR64", resource_template(&[qword_memory(0, 0)])),
+                create_qword_field(name_string("MR64"), QWORD_MIN_OFFSET, "MINL"),
+                create_qword_field(name_string("MR64"), QWORD_MAX_OFFSET, "MAXL"),
+                create_qword_field(name_string("MR64"), QWORD_LEN_OFFSET, "LENL"),
+                shift_left(name_string("MRBH"), int(32), local(0)),
+                or(local(0), name_string("MRBL"), name_string("MINL")),
+                shift_left(name_string("MRLH"), int(32), local(0)),
+                or(local(0), name_string("MRLL"), name_string("LENL")),
+                add(name_string("MINL"), name_string("LENL"), local(0)),
+                subtract(local(0), int(1), name_string("MAXL")),
+                release("MLCK"),
+                ret(name_string("MR64")),
+            ],
+        ),
+        // _OST of a slot.
+        method(
+            "MOST",
+            3,
+            false,
+            &[
+                acquire("MLCK"),
+                store(arg(0), name_string("MSEL")),
+                store(arg(1), name_string("MOEV")),
+                store(arg(2), name_string("MOSC")),
+                release("MLCK"),
+            ],
+        ),
+    ];
+    for i in 0..slots {
+        terms.push(device(
+            &slot_name(i),
+            &[
+                name("_HID", eisa_id("PNP0C80")),
+                name("_UID", int(i)),
+                method(
+                    "_CRS",
+                    0,
+                    false,
+                    &[ret(call("MCRS", &[name_string("_UID")]))],
+                ),
+                method(
+                    "_STA",
+                    0,
+                    false,
+                    &[ret(call("MRST", &[name_string("_UID")]))],
+                ),
+                method(
+                    "_OST",
+                    3,
+                    false,
+                    &[call("MOST", &[name_string("_UID"), arg(1), arg(2)])],
+                ),
+            ],
+        ));
+    }
+    device("MHPC", &terms)
+}
+
+/// The name of the memory device for a hotplug slot.
+fn slot_name(slot: u64) -> String {
+    format!("MP{:02X}", slot)
+}
+
+/// The generic event device, which scans for hotplugged memory on its
+/// interrupt.
+fn ged() -> Vec<u8> {
+    use aml::*;
+
+    device(
+        "GED",
+        &[
+            name("_HID", string("ACPI0013")),
+            name("_UID", int(0)),
+            name("_CRS", resource_template(&[interrupt(SCI_IRQ)])),
+            method("_EVT", 1, false, &[call("\\_SB.MHPC.MSCN", &[])]),
+        ],
+    )
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut t = Table::new(b"FACP", 6);
+    t.u32(0); // FIRMWARE_CTRL, no FACS when hardware-reduced
+    t.u32(dsdt_addr as u32);
+    t.u8(0);
+    t.u8(0); // preferred power management profile: unspecified
+    t.u16(SCI_IRQ as u16);
+    // SMI command port through to the legacy boot architecture flags are
+    // all unused on a hardware-reduced machine.
+    t.zeros(112 - 48);
+    t.u32(FADT_RESET_REG_SUP | FADT_HW_REDUCED_ACPI);
+    t.io_port(i8042::COMMAND_PORT);
+    t.u8(i8042::CMD_RESET_CPU);
+    t.u16(0); // ARM boot architecture flags
+    t.u8(0); // minor version
+    t.u64(0); // X_FIRMWARE_CTRL
+    t.u64(dsdt_addr);
+    // X_PM1a_EVT_BLK through X_GPE1_BLK.
+    t.zeros(8 * 12);
+    t.io_port(sleep::CONTROL_PORT);
+    t.io_port(sleep::STATUS_PORT);
+    t.u64(0); // hypervisor vendor identity
+    t.finish()
+}
+
+fn madt(num_vcpus: u8) -> Vec<u8> {
+    let mut t = Table::new(b"APIC", 4);
+    t.u32(LAPIC_ADDR);
+    t.u32(MADT_PCAT_COMPAT);
+    for id in 0..num_vcpus {
+        t.u8(MADT_LAPIC);
+        t.u8(8);
+        t.u8(id); // processor uid
+        t.u8(id); // apic id
+        t.u32(LAPIC_ENABLED);
+    }
+    t.u8(MADT_IOAPIC);
+    t.u8(12);
+    t.u8(0); // id
+    t.u8(0);
+    t.u32(IOAPIC_ADDR);
+    t.u32(0); // gsi base
+
+    // The PIT is wired to pin 2 of KVM's IOAPIC, not 0.
+    t.u8(MADT_OVERRIDE);
+    t.u8(10);
+    t.u8(0); // ISA bus
+    t.u8(0); // irq
+    t.u32(2); // gsi
+    t.u16(0); // flags: conforming to the bus
+
+    t.u8(MADT_OVERRIDE);
+    t.u8(10);
+    t.u8(0);
+    t.u8(SCI_IRQ as u8);
+    t.u32(SCI_IRQ);
+    // Interrupts are pulsed through an irqfd. A level triggered pin would
+    // lose pulses that arrive while the guest has it masked.
+    t.u16(OVERRIDE_ACTIVE_HIGH | OVERRIDE_EDGE);
+    t.finish()
+}
+
+fn xsdt(tables: &[u64]) -> Vec<u8> {
+    let mut t = Table::new(b"XSDT", 1);
+    for addr in tables.iter() {
+        t.u64(*addr);
+    }
+    t.finish()
+}
+
+fn rsdp(xsdt_addr: u64) -> Vec<u8> {
+    let mut rsdp = Vec::new();
+    rsdp.extend_from_slice(b"RSD PTR ");
+    rsdp.push(0); // checksum of the first 20 bytes
+    rsdp.extend_from_slice(OEM_ID);
+    rsdp.push(2); // revision
+    rsdp.extend_from_slice(&0u32.to_le_bytes()); // no RSDT
+    rsdp.extend_from_slice(&36u32.to_le_bytes());
+    rsdp.extend_from_slice(&xsdt_addr.to_le_bytes());
+    rsdp.extend_from_slice(&[0; 4]); // extended checksum, reserved
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+fn write_all(mem: &mut dyn Addressable, bytes: &[u8], addr: usize) -> Result<()> {
+    if mem.write(bytes, MemoryAddr(addr))? != bytes.len() {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(())
+}
+
+/// Write a table at `*addr`, returning where it was written and advancing
+/// `*addr` past it.
+fn place(mem: &mut dyn Addressable, table: &[u8], addr: &mut usize) -> Result<u64> {
+    write_all(mem, table, *addr)?;
+    let placed = *addr as u64;
+    // Keep each table 8 byte aligned.
+    *addr += (table.len() + 7) & !7;
+    Ok(placed)
+}
+
+/// Write the tables for a machine with the given number of vcpus.
+pub fn write_tables(mem: &mut dyn Addressable, num_vcpus: u8) -> Result<()> {
+    let mut addr = TABLES_ADDR;
+    let dsdt = place(mem, &dsdt(), &mut addr)?;
+    let tables = [
+        place(mem, &fadt(dsdt), &mut addr)?,
+        place(mem, &madt(num_vcpus), &mut addr)?,
+    ];
+    let xsdt = place(mem, &xsdt(&tables), &mut addr)?;
+    write_all(mem, &rsdp(xsdt), RSDP_ADDR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::memorymap::MemoryMmap;
+
+    fn read(mem: &MemoryMmap, addr: usize, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        mem.read(&mut buf, MemoryAddr(addr)).unwrap();
+        buf
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        let mut v = [0; 4];
+        v.copy_from_slice(&b[i..i + 4]);
+        u32::from_le_bytes(v)
+    }
+
+    fn u64_at(b: &[u8], i: usize) -> u64 {
+        let mut v = [0; 8];
+        v.copy_from_slice(&b[i..i + 8]);
+        u64::from_le_bytes(v)
+    }
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    /// Find a table through the RSDP and XSDT, checking checksums on the way.
+    fn find_table(mem: &MemoryMmap, signature: &[u8; 4]) -> Option<Vec<u8>> {
+        let rsdp = read(mem, RSDP_ADDR, 36);
+        assert_eq!(b"RSD PTR ", &rsdp[..8]);
+        assert_eq!(0, sum(&rsdp[..20]));
+        assert_eq!(0, sum(&rsdp));
+        let xsdt_addr = u64_at(&rsdp, 24) as usize;
+        let len = u32_at(&read(mem, xsdt_addr, 8), 4) as usize;
+        let xsdt = read(mem, xsdt_addr, len);
+        assert_eq!(b"XSDT", &xsdt[..4]);
+        assert_eq!(0, sum(&xsdt));
+        for i in (36..len).step_by(8) {
+            let addr = u64_at(&xsdt, i) as usize;
+            let len = u32_at(&read(mem, addr, 8), 4) as usize;
+            let table = read(mem, addr, len);
+            assert_eq!(0, sum(&table));
+            if &table[..4] == signature {
+                return Some(table);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn madt_lists_vcpus() {
+        let mut mem = MemoryMmap::new(1 << 20).unwrap();
+        write_tables(&mut mem, 3).unwrap();
+        let madt = find_table(&mem, b"APIC").unwrap();
+        assert_eq!(LAPIC_ADDR, u32_at(&madt, 36));
+
+        let mut lapics = Vec::new();
+        let mut i = 44;
+        while i < madt.len() {
+            if madt[i] == MADT_LAPIC {
+                lapics.push(madt[i + 3]);
+            }
+            i += madt[i + 1] as usize;
+        }
+        assert_eq!(madt.len(), i);
+        assert_eq!(vec![0, 1, 2], lapics);
+    }
+
+    #[test]
+    fn fadt_layout() {
+        let mut mem = MemoryMmap::new(1 << 20).unwrap();
+        write_tables(&mut mem, 1).unwrap();
+        let fadt = find_table(&mem, b"FACP").unwrap();
+        assert_eq!(276, fadt.len());
+        assert_eq!(
+            FADT_RESET_REG_SUP | FADT_HW_REDUCED_ACPI,
+            u32_at(&fadt, 112)
+        );
+        assert_eq!(u64::from(sleep::CONTROL_PORT), u64_at(&fadt, 244 + 4));
+        assert_eq!(u64::from(sleep::STATUS_PORT), u64_at(&fadt, 256 + 4));
+
+        let dsdt_addr = u64_at(&fadt, 140) as usize;
+        assert_eq!(dsdt_addr, u32_at(&fadt, 40) as usize);
+        let len = u32_at(&read(&mem, dsdt_addr, 8), 4) as usize;
+        let dsdt = read(&mem, dsdt_addr, len);
+        assert_eq!(b"DSDT", &dsdt[..4]);
+        assert_eq!(0, sum(&dsdt));
+        assert_eq!(b"_S5_", &dsdt[37..41]);
+    }
+
+    /// Whether `needle` appears in `haystack`.
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn dsdt_memory_hotplug() {
+        let dsdt = dsdt();
+        assert_eq!(0, sum(&dsdt));
+        assert!(contains(&dsdt, b"ACPI0013\0"));
+        // Name (_HID, EISAID ("PNP0C80")) for each slot.
+        let hid = [b"_HID".to_vec(), aml::eisa_id("PNP0C80")].concat();
+        let count = dsdt.windows(hid.len()).filter(|w| *w == &hid[..]).count();
+        assert_eq!(memhp::MAX_DEVICES, count);
+        // The controller's registers are in io space at its ports.
+        let region = [
+            b"HPMR".to_vec(),
+            vec![aml::SYSTEM_IO],
+            aml::int(memhp::REGISTER_BASE as u64),
+        ]
+        .concat();
+        assert!(contains(&dsdt, &region));
+    }
+
+    #[test]
+    fn sci_edge_triggered() {
+        let madt = madt(1);
+        let i = (44..madt.len())
+            .find(|&i| madt[i] == MADT_OVERRIDE && u32::from(madt[i + 3]) == SCI_IRQ)
+            .unwrap();
+        assert_eq!(
+            OVERRIDE_ACTIVE_HIGH | OVERRIDE_EDGE,
+            u16::from_le_bytes([madt[i + 8], madt[i + 9]])
+        );
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut mem = MemoryMmap::new(RSDP_ADDR).unwrap();
+        assert!(write_tables(&mut mem, 1).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Minimal i8042 keyboard controller.
+//!
+//! Only enough is emulated for the guest to reset the machine by pulsing the
+//! reset line, which is how linux reboots with `reboot=k`. There's no
+//! keyboard attached, so the output buffer is always empty.
+
+use crate::vm::exit::{ExitHandler, VcpuExit};
+use crate::vm::run::KvmExit;
+use log::debug;
+
+pub const DATA_PORT: u16 = 0x60;
+pub const COMMAND_PORT: u16 = 0x64;
+
+/// Pulse the reset line.
+pub const CMD_RESET_CPU: u8 = 0xfe;
+
+pub struct I8042;
+
+impl I8042 {
+    pub fn new() -> Self {
+        I8042
+    }
+}
+
+impl ExitHandler for I8042 {
+    fn handle(&mut self, vcpu_id: u8, exit: &mut KvmExit) -> Option<VcpuExit> {
+        match exit {
+            KvmExit::IoIn(DATA_PORT, data) | KvmExit::IoIn(COMMAND_PORT, data) => {
+                // Status register reads as empty buffers, data reads as 0.
+                for b in data.iter_mut() {
+                    *b = 0;
+                }
+                Some(VcpuExit::Continue)
+            }
+            KvmExit::IoOut(COMMAND_PORT, data) if data.first() == Some(&CMD_RESET_CPU) => {
+                debug!("i8042 reset requested by vcpu {}", vcpu_id);
+                Some(VcpuExit::Reset)
+            }
+            KvmExit::IoOut(DATA_PORT, _) | KvmExit::IoOut(COMMAND_PORT, _) => {
+                Some(VcpuExit::Continue)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset() {
+        let mut dev = I8042::new();
+        let status = &mut [0xff];
+        assert_eq!(
+            Some(VcpuExit::Continue),
+            dev.handle(0, &mut KvmExit::IoIn(COMMAND_PORT, status))
+        );
+        assert_eq!([0], *status);
+        assert_eq!(
+            Some(VcpuExit::Continue),
+            dev.handle(0, &mut KvmExit::IoOut(COMMAND_PORT, &[0xad]))
+        );
+        assert_eq!(
+            Some(VcpuExit::Reset),
+            dev.handle(0, &mut KvmExit::IoOut(COMMAND_PORT, &[CMD_RESET_CPU]))
+        );
+        assert_eq!(None, dev.handle(0, &mut KvmExit::IoOut(0x3f8, &[0])));
+    }
+}
@@ -1,21 +1,147 @@
+use super::{Error as DeviceError, Stateful};
 use crate::memory::{Addressable, MemoryAddr, Result};
+use std::io::{self, Write};
 
-pub struct Serial {}
+// 16550 register offsets. Offsets 0 and 1 access the divisor latch instead
+// when LCR_DLAB is set.
+const DATA: usize = 0;
+const IER: usize = 1;
+const IIR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const LCR_DLAB: u8 = 0x80;
+const IIR_NO_INTERRUPT: u8 = 0x01;
+/// Transmit holding register and transmitter empty.
+const LSR_THR_EMPTY: u8 = 0x60;
+/// Carrier detect, ring, data set ready and clear to send.
+const MSR_DEFAULT: u8 = 0xb0;
+
+/// Size of the state saved by `Stateful::save_state`.
+const STATE_LEN: usize = 6;
+
+/// A 16550 UART writing transmitted bytes to stdout. Input and interrupts
+/// aren't supported.
+pub struct Serial {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+}
 
 impl Serial {
     pub fn new() -> Self {
-        Serial {}
+        Serial {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            // 115200 baud.
+            dll: 1,
+            dlm: 0,
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn read_reg(&self, offset: usize) -> u8 {
+        match offset {
+            DATA if self.dlab() => self.dll,
+            IER if self.dlab() => self.dlm,
+            IER => self.ier,
+            IIR => IIR_NO_INTERRUPT,
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => LSR_THR_EMPTY,
+            MSR => MSR_DEFAULT,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u8) {
+        match offset {
+            DATA if self.dlab() => self.dll = val,
+            DATA => {
+                let mut out = io::stdout();
+                let _ = out.write_all(&[val]);
+                let _ = out.flush();
+            }
+            IER if self.dlab() => self.dlm = val,
+            IER => self.ier = val & 0x0f,
+            LCR => self.lcr = val,
+            MCR => self.mcr = val & 0x1f,
+            SCR => self.scr = val,
+            _ => (),
+        }
     }
 }
 
 impl Addressable for Serial {
     fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> Result<usize> {
-        println!("read: ({}) {:?}", addr, buf);
-        Ok(0)
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read_reg(addr.0 + i);
+        }
+        Ok(buf.len())
     }
 
     fn write(&mut self, buf: &[u8], addr: MemoryAddr) -> Result<usize> {
-        println!("write: ({}) {:?}", addr, buf);
-        Ok(0)
+        for (i, b) in buf.iter().enumerate() {
+            self.write_reg(addr.0 + i, *b);
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Stateful for Serial {
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.ier, self.lcr, self.mcr, self.scr, self.dll, self.dlm]
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> std::result::Result<(), DeviceError> {
+        if state.len() != STATE_LEN {
+            return Err(DeviceError::InvalidState);
+        }
+        self.ier = state[0];
+        self.lcr = state[1];
+        self.mcr = state[2];
+        self.scr = state[3];
+        self.dll = state[4];
+        self.dlm = state[5];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisor_latch_and_state() {
+        let mut serial = Serial::new();
+        serial.write(&[LCR_DLAB], MemoryAddr(LCR)).unwrap();
+        serial.write(&[0x0c, 0x00], MemoryAddr(DATA)).unwrap();
+        serial.write(&[0x03], MemoryAddr(LCR)).unwrap();
+        serial.write(&[0x5a], MemoryAddr(SCR)).unwrap();
+
+        let mut restored = Serial::new();
+        restored.restore_state(&serial.save_state()).unwrap();
+        let buf = &mut [0; 8];
+        restored.read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!(0x03, buf[LCR]);
+        assert_eq!(LSR_THR_EMPTY, buf[LSR]);
+        assert_eq!(0x5a, buf[SCR]);
+        restored.write(&[LCR_DLAB], MemoryAddr(LCR)).unwrap();
+        restored.read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!([0x0c, 0x00], buf[..2]);
+
+        assert!(restored.restore_state(&[0]).is_err());
     }
 }
@@ -0,0 +1,276 @@
+//! Memory hotplug controller.
+//!
+//! Implements the register interface used by QEMU's ACPI memory hotplug
+//! device so that a guest can discover memory added at runtime. The block is
+//! `REGISTER_LEN` bytes and normally lives at io port 0xa00.
+//!
+//! Reads return information about the selected memory device:
+//!
+//!   [0x00-0x03] low 32 bits of the guest physical address
+//!   [0x04-0x07] high 32 bits of the guest physical address
+//!   [0x08-0x0b] low 32 bits of the size
+//!   [0x0c-0x0f] high 32 bits of the size
+//!   [0x10-0x13] proximity domain
+//!   [0x14]      status (bit 0: enabled, bit 1: insert event)
+//!
+//! Writes:
+//!
+//!   [0x00-0x03] select memory device
+//!   [0x04-0x07] OST event code
+//!   [0x08-0x0b] OST status code
+//!   [0x14]      control (bit 1: clear insert event)
+//!
+//! The guest finds the interface through the memory devices in the DSDT (see
+//! `acpi`), which declares `MAX_DEVICES` slots.
+
+use super::{Bus, Error as BusError, Stateful};
+use crate::acpi::SCI_IRQ;
+use crate::memory::memorymap::MemoryMmap;
+use crate::memory::{Addressable, MemoryAddr, MemoryRange, Region, Result};
+use crate::vm::{Error as VmError, Vm};
+use log::debug;
+use std::sync::{Arc, Mutex};
+
+pub const REGISTER_BASE: usize = 0x0a00;
+pub const REGISTER_LEN: usize = 0x18;
+/// Number of memory devices that can be plugged.
+pub const MAX_DEVICES: usize = 16;
+
+const STATUS_ENABLED: u8 = 1 << 0;
+const STATUS_INSERT: u8 = 1 << 1;
+const CONTROL_CLEAR_INSERT: u8 = 1 << 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Vm(VmError),
+    Bus(BusError),
+    /// All `MAX_DEVICES` slots are in use.
+    NoFreeSlot,
+}
+
+struct MemoryDevice {
+    guest_addr: MemoryAddr,
+    size: usize,
+    inserting: bool,
+    mem: Arc<Mutex<MemoryMmap>>,
+}
+
+pub struct MemoryHotplug {
+    mmio_bus: Arc<Bus>,
+    devices: Vec<MemoryDevice>,
+    selected: usize,
+}
+
+impl MemoryHotplug {
+    /// Create a controller that places hotplugged memory on the provided
+    /// mmio bus.
+    pub fn new(mmio_bus: Arc<Bus>) -> Self {
+        MemoryHotplug {
+            mmio_bus,
+            devices: Vec::new(),
+            selected: 0,
+        }
+    }
+
+    /// Add memory to a running guest at the given address.
+    ///
+    /// The memory is registered with KVM first so that it's accessible by the
+    /// guest before the bus or guest are told about it. The guest is then
+    /// notified with an SCI.
+    pub fn plug(
+        &mut self,
+        vm: &Vm,
+        guest_addr: MemoryAddr,
+        mem: MemoryMmap,
+    ) -> std::result::Result<(), Error> {
+        if self.devices.len() == MAX_DEVICES {
+            return Err(Error::NoFreeSlot);
+        }
+        let size = mem.len();
+        let slot = vm.hotplug_memory(guest_addr, &mem).map_err(Error::Vm)?;
+        let mem = Arc::new(Mutex::new(mem));
+        if let Err(e) = self
+            .mmio_bus
+            .insert(MemoryRange::new(guest_addr, size), mem.clone())
+        {
+            // The memory is unmapped when dropped, so the guest mustn't keep
+            // it.
+            vm.remove_memory(slot).map_err(Error::Vm)?;
+            return Err(Error::Bus(e));
+        }
+        self.devices.push(MemoryDevice {
+            guest_addr,
+            size,
+            inserting: true,
+            mem,
+        });
+        vm.inject_irq(SCI_IRQ).map_err(Error::Vm)
+    }
+
+    /// The guest physical address following the last plugged device, or
+    /// `base` if nothing has been plugged.
+    pub fn next_addr(&self, base: MemoryAddr) -> MemoryAddr {
+        self.devices
+            .iter()
+            .filter_map(|dev| dev.guest_addr.checked_add(dev.size))
+            .max()
+            .unwrap_or(base)
+    }
+
+    /// Number of memory devices plugged so far.
+    pub fn plugged(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// The guest physical address and memory of each plugged device.
+    pub fn regions(&self) -> Vec<(MemoryAddr, Arc<Mutex<MemoryMmap>>)> {
+        self.devices
+            .iter()
+            .map(|dev| (dev.guest_addr, dev.mem.clone()))
+            .collect()
+    }
+
+    fn register(&self, offset: usize) -> u32 {
+        let dev = match self.devices.get(self.selected) {
+            Some(dev) => dev,
+            None => return 0,
+        };
+        match offset {
+            0x00 => u64::from(dev.guest_addr) as u32,
+            0x04 => (u64::from(dev.guest_addr) >> 32) as u32,
+            0x08 => dev.size as u32,
+            0x0c => (dev.size as u64 >> 32) as u32,
+            0x14 => {
+                let mut status = STATUS_ENABLED;
+                if dev.inserting {
+                    status |= STATUS_INSERT;
+                }
+                u32::from(status)
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl Addressable for MemoryHotplug {
+    fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> Result<usize> {
+        let reg = addr.align_down(4);
+        // Aligning down never moves past `addr`.
+        let shift = addr.offset_from(reg).unwrap_or(0);
+        let bytes = self.register(reg.0).to_le_bytes();
+        let n = buf.len().min(4 - shift);
+        buf[..n].copy_from_slice(&bytes[shift..shift + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8], addr: MemoryAddr) -> Result<usize> {
+        let mut bytes = [0; 4];
+        let n = buf.len().min(4);
+        bytes[..n].copy_from_slice(&buf[..n]);
+        let val = u32::from_le_bytes(bytes);
+        match addr.0 {
+            0x00 => self.selected = val as usize,
+            0x04 | 0x08 => debug!("memory hotplug ost: ({}) {:#x}", addr, val),
+            0x14 => {
+                if let Some(dev) = self.devices.get_mut(self.selected) {
+                    if val as u8 & CONTROL_CLEAR_INSERT != 0 {
+                        dev.inserting = false;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(n)
+    }
+}
+
+/// Only the selected device is saved. Plugged memory isn't part of the state,
+/// so a controller with plugged devices can't be restored from it.
+impl Stateful for MemoryHotplug {
+    fn save_state(&self) -> Vec<u8> {
+        (self.selected as u64).to_le_bytes().to_vec()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> std::result::Result<(), BusError> {
+        if state.len() != 8 {
+            return Err(BusError::InvalidState);
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(state);
+        self.selected = u64::from_le_bytes(bytes) as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::KvmContext;
+
+    fn read_u32(hp: &MemoryHotplug, offset: usize) -> u32 {
+        let mut buf = [0; 4];
+        hp.read(&mut buf, MemoryAddr(offset)).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn plug_and_report() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mem = MemoryMmap::new(4096).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+
+        let bus = Arc::new(Bus::new());
+        let mut hp = MemoryHotplug::new(bus.clone());
+        hp.plug(
+            &vm,
+            MemoryAddr(0x1_0000_0000),
+            MemoryMmap::new(4096).unwrap(),
+        )
+        .unwrap();
+
+        hp.write(&[0, 0, 0, 0], MemoryAddr(0x00)).unwrap();
+        assert_eq!(0, read_u32(&hp, 0x00));
+        assert_eq!(1, read_u32(&hp, 0x04));
+        assert_eq!(4096, read_u32(&hp, 0x08));
+        assert_eq!(0b11, read_u32(&hp, 0x14));
+
+        hp.write(&[CONTROL_CLEAR_INSERT], MemoryAddr(0x14)).unwrap();
+        assert_eq!(0b01, read_u32(&hp, 0x14));
+
+        // Hotplugged memory is reachable through the bus.
+        bus.write(MemoryAddr(0x1_0000_0010), &[7]).unwrap();
+        let mut buf = [0; 1];
+        bus.read(MemoryAddr(0x1_0000_0010), &mut buf).unwrap();
+        assert_eq!([7], buf);
+        assert_eq!(
+            MemoryAddr(0x1_0000_1000),
+            hp.next_addr(MemoryAddr(0x1_0000_0000))
+        );
+    }
+
+    #[test]
+    fn plug_bus_conflict() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mem = MemoryMmap::new(4096).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+
+        let bus = Arc::new(Bus::new());
+        let taken = Arc::new(Mutex::new(MemoryMmap::new(4096).unwrap()));
+        bus.insert(MemoryRange::new(MemoryAddr(0x1_0000_0000), 4096), taken)
+            .unwrap();
+        let mut hp = MemoryHotplug::new(bus);
+        let addr = MemoryAddr(0x1_0000_0000);
+        match hp.plug(&vm, addr, MemoryMmap::new(4096).unwrap()) {
+            Err(Error::Bus(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(0, hp.plugged());
+        assert_eq!(addr, hp.next_addr(addr));
+
+        // The failed plug didn't leave the range registered with KVM.
+        let extra = MemoryMmap::new(4096).unwrap();
+        vm.hotplug_memory(addr, &extra).unwrap();
+    }
+}
@@ -1,71 +1,65 @@
+pub mod i8042;
 pub mod legacy;
+pub mod memhp;
+pub mod rom;
+pub mod sleep;
+pub mod virtio;
 
-use crate::memory::{Addressable, MemoryAddr};
-use std::cmp::{Ord, Ordering, PartialEq};
+use crate::memory::{Addressable, MemoryAddr, MemoryRange};
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 #[derive(Debug)]
 pub enum Error {
     ReadFailed,
     WriteFailed,
     MissingDevice,
+    Overlap,
+    InvalidState,
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-/// Describes the memory range for a device on a bus.
-#[derive(Eq, Debug)]
-pub struct Range(pub MemoryAddr, pub usize);
+/// A device whose state can be saved in a snapshot.
+pub trait Stateful {
+    fn save_state(&self) -> Vec<u8>;
 
-impl Range {
-    pub fn contains_addr(&self, addr: &MemoryAddr) -> bool {
-        &self.0 <= addr && &(self.0.add_offset(self.1)) > addr
-    }
-}
-
-impl PartialEq for Range {
-    fn eq(&self, other: &Range) -> bool {
-        self.0 == other.0
-    }
-}
-
-impl Ord for Range {
-    fn cmp(&self, other: &Range) -> Ordering {
-        self.0.cmp(&other.0)
-    }
-}
-
-impl PartialOrd for Range {
-    fn partial_cmp(&self, other: &Range) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
-    }
+    /// Restore state previously returned by `save_state`.
+    fn restore_state(&mut self, state: &[u8]) -> Result<()>;
 }
 
 /// A bus of similiarly related devices.
+///
+/// Devices may be inserted while other threads are accessing the bus, e.g.
+/// when hotplugging memory with running vcpus.
 pub struct Bus {
-    devices: BTreeMap<Range, Arc<Mutex<Addressable>>>,
+    /// Devices keyed by the start of their range.
+    devices: RwLock<BTreeMap<MemoryAddr, (MemoryRange, Arc<Mutex<Addressable>>)>>,
+    /// Number of accesses to addresses without a device, for diagnostics.
+    unmapped: Mutex<BTreeMap<MemoryAddr, u64>>,
 }
 
 impl Bus {
     pub fn new() -> Self {
         Bus {
-            devices: BTreeMap::new(),
+            devices: RwLock::new(BTreeMap::new()),
+            unmapped: Mutex::new(BTreeMap::new()),
         }
     }
 
     /// Insert a device into the bus with the given range. Ranges must not
     /// overlap between devices.
-    ///
-    /// TODO: Actually check for overlapping ranges.
-    pub fn insert(&mut self, range: Range, dev: Arc<Mutex<Addressable>>) -> Result<()> {
-        self.devices.insert(range, dev);
+    pub fn insert(&self, range: MemoryRange, dev: Arc<Mutex<Addressable>>) -> Result<()> {
+        let mut devices = self.devices.write().expect("failed to acquire lock");
+        if devices.values().any(|(r, _)| r.overlaps(&range)) {
+            return Err(Error::Overlap);
+        }
+        devices.insert(range.start(), (range, dev));
         Ok(())
     }
 
     pub fn read(&self, addr: MemoryAddr, bs: &mut [u8]) -> Result<()> {
-        let (offset, dev) = self.device_at_addr(&addr).ok_or(Error::MissingDevice)?;
+        let (offset, dev) = self.device_at_addr(addr).ok_or(Error::MissingDevice)?;
         dev.lock()
             .expect("failed to acquire mutex")
             .read(bs, offset)
@@ -74,7 +68,7 @@ impl Bus {
     }
 
     pub fn write(&self, addr: MemoryAddr, bs: &[u8]) -> Result<()> {
-        let (offset, dev) = self.device_at_addr(&addr).ok_or(Error::MissingDevice)?;
+        let (offset, dev) = self.device_at_addr(addr).ok_or(Error::MissingDevice)?;
         dev.lock()
             .expect("failed to acquire mutex")
             .write(bs, offset)
@@ -82,16 +76,30 @@ impl Bus {
         Ok(())
     }
 
+    /// Record an access to an address without a device, returning the number
+    /// of such accesses to the address so far.
+    pub fn record_unmapped(&self, addr: MemoryAddr) -> u64 {
+        let mut unmapped = self.unmapped.lock().expect("failed to acquire mutex");
+        let count = unmapped.entry(addr).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Get the number of accesses recorded for each address without a device.
+    pub fn unmapped_accesses(&self) -> BTreeMap<MemoryAddr, u64> {
+        self.unmapped
+            .lock()
+            .expect("failed to acquire mutex")
+            .clone()
+    }
+
     /// Find the device at the given address on the bus. If the device exists,
     /// the device and the offset from the start of the device will be returned.
-    fn device_at_addr(&self, addr: &MemoryAddr) -> Option<(MemoryAddr, &Mutex<Addressable>)> {
-        for (range, dev) in self.devices.iter() {
-            if range.contains_addr(&addr) {
-                let offset = MemoryAddr(addr.0 - (range.0).0);
-                return Some((offset, dev));
-            }
-        }
-        None
+    fn device_at_addr(&self, addr: MemoryAddr) -> Option<(MemoryAddr, Arc<Mutex<Addressable>>)> {
+        let devices = self.devices.read().expect("failed to acquire lock");
+        let (range, dev) = devices.range(..=addr).next_back()?.1;
+        let offset = range.offset_of(addr)?;
+        Some((MemoryAddr(offset), dev.clone()))
     }
 }
 
@@ -99,5 +107,71 @@ impl Bus {
 mod tests {
     use super::*;
     use crate::memory::{Error as MemError, Result as MemResult};
-    use std::io::{Read, Write};
+
+    struct Dummy;
+
+    impl Addressable for Dummy {
+        fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> MemResult<usize> {
+            buf[0] = addr.0 as u8;
+            Ok(1)
+        }
+
+        fn write(&mut self, _buf: &[u8], _addr: MemoryAddr) -> MemResult<usize> {
+            Err(MemError::OutOfBounds)
+        }
+    }
+
+    #[test]
+    fn insert_overlap() {
+        let bus = Bus::new();
+        bus.insert(
+            MemoryRange::new(MemoryAddr(0x10), 0x10),
+            Arc::new(Mutex::new(Dummy)),
+        )
+        .unwrap();
+        match bus.insert(
+            MemoryRange::new(MemoryAddr(0x18), 0x10),
+            Arc::new(Mutex::new(Dummy)),
+        ) {
+            Err(Error::Overlap) => (),
+            _ => panic!("expected overlap"),
+        }
+        bus.insert(
+            MemoryRange::new(MemoryAddr(0x20), 0x10),
+            Arc::new(Mutex::new(Dummy)),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn unmapped_counts() {
+        let bus = Bus::new();
+        assert_eq!(1, bus.record_unmapped(MemoryAddr(0x80)));
+        assert_eq!(2, bus.record_unmapped(MemoryAddr(0x80)));
+        assert_eq!(1, bus.record_unmapped(MemoryAddr(0x81)));
+        let counts = bus.unmapped_accesses();
+        assert_eq!(Some(&2), counts.get(&MemoryAddr(0x80)));
+        assert_eq!(Some(&1), counts.get(&MemoryAddr(0x81)));
+    }
+
+    #[test]
+    fn read_offset() {
+        let bus = Bus::new();
+        bus.insert(
+            MemoryRange::new(MemoryAddr(0x10), 0x10),
+            Arc::new(Mutex::new(Dummy)),
+        )
+        .unwrap();
+        let buf = &mut [0; 1];
+        bus.read(MemoryAddr(0x14), buf).unwrap();
+        assert_eq!(4, buf[0]);
+        match bus.read(MemoryAddr(0x20), buf) {
+            Err(Error::MissingDevice) => (),
+            _ => panic!("expected missing device"),
+        }
+        match bus.write(MemoryAddr(0x10), buf) {
+            Err(Error::WriteFailed) => (),
+            _ => panic!("expected failed write"),
+        }
+    }
 }
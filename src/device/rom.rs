@@ -0,0 +1,76 @@
+use crate::memory::memorymap::MemoryMmap;
+use crate::memory::{Addressable, Error, MemoryAddr, Region, Result};
+use log::debug;
+
+/// Read-only memory such as firmware or an option ROM.
+///
+/// The backing memory should be mapped into the guest with
+/// `Vm::add_readonly_region`, which lets the guest read it directly. Guest
+/// writes exit as mmio writes and land here, where they're ignored. Devices
+/// that need to act on writes (e.g. flash) can be placed on the bus over the
+/// same range instead.
+pub struct Rom {
+    mem: MemoryMmap,
+}
+
+impl Rom {
+    /// Create a rom containing the image. The size is rounded up to a whole
+    /// number of pages, and is at least one page so an empty image can still
+    /// be mapped.
+    pub fn new(image: &[u8]) -> Result<Self> {
+        const PAGE_SIZE: usize = 4096;
+        let size = image.len().max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mut mem = MemoryMmap::new(size)?;
+        if !image.is_empty() {
+            mem.write(image, MemoryAddr(0))?;
+        }
+        // The image write isn't a guest modification.
+        mem.dirty_bitmap().take();
+        Ok(Rom { mem })
+    }
+
+    pub fn memory(&self) -> &MemoryMmap {
+        &self.mem
+    }
+
+    pub fn len(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl Addressable for Rom {
+    fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> Result<usize> {
+        self.mem.read(buf, addr)
+    }
+
+    fn write(&mut self, buf: &[u8], addr: MemoryAddr) -> Result<usize> {
+        if addr.0 >= self.mem.len() {
+            return Err(Error::OutOfBounds);
+        }
+        debug!("ignoring rom write: ({}) {:?}", addr, buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_ignored() {
+        let mut rom = Rom::new(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(4096, rom.len());
+        assert_eq!(2, rom.write(&[9, 9], MemoryAddr(0)).unwrap());
+        let buf = &mut [0; 4];
+        rom.read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!(&[1, 2, 3, 4], buf);
+    }
+    #[test]
+    fn empty_image() {
+        let rom = Rom::new(&[]).unwrap();
+        assert_eq!(4096, rom.len());
+        let buf = &mut [1; 4];
+        rom.read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!(&[0; 4], buf);
+    }
+}
@@ -0,0 +1,84 @@
+//! ACPI sleep control and status registers.
+//!
+//! A hardware-reduced ACPI machine has no PM1 blocks, and instead enters
+//! sleep states through these two registers, described to the guest by the
+//! FADT (see `acpi`). Linux powers off by writing the S5 sleep type along
+//! with SLP_EN to the control register. Other sleep states aren't supported,
+//! so the guest never wakes and the status register always reads as 0.
+
+use crate::vm::exit::{ExitHandler, VcpuExit};
+use crate::vm::run::KvmExit;
+use log::debug;
+
+pub const CONTROL_PORT: u16 = 0x0600;
+pub const STATUS_PORT: u16 = 0x0601;
+
+/// Sleep type for S5, soft off. Must match the `_S5` object in the DSDT.
+pub const S5_SLEEP_TYPE: u8 = 5;
+
+const SLP_TYP_SHIFT: u8 = 2;
+const SLP_TYP_MASK: u8 = 0x7;
+const SLP_EN: u8 = 1 << 5;
+
+pub struct SleepControl;
+
+impl SleepControl {
+    pub fn new() -> Self {
+        SleepControl
+    }
+}
+
+impl ExitHandler for SleepControl {
+    fn handle(&mut self, vcpu_id: u8, exit: &mut KvmExit) -> Option<VcpuExit> {
+        match exit {
+            KvmExit::IoIn(CONTROL_PORT, data) | KvmExit::IoIn(STATUS_PORT, data) => {
+                for b in data.iter_mut() {
+                    *b = 0;
+                }
+                Some(VcpuExit::Continue)
+            }
+            KvmExit::IoOut(CONTROL_PORT, data) => {
+                let val = data.first().cloned().unwrap_or(0);
+                let sleep_type = (val >> SLP_TYP_SHIFT) & SLP_TYP_MASK;
+                if val & SLP_EN != 0 && sleep_type == S5_SLEEP_TYPE {
+                    debug!("poweroff requested by vcpu {}", vcpu_id);
+                    Some(VcpuExit::Shutdown)
+                } else {
+                    Some(VcpuExit::Continue)
+                }
+            }
+            KvmExit::IoOut(STATUS_PORT, _) => Some(VcpuExit::Continue),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poweroff() {
+        let mut dev = SleepControl::new();
+        let status = &mut [0xff];
+        assert_eq!(
+            Some(VcpuExit::Continue),
+            dev.handle(0, &mut KvmExit::IoIn(STATUS_PORT, status))
+        );
+        assert_eq!([0], *status);
+        // The sleep type alone, or another sleep state, does nothing.
+        assert_eq!(
+            Some(VcpuExit::Continue),
+            dev.handle(0, &mut KvmExit::IoOut(CONTROL_PORT, &[5 << 2]))
+        );
+        assert_eq!(
+            Some(VcpuExit::Continue),
+            dev.handle(0, &mut KvmExit::IoOut(CONTROL_PORT, &[3 << 2 | SLP_EN]))
+        );
+        assert_eq!(
+            Some(VcpuExit::Shutdown),
+            dev.handle(0, &mut KvmExit::IoOut(CONTROL_PORT, &[5 << 2 | SLP_EN]))
+        );
+        assert_eq!(None, dev.handle(0, &mut KvmExit::IoOut(0x3f8, &[0])));
+    }
+}
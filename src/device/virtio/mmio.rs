@@ -0,0 +1,504 @@
+//! The virtio-mmio transport, version 2.
+//!
+//! Each device gets `MMIO_LEN` bytes of mmio space with the layout below,
+//! followed by its config space at `CONFIG`. Registers are 32 bits wide,
+//! and 64-bit addresses are split into low and high halves.
+//!
+//! Linux finds devices on x86 through `virtio_mmio.device=` parameters on
+//! the kernel command line, see `cmdline_param`.
+
+use super::{
+    Interrupt, InterruptLine, QueueConfig, VirtioDevice, STATUS_DEVICE_NEEDS_RESET,
+    STATUS_DRIVER_OK, STATUS_FEATURES_OK, VIRTIO_F_VERSION_1,
+};
+use crate::memory::{Addressable, MemoryAddr, MemoryRange, Result};
+use log::{debug, error};
+use std::sync::Arc;
+
+pub const MMIO_LEN: usize = 0x200;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// "virt"
+const MAGIC: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+/// "SUBM"
+const VENDOR: u32 = 0x4d42_5553;
+
+/// Format the kernel command line parameter describing a device at `range`
+/// using interrupt `irq`.
+pub fn cmdline_param(range: MemoryRange, irq: u32) -> String {
+    format!(
+        "virtio_mmio.device={:#x}@{:#x}:{}",
+        range.len(),
+        range.start().0,
+        irq
+    )
+}
+
+/// Replace the low or high half of a 64-bit value.
+fn set_half(val: &mut u64, high: bool, half: u32) {
+    if high {
+        *val = (*val & 0xffff_ffff) | u64::from(half) << 32;
+    } else {
+        *val = (*val & !0xffff_ffff) | u64::from(half);
+    }
+}
+
+pub struct MmioTransport {
+    device: Box<dyn VirtioDevice>,
+    interrupt: Arc<Interrupt>,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<QueueConfig>,
+}
+
+impl MmioTransport {
+    pub fn new(device: Box<dyn VirtioDevice>, line: Box<dyn InterruptLine>) -> Self {
+        let queues = Self::initial_queues(device.as_ref());
+        MmioTransport {
+            device,
+            interrupt: Interrupt::new(line),
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+        }
+    }
+
+    fn initial_queues(device: &dyn VirtioDevice) -> Vec<QueueConfig> {
+        device
+            .queue_max_sizes()
+            .iter()
+            .map(|&max_size| QueueConfig {
+                max_size,
+                size: max_size,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | 1 << VIRTIO_F_VERSION_1
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut QueueConfig> {
+        // Queues can't be changed once the device is running.
+        if self.status & STATUS_DRIVER_OK != 0 {
+            return None;
+        }
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => MMIO_VERSION,
+            DEVICE_ID => self.device.device_type(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |q| u32::from(q.max_size)),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt.status(),
+            STATUS => self.status,
+            CONFIG_GENERATION => self.interrupt.config_generation(),
+            _ => {
+                debug!("virtio-mmio read from unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            // Features are fixed once accepted.
+            DRIVER_FEATURES if self.status & STATUS_FEATURES_OK == 0 => {
+                match self.driver_features_sel {
+                    0 => set_half(&mut self.driver_features, false, val),
+                    1 => set_half(&mut self.driver_features, true, val),
+                    _ => (),
+                }
+            }
+            QUEUE_SEL => self.queue_sel = val,
+            QUEUE_NUM => {
+                if let Some(q) = self.selected_queue() {
+                    // Sizes above the maximum are ignored.
+                    if val <= u32::from(q.max_size) {
+                        q.size = val as u16;
+                    } else {
+                        debug!("virtio-mmio queue size {} too large", val);
+                    }
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.selected_queue() {
+                    q.ready = val == 1;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_half(&mut q.desc_table, offset == QUEUE_DESC_HIGH, val);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_half(&mut q.avail_ring, offset == QUEUE_DRIVER_HIGH, val);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_half(&mut q.used_ring, offset == QUEUE_DEVICE_HIGH, val);
+                }
+            }
+            QUEUE_NOTIFY => {
+                if self.status & STATUS_DRIVER_OK != 0 {
+                    self.device.queue_notify(val as u16);
+                }
+            }
+            INTERRUPT_ACK => self.interrupt.ack(val),
+            STATUS => self.set_status(val),
+            _ => debug!("virtio-mmio write to unknown register {:#x}", offset),
+        }
+    }
+
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        let added = status & !self.status;
+        let mut status = status;
+        // The driver checks that FEATURES_OK stuck, and gives up on the
+        // device if it didn't.
+        if added & STATUS_FEATURES_OK != 0 {
+            let unsupported = self.driver_features & !self.device_features();
+            if unsupported != 0 || self.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+                debug!(
+                    "virtio-mmio driver features {:#x} not accepted",
+                    self.driver_features
+                );
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        if added & STATUS_DRIVER_OK != 0 {
+            if let Err(e) =
+                self.device
+                    .activate(self.driver_features, &self.queues, self.interrupt.clone())
+            {
+                error!("failed to activate virtio device: {:?}", e);
+                status |= STATUS_DEVICE_NEEDS_RESET;
+                if let Err(e) = self.interrupt.signal_config_changed() {
+                    error!("failed to signal virtio device reset: {}", e);
+                }
+            }
+        }
+        self.status = status;
+    }
+
+    fn reset(&mut self) {
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.device.reset();
+        }
+        self.interrupt.reset();
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues = Self::initial_queues(self.device.as_ref());
+    }
+}
+
+impl Addressable for MmioTransport {
+    fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> Result<usize> {
+        if addr.0 >= CONFIG {
+            self.device.read_config(addr.0 - CONFIG, buf);
+        } else if buf.len() == 4 {
+            buf.copy_from_slice(&self.read_reg(addr.0).to_le_bytes());
+        } else {
+            debug!("virtio-mmio {}-byte read at {:#x}", buf.len(), addr.0);
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8], addr: MemoryAddr) -> Result<usize> {
+        if addr.0 >= CONFIG {
+            self.device.write_config(addr.0 - CONFIG, buf);
+        } else if buf.len() == 4 {
+            let mut val = [0; 4];
+            val.copy_from_slice(buf);
+            self.write_reg(addr.0, u32::from_le_bytes(val));
+        } else {
+            debug!("virtio-mmio {}-byte write at {:#x}", buf.len(), addr.0);
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Result as VirtioResult, INT_CONFIG_CHANGED, INT_USED_RING, TYPE_RNG};
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Log {
+        activated: Option<(u64, Vec<QueueConfig>)>,
+        notified: Vec<u16>,
+        resets: usize,
+        interrupt: Option<Arc<Interrupt>>,
+    }
+
+    struct Dummy {
+        config: [u8; 8],
+        log: Arc<Mutex<Log>>,
+    }
+
+    impl VirtioDevice for Dummy {
+        fn device_type(&self) -> u32 {
+            TYPE_RNG
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[256, 64]
+        }
+
+        fn read_config(&self, offset: usize, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = *self.config.get(offset + i).unwrap_or(&0);
+            }
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            for (i, b) in data.iter().enumerate() {
+                if let Some(c) = self.config.get_mut(offset + i) {
+                    *c = *b;
+                }
+            }
+        }
+
+        fn activate(
+            &mut self,
+            features: u64,
+            queues: &[QueueConfig],
+            interrupt: Arc<Interrupt>,
+        ) -> VirtioResult<()> {
+            let mut log = self.log.lock().unwrap();
+            log.activated = Some((features, queues.to_vec()));
+            log.interrupt = Some(interrupt);
+            Ok(())
+        }
+
+        fn queue_notify(&mut self, queue: u16) {
+            self.log.lock().unwrap().notified.push(queue);
+        }
+
+        fn reset(&mut self) {
+            self.log.lock().unwrap().resets += 1;
+        }
+    }
+
+    struct CountingLine(Arc<AtomicUsize>);
+
+    impl InterruptLine for CountingLine {
+        fn trigger(&self) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn transport() -> (MmioTransport, Arc<Mutex<Log>>, Arc<AtomicUsize>) {
+        let log = Arc::new(Mutex::new(Log::default()));
+        let triggers = Arc::new(AtomicUsize::new(0));
+        let dev = Dummy {
+            config: [1, 2, 3, 4, 5, 6, 7, 8],
+            log: log.clone(),
+        };
+        let t = MmioTransport::new(Box::new(dev), Box::new(CountingLine(triggers.clone())));
+        (t, log, triggers)
+    }
+
+    fn read(t: &MmioTransport, offset: usize) -> u32 {
+        let mut buf = [0; 4];
+        t.read(&mut buf, MemoryAddr(offset)).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    fn write(t: &mut MmioTransport, offset: usize, val: u32) {
+        t.write(&val.to_le_bytes(), MemoryAddr(offset)).unwrap();
+    }
+
+    /// Go through driver initialization up to accepting `features`.
+    fn negotiate(t: &mut MmioTransport, features: u64) -> u32 {
+        let status = super::super::STATUS_ACKNOWLEDGE | super::super::STATUS_DRIVER;
+        write(t, STATUS, status);
+        write(t, DRIVER_FEATURES_SEL, 0);
+        write(t, DRIVER_FEATURES, features as u32);
+        write(t, DRIVER_FEATURES_SEL, 1);
+        write(t, DRIVER_FEATURES, (features >> 32) as u32);
+        write(t, STATUS, status | STATUS_FEATURES_OK);
+        read(t, STATUS)
+    }
+
+    #[test]
+    fn identification() {
+        let (mut t, _, _) = transport();
+        assert_eq!(MAGIC, read(&t, MAGIC_VALUE));
+        assert_eq!(2, read(&t, VERSION));
+        assert_eq!(TYPE_RNG, read(&t, DEVICE_ID));
+        assert_eq!(1 << 3, read(&t, DEVICE_FEATURES));
+        write(&mut t, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(1, read(&t, DEVICE_FEATURES));
+        write(&mut t, DEVICE_FEATURES_SEL, 2);
+        assert_eq!(0, read(&t, DEVICE_FEATURES));
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let (mut t, _, _) = transport();
+        // Version 1 is required.
+        assert_eq!(0, negotiate(&mut t, 1 << 3) & STATUS_FEATURES_OK);
+        write(&mut t, STATUS, 0);
+        // Unoffered features are refused.
+        assert_eq!(0, negotiate(&mut t, 1 << 32 | 1 << 4) & STATUS_FEATURES_OK);
+        write(&mut t, STATUS, 0);
+        let status = negotiate(&mut t, 1 << 32 | 1 << 3);
+        assert_ne!(0, status & STATUS_FEATURES_OK);
+        // Features can't change after being accepted.
+        write(&mut t, DRIVER_FEATURES_SEL, 0);
+        write(&mut t, DRIVER_FEATURES, 0);
+        assert_eq!(1 << 32 | 1 << 3, t.driver_features);
+    }
+
+    #[test]
+    fn queue_setup_and_activation() {
+        let (mut t, log, triggers) = transport();
+        let status = negotiate(&mut t, 1 << 32);
+
+        write(&mut t, QUEUE_SEL, 1);
+        assert_eq!(64, read(&t, QUEUE_NUM_MAX));
+        write(&mut t, QUEUE_NUM, 32);
+        // Too large a size doesn't replace the last one.
+        write(&mut t, QUEUE_NUM, 0x1_0040);
+        assert_eq!(32, t.queues[1].size);
+        write(&mut t, QUEUE_DESC_LOW, 0x1000);
+        write(&mut t, QUEUE_DESC_HIGH, 0x1);
+        write(&mut t, QUEUE_DRIVER_LOW, 0x2000);
+        write(&mut t, QUEUE_DEVICE_LOW, 0x3000);
+        write(&mut t, QUEUE_READY, 1);
+        assert_eq!(1, read(&t, QUEUE_READY));
+        write(&mut t, QUEUE_SEL, 2);
+        assert_eq!(0, read(&t, QUEUE_NUM_MAX));
+
+        // Notifications before the driver is ready are dropped.
+        write(&mut t, QUEUE_NOTIFY, 1);
+        write(&mut t, STATUS, status | STATUS_DRIVER_OK);
+        write(&mut t, QUEUE_NOTIFY, 1);
+        {
+            let log = log.lock().unwrap();
+            let (features, queues) = log.activated.clone().unwrap();
+            assert_eq!(1 << 32, features);
+            assert!(!queues[0].ready);
+            assert_eq!(
+                QueueConfig {
+                    max_size: 64,
+                    size: 32,
+                    ready: true,
+                    desc_table: 0x1_0000_1000,
+                    avail_ring: 0x2000,
+                    used_ring: 0x3000,
+                },
+                queues[1]
+            );
+            assert_eq!(vec![1], log.notified);
+        }
+
+        // Queues are fixed while the device runs.
+        write(&mut t, QUEUE_SEL, 1);
+        write(&mut t, QUEUE_NUM, 16);
+        assert_eq!(32, t.queues[1].size);
+
+        let interrupt = log.lock().unwrap().interrupt.clone().unwrap();
+        interrupt.signal_used().unwrap();
+        interrupt.signal_config_changed().unwrap();
+        assert_eq!(2, triggers.load(Ordering::SeqCst));
+        assert_eq!(
+            INT_USED_RING | INT_CONFIG_CHANGED,
+            read(&t, INTERRUPT_STATUS)
+        );
+        assert_eq!(1, read(&t, CONFIG_GENERATION));
+        write(&mut t, INTERRUPT_ACK, INT_USED_RING);
+        assert_eq!(INT_CONFIG_CHANGED, read(&t, INTERRUPT_STATUS));
+
+        write(&mut t, STATUS, 0);
+        assert_eq!(1, log.lock().unwrap().resets);
+        assert_eq!(0, read(&t, STATUS));
+        assert_eq!(0, read(&t, INTERRUPT_STATUS));
+        assert_eq!(256, t.queues[0].size);
+        assert!(!t.queues[1].ready);
+    }
+
+    #[test]
+    fn config_space() {
+        let (mut t, _, _) = transport();
+        let mut buf = [0; 2];
+        t.read(&mut buf, MemoryAddr(CONFIG + 6)).unwrap();
+        assert_eq!([7, 8], buf);
+        t.write(&[0xaa], MemoryAddr(CONFIG + 7)).unwrap();
+        t.read(&mut buf, MemoryAddr(CONFIG + 7)).unwrap();
+        assert_eq!([0xaa, 0], buf);
+    }
+
+    #[test]
+    fn cmdline() {
+        let range = MemoryRange::new(MemoryAddr(0xd000_0000), MMIO_LEN);
+        assert_eq!(
+            "virtio_mmio.device=0x200@0xd0000000:5",
+            cmdline_param(range, 5)
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Paravirtual virtio devices.
+//!
+//! Devices implement `VirtioDevice` and are exposed to the guest through a
+//! transport, currently only virtio-mmio (see `mmio`).
+
+pub mod mmio;
+pub mod queue;
+
+use crate::vm::irq::IrqLine;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Device types, from the virtio spec.
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+pub const TYPE_RNG: u32 = 4;
+
+/// Feature bits independent of the device type.
+pub const VIRTIO_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_F_EVENT_IDX: u32 = 29;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+
+/// Device status bits, set by the driver as it initializes the device.
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+pub const STATUS_FAILED: u32 = 0x80;
+
+/// Interrupt status bits.
+pub const INT_USED_RING: u32 = 1;
+pub const INT_CONFIG_CHANGED: u32 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A queue wasn't set up by the driver before the device was activated.
+    QueueNotReady(u16),
+    /// The driver configured a queue in a way the device can't use.
+    InvalidQueue(u16),
+    Interrupt(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The guest physical addresses and size of a virtqueue, as configured by
+/// the driver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    /// Descriptor table, or descriptor ring of a packed queue.
+    pub desc_table: u64,
+    /// Available ring, or driver event suppression area of a packed queue.
+    pub avail_ring: u64,
+    /// Used ring, or device event suppression area of a packed queue.
+    pub used_ring: u64,
+}
+
+/// A line for interrupting the guest, such as an `IrqLine`.
+pub trait InterruptLine: Send + Sync {
+    fn trigger(&self) -> io::Result<()>;
+}
+
+impl InterruptLine for IrqLine {
+    fn trigger(&self) -> io::Result<()> {
+        IrqLine::trigger(self)
+    }
+}
+
+/// Interrupt status shared by a device and its transport.
+pub struct Interrupt {
+    status: AtomicU32,
+    /// Bumped on every config change, so the driver can tell if config
+    /// space changed while it was reading it.
+    config_generation: AtomicU32,
+    line: Box<dyn InterruptLine>,
+}
+
+impl Interrupt {
+    pub fn new(line: Box<dyn InterruptLine>) -> Arc<Self> {
+        Arc::new(Interrupt {
+            status: AtomicU32::new(0),
+            config_generation: AtomicU32::new(0),
+            line,
+        })
+    }
+
+    /// Tell the driver that buffers were added to a used ring.
+    pub fn signal_used(&self) -> io::Result<()> {
+        self.signal(INT_USED_RING)
+    }
+
+    /// Tell the driver that the device's config space changed.
+    pub fn signal_config_changed(&self) -> io::Result<()> {
+        self.config_generation.fetch_add(1, Ordering::SeqCst);
+        self.signal(INT_CONFIG_CHANGED)
+    }
+
+    fn signal(&self, bits: u32) -> io::Result<()> {
+        self.status.fetch_or(bits, Ordering::SeqCst);
+        self.line.trigger()
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::SeqCst)
+    }
+
+    /// Clear status bits handled by the driver.
+    pub fn ack(&self, bits: u32) {
+        self.status.fetch_and(!bits, Ordering::SeqCst);
+    }
+
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation.load(Ordering::SeqCst)
+    }
+
+    fn reset(&self) {
+        self.status.store(0, Ordering::SeqCst);
+    }
+}
+
+/// A virtio device, independent of the transport it's exposed through.
+pub trait VirtioDevice: Send {
+    fn device_type(&self) -> u32;
+
+    /// Feature bits offered to the driver. `VIRTIO_F_VERSION_1` is always
+    /// offered by the transport.
+    fn features(&self) -> u64;
+
+    /// Maximum size of each of the device's queues.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Read from the device-specific config space. Bytes past the end of it
+    /// should read as 0.
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+
+    /// Start the device once the driver is ready, with the negotiated
+    /// features and the configuration of every queue.
+    fn activate(
+        &mut self,
+        features: u64,
+        queues: &[QueueConfig],
+        interrupt: Arc<Interrupt>,
+    ) -> Result<()>;
+
+    /// The driver made buffers available on a queue.
+    fn queue_notify(&mut self, queue: u16);
+
+    /// Stop using the queues and return to the initial state.
+    fn reset(&mut self);
+}
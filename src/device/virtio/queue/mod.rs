@@ -0,0 +1,356 @@
+//! Virtqueues, shared with the driver through guest memory.
+//!
+//! Queues use the split layout (see `split`), or the packed layout (see
+//! `packed`) if `VIRTIO_F_RING_PACKED` was negotiated. Either way devices
+//! take `DescriptorChain`s made available by the driver and return them
+//! once used. All fields in guest memory are little endian.
+
+mod packed;
+mod split;
+
+pub use packed::PackedQueue;
+pub use split::SplitQueue;
+
+use super::{QueueConfig, VIRTIO_F_RING_PACKED};
+use crate::memory::{Error as MemError, Memory, MemoryAddr};
+
+/// The buffer continues in the next descriptor.
+const DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device, rather than read.
+const DESC_F_WRITE: u16 = 2;
+/// The buffer holds a table of descriptors.
+const DESC_F_INDIRECT: u16 = 4;
+
+const DESC_SIZE: u64 = 16;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The queue size is zero, above the maximum, or not a power of two for
+    /// a split queue.
+    InvalidSize(u16),
+    /// A ring isn't aligned as the spec requires, or wraps around the
+    /// address space.
+    InvalidRing(u64),
+    /// The driver made more buffers available than fit in the queue.
+    InvalidAvailIdx(u16),
+    /// A descriptor refers to one past the end of its table.
+    InvalidIndex(u16),
+    /// A chain has more descriptors than its table or ring holds, so it
+    /// loops.
+    ChainLoop(u16),
+    /// An indirect descriptor that wasn't negotiated, is nested, is chained
+    /// to others or has a bad table length.
+    InvalidIndirect(u16),
+    /// A buffer wraps around the address space.
+    InvalidBuffer(u64, u32),
+    /// The device returned a chain with an id that isn't in use.
+    UnknownId(u16),
+    /// The driver made a chain available with an id that's still in use.
+    IdInUse(u16),
+    Memory(MemError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// One buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// Written by the device, rather than read.
+    pub write: bool,
+}
+
+/// The buffers of a request made available by the driver, with indirect
+/// tables already followed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorChain {
+    head: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// The id to return the chain with in `add_used`.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Descriptor> {
+        self.descriptors.iter()
+    }
+
+    /// Buffers the device reads from.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.iter().filter(|d| !d.write)
+    }
+
+    /// Buffers the device writes to.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.iter().filter(|d| d.write)
+    }
+}
+
+impl<'a> IntoIterator for &'a DescriptorChain {
+    type Item = &'a Descriptor;
+    type IntoIter = std::slice::Iter<'a, Descriptor>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Build a descriptor from the fields in guest memory.
+fn descriptor(addr: u64, len: u32, flags: u16) -> Result<Descriptor> {
+    if addr.checked_add(u64::from(len)).is_none() {
+        return Err(Error::InvalidBuffer(addr, len));
+    }
+    Ok(Descriptor {
+        addr,
+        len,
+        write: flags & DESC_F_WRITE != 0,
+    })
+}
+
+/// How the last four bytes of a descriptor are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Flags, then the index of the next descriptor.
+    Split,
+    /// Buffer id, then flags.
+    Packed,
+}
+
+/// A descriptor as laid out in a descriptor table, ring or indirect table.
+#[derive(Debug, Clone, Copy)]
+struct RawDescriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    /// Only used by split queues.
+    next: u16,
+    /// Only used by packed queues.
+    id: u16,
+}
+
+impl RawDescriptor {
+    fn read(mem: &dyn Memory, addr: u64, layout: Layout) -> Result<Self> {
+        let mut buf = [0; DESC_SIZE as usize];
+        read_exact(mem, addr, &mut buf)?;
+        let mut addr = [0; 8];
+        addr.copy_from_slice(&buf[0..8]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&buf[8..12]);
+        let first = u16::from_le_bytes([buf[12], buf[13]]);
+        let second = u16::from_le_bytes([buf[14], buf[15]]);
+        let (flags, next, id) = match layout {
+            Layout::Split => (first, second, 0),
+            Layout::Packed => (second, 0, first),
+        };
+        Ok(RawDescriptor {
+            addr: u64::from_le_bytes(addr),
+            len: u32::from_le_bytes(len),
+            flags,
+            next,
+            id,
+        })
+    }
+
+    fn descriptor(&self) -> Result<Descriptor> {
+        descriptor(self.addr, self.len, self.flags)
+    }
+}
+
+/// Read the table of the indirect descriptor `raw` at `index`, which must
+/// have been `negotiated` and end its chain. Split tables are chained with
+/// `DESC_F_NEXT`, while packed ones are used in order.
+fn read_indirect(
+    mem: &dyn Memory,
+    layout: Layout,
+    raw: &RawDescriptor,
+    index: u16,
+    negotiated: bool,
+    descriptors: &mut Vec<Descriptor>,
+) -> Result<()> {
+    let len = u64::from(raw.len);
+    let count = len / DESC_SIZE;
+    if !negotiated
+        || raw.flags & DESC_F_NEXT != 0
+        || len == 0
+        || len % DESC_SIZE != 0
+        || count > u64::from(u16::MAX)
+    {
+        return Err(Error::InvalidIndirect(index));
+    }
+    if raw.addr.checked_add(len).is_none() {
+        return Err(Error::InvalidBuffer(raw.addr, raw.len));
+    }
+    let mut i = 0;
+    for _ in 0..count {
+        if u64::from(i) >= count {
+            return Err(Error::InvalidIndex(i));
+        }
+        let desc = RawDescriptor::read(mem, raw.addr + DESC_SIZE * u64::from(i), layout)?;
+        if desc.flags & DESC_F_INDIRECT != 0 {
+            return Err(Error::InvalidIndirect(index));
+        }
+        descriptors.push(desc.descriptor()?);
+        i = match layout {
+            Layout::Split if desc.flags & DESC_F_NEXT != 0 => desc.next,
+            Layout::Packed if u64::from(i) + 1 < count => i + 1,
+            _ => return Ok(()),
+        };
+    }
+    Err(Error::ChainLoop(index))
+}
+
+fn read_exact(mem: &dyn Memory, addr: u64, buf: &mut [u8]) -> Result<()> {
+    let n = mem
+        .read(buf, MemoryAddr(addr as usize))
+        .map_err(Error::Memory)?;
+    if n != buf.len() {
+        return Err(Error::Memory(MemError::OutOfBounds));
+    }
+    Ok(())
+}
+
+fn write_all(mem: &mut dyn Memory, addr: u64, buf: &[u8]) -> Result<()> {
+    let n = mem
+        .write(buf, MemoryAddr(addr as usize))
+        .map_err(Error::Memory)?;
+    if n != buf.len() {
+        return Err(Error::Memory(MemError::OutOfBounds));
+    }
+    Ok(())
+}
+
+fn read_u16(mem: &dyn Memory, addr: u64) -> Result<u16> {
+    let mut buf = [0; 2];
+    read_exact(mem, addr, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn write_u16(mem: &mut dyn Memory, addr: u64, val: u16) -> Result<()> {
+    write_all(mem, addr, &val.to_le_bytes())
+}
+
+/// Whether the driver asked for an interrupt at `event` while the used index
+/// moved from `old` to `new`, from the spec's `vring_need_event`.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// A virtqueue of either layout.
+#[derive(Debug)]
+pub enum Queue {
+    Split(SplitQueue),
+    Packed(PackedQueue),
+}
+
+impl Queue {
+    /// Create a queue from its configuration, with the layout picked by the
+    /// negotiated features.
+    pub fn new(config: &QueueConfig, features: u64) -> Result<Self> {
+        if features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+            PackedQueue::new(config, features).map(Queue::Packed)
+        } else {
+            SplitQueue::new(config, features).map(Queue::Split)
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        match self {
+            Queue::Split(q) => q.size(),
+            Queue::Packed(q) => q.size(),
+        }
+    }
+
+    /// Take the next chain made available by the driver, if any. After an
+    /// error the queue is unusable until the device is reset.
+    pub fn pop(&mut self, mem: &dyn Memory) -> Result<Option<DescriptorChain>> {
+        match self {
+            Queue::Split(q) => q.pop(mem),
+            Queue::Packed(q) => q.pop(mem),
+        }
+    }
+
+    /// Return a chain to the driver, with the number of bytes written to it.
+    pub fn add_used(&mut self, mem: &mut dyn Memory, head: u16, len: u32) -> Result<()> {
+        match self {
+            Queue::Split(q) => q.add_used(mem, head, len),
+            Queue::Packed(q) => q.add_used(mem, head, len),
+        }
+    }
+
+    /// Whether the driver wants an interrupt for the chains returned since
+    /// the last call.
+    pub fn needs_notification(&mut self, mem: &dyn Memory) -> Result<bool> {
+        match self {
+            Queue::Split(q) => q.needs_notification(mem),
+            Queue::Packed(q) => q.needs_notification(mem),
+        }
+    }
+
+    /// Ask the driver to notify the device of new chains, returning whether
+    /// any are already available.
+    pub fn enable_notification(&mut self, mem: &mut dyn Memory) -> Result<bool> {
+        match self {
+            Queue::Split(q) => q.enable_notification(mem),
+            Queue::Packed(q) => q.enable_notification(mem),
+        }
+    }
+
+    /// Ask the driver not to notify the device. This is only a hint.
+    pub fn disable_notification(&mut self, mem: &mut dyn Memory) -> Result<()> {
+        match self {
+            Queue::Split(q) => q.disable_notification(mem),
+            Queue::Packed(q) => q.disable_notification(mem),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::memorymap::MemoryMmap;
+    use crate::memory::Addressable;
+
+    pub(super) const MEM_SIZE: usize = 1 << 20;
+    /// Where `config` places the descriptors, and the areas of the driver
+    /// and the device.
+    pub(super) const DESC: u64 = 0x1000;
+    pub(super) const AVAIL: u64 = 0x2000;
+    pub(super) const USED: u64 = 0x3000;
+
+    pub(super) fn config(size: u16) -> QueueConfig {
+        QueueConfig {
+            max_size: 256,
+            size,
+            ready: true,
+            desc_table: DESC,
+            avail_ring: AVAIL,
+            used_ring: USED,
+        }
+    }
+
+    pub(super) fn write(mem: &mut MemoryMmap, addr: u64, buf: &[u8]) {
+        mem.write(buf, MemoryAddr(addr as usize)).unwrap();
+    }
+
+    /// A queue of `size` placed by `config` in fresh memory, built by `new`.
+    pub(super) fn queue<Q>(
+        new: fn(&QueueConfig, u64) -> Result<Q>,
+        size: u16,
+        features: u64,
+    ) -> (MemoryMmap, Q) {
+        let mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        (mem, new(&config(size), features).unwrap())
+    }
+}
@@ -0,0 +1,546 @@
+//! Packed virtqueues.
+//!
+//! A packed queue has a single ring of descriptors. The driver makes them
+//! available and the device marks them used by setting flag bits to match a
+//! wrap counter, which each side flips whenever it wraps around the ring.
+//! Event suppression areas for the driver and device replace the ring flags
+//! and event indexes of split queues.
+
+use super::{
+    need_event, read_indirect, read_u16, write_all, write_u16, DescriptorChain, Error, Layout,
+    RawDescriptor, Result, DESC_F_INDIRECT, DESC_F_NEXT, DESC_SIZE,
+};
+use crate::device::virtio::{QueueConfig, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use crate::memory::Memory;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{fence, Ordering};
+
+const DESC_F_AVAIL: u16 = 1 << 7;
+const DESC_F_USED: u16 = 1 << 15;
+
+/// Event suppression flags.
+const EVENT_F_ENABLE: u16 = 0;
+const EVENT_F_DISABLE: u16 = 1;
+/// Only notify at the descriptor given in the suppression area.
+const EVENT_F_DESC: u16 = 2;
+
+/// Event suppression areas store the wrap counter in the top bit of the
+/// index, which limits the size of the ring.
+const MAX_SIZE: u16 = 1 << 15;
+const WRAP_BIT: u16 = 1 << 15;
+
+/// Whether a descriptor with `flags` was made available by the driver in
+/// the lap of the ring given by `wrap`.
+fn is_available(flags: u16, wrap: bool) -> bool {
+    (flags & DESC_F_AVAIL != 0) == wrap && (flags & DESC_F_USED != 0) != wrap
+}
+
+/// The device side of a packed virtqueue.
+#[derive(Debug)]
+pub struct PackedQueue {
+    size: u16,
+    desc_ring: u64,
+    driver_event: u64,
+    device_event: u64,
+    indirect: bool,
+    event_idx: bool,
+    /// Position in the ring of the next chain to pop, and its wrap counter.
+    next_avail: u16,
+    avail_wrap: bool,
+    /// Position in the ring of the next used descriptor, and its wrap
+    /// counter.
+    next_used: u16,
+    used_wrap: bool,
+    /// Ring entries taken by each chain popped but not yet returned, by id.
+    in_flight: HashMap<u16, u16>,
+    /// Ring entries returned since the driver was last checked for wanting
+    /// an interrupt.
+    unsignalled: u16,
+}
+
+impl PackedQueue {
+    /// Create a queue from its configuration and the negotiated features.
+    pub fn new(config: &QueueConfig, features: u64) -> Result<Self> {
+        let size = config.size;
+        if size == 0 || size > config.max_size || size > MAX_SIZE {
+            return Err(Error::InvalidSize(size));
+        }
+        for &(addr, align, len) in [
+            (config.desc_table, 16, DESC_SIZE * u64::from(size)),
+            (config.avail_ring, 4, 4),
+            (config.used_ring, 4, 4),
+        ]
+        .iter()
+        {
+            if addr % align != 0 || addr.checked_add(len).is_none() {
+                return Err(Error::InvalidRing(addr));
+            }
+        }
+        Ok(PackedQueue {
+            size,
+            desc_ring: config.desc_table,
+            driver_event: config.avail_ring,
+            device_event: config.used_ring,
+            indirect: features & (1 << VIRTIO_F_INDIRECT_DESC) != 0,
+            event_idx: features & (1 << VIRTIO_F_EVENT_IDX) != 0,
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            in_flight: HashMap::new(),
+            unsignalled: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn desc_addr(&self, index: u16) -> u64 {
+        self.desc_ring + DESC_SIZE * u64::from(index)
+    }
+
+    fn next_is_available(&self, mem: &dyn Memory) -> Result<bool> {
+        let flags = read_u16(mem, self.desc_addr(self.next_avail) + 14)?;
+        Ok(is_available(flags, self.avail_wrap))
+    }
+
+    /// Take the next chain made available by the driver, if any.
+    ///
+    /// The chain's id is the buffer id of its last descriptor.
+    pub fn pop(&mut self, mem: &dyn Memory) -> Result<Option<DescriptorChain>> {
+        if !self.next_is_available(mem)? {
+            return Ok(None);
+        }
+        // Don't read the rest of the chain before the flags that cover it.
+        fence(Ordering::Acquire);
+
+        let mut descriptors = Vec::new();
+        let mut index = self.next_avail;
+        let mut wrap = self.avail_wrap;
+        for count in 1..=self.size {
+            let raw = RawDescriptor::read(mem, self.desc_addr(index), Layout::Packed)?;
+            let at = index;
+            index += 1;
+            if index == self.size {
+                index = 0;
+                wrap = !wrap;
+            }
+
+            let last = if raw.flags & DESC_F_INDIRECT != 0 {
+                read_indirect(
+                    mem,
+                    Layout::Packed,
+                    &raw,
+                    at,
+                    self.indirect,
+                    &mut descriptors,
+                )?;
+                true
+            } else {
+                descriptors.push(raw.descriptor()?);
+                raw.flags & DESC_F_NEXT == 0
+            };
+            if last {
+                if self.in_flight.contains_key(&raw.id) {
+                    return Err(Error::IdInUse(raw.id));
+                }
+                self.next_avail = index;
+                self.avail_wrap = wrap;
+                self.in_flight.insert(raw.id, count);
+                return Ok(Some(DescriptorChain {
+                    head: raw.id,
+                    descriptors,
+                }));
+            }
+        }
+        Err(Error::ChainLoop(self.next_avail))
+    }
+
+    /// Return a chain to the driver, with the number of bytes written to it.
+    pub fn add_used(&mut self, mem: &mut dyn Memory, id: u16, len: u32) -> Result<()> {
+        let count = self.in_flight.remove(&id).ok_or(Error::UnknownId(id))?;
+        let addr = self.desc_addr(self.next_used);
+        let mut buf = [0; 6];
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+        buf[4..].copy_from_slice(&id.to_le_bytes());
+        write_all(mem, addr + 8, &buf)?;
+        let flags = if self.used_wrap {
+            DESC_F_AVAIL | DESC_F_USED
+        } else {
+            0
+        };
+        // The length and id must be visible before the flags that cover
+        // them.
+        fence(Ordering::Release);
+        write_u16(mem, addr + 14, flags)?;
+
+        // The used descriptor stands for every ring entry of the chain.
+        self.next_used += count;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap = !self.used_wrap;
+        }
+        self.unsignalled = self.unsignalled.wrapping_add(count);
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the chains returned since
+    /// the last call.
+    pub fn needs_notification(&mut self, mem: &dyn Memory) -> Result<bool> {
+        // Order the used flags write before reading what the driver wants.
+        fence(Ordering::SeqCst);
+        let unsignalled = mem::replace(&mut self.unsignalled, 0);
+        match read_u16(mem, self.driver_event + 2)? {
+            EVENT_F_DISABLE => Ok(false),
+            EVENT_F_DESC if self.event_idx => {
+                let off_wrap = read_u16(mem, self.driver_event)?;
+                // Compare positions as if the current lap of the ring starts
+                // at 0, and the previous one at -size.
+                let mut event = off_wrap & !WRAP_BIT;
+                if (off_wrap & WRAP_BIT != 0) != self.used_wrap {
+                    event = event.wrapping_sub(self.size);
+                }
+                let new = self.next_used;
+                Ok(need_event(event, new, new.wrapping_sub(unsignalled)))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Ask the driver to notify the device of new chains. Returns whether
+    /// chains were made available while notifications were off.
+    pub fn enable_notification(&mut self, mem: &mut dyn Memory) -> Result<bool> {
+        if self.event_idx {
+            let wrap = if self.avail_wrap { WRAP_BIT } else { 0 };
+            write_u16(mem, self.device_event, self.next_avail | wrap)?;
+            write_u16(mem, self.device_event + 2, EVENT_F_DESC)?;
+        } else {
+            write_u16(mem, self.device_event + 2, EVENT_F_ENABLE)?;
+        }
+        // Order the write before checking the ring, or a chain made available
+        // in between could be missed.
+        fence(Ordering::SeqCst);
+        self.next_is_available(mem)
+    }
+
+    /// Ask the driver not to notify the device. This is only a hint.
+    pub fn disable_notification(&mut self, mem: &mut dyn Memory) -> Result<()> {
+        write_u16(mem, self.device_event + 2, EVENT_F_DISABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{self, config, write, AVAIL, DESC, USED};
+    use super::super::{Descriptor, Queue, DESC_F_WRITE};
+    use super::*;
+    use crate::device::virtio::VIRTIO_F_RING_PACKED;
+    use crate::memory::memorymap::MemoryMmap;
+
+    const RING: u64 = DESC;
+    const DRIVER: u64 = AVAIL;
+    const DEVICE: u64 = USED;
+    const SIZE: u16 = 6;
+
+    fn queue(features: u64) -> (MemoryMmap, PackedQueue) {
+        tests::queue(PackedQueue::new, SIZE, features)
+    }
+
+    fn read_desc(mem: &MemoryMmap, i: u16) -> RawDescriptor {
+        RawDescriptor::read(mem, RING + DESC_SIZE * u64::from(i), Layout::Packed).unwrap()
+    }
+
+    fn set_desc(mem: &mut MemoryMmap, at: u64, addr: u64, len: u32, id: u16, flags: u16) {
+        write(mem, at, &addr.to_le_bytes());
+        write(mem, at + 8, &len.to_le_bytes());
+        write(mem, at + 12, &id.to_le_bytes());
+        write(mem, at + 14, &flags.to_le_bytes());
+    }
+
+    /// Make a descriptor in the ring available for the lap given by `wrap`.
+    fn avail_desc(mem: &mut MemoryMmap, i: u16, wrap: bool, len: u32, id: u16, flags: u16) {
+        let avail = if wrap { DESC_F_AVAIL } else { DESC_F_USED };
+        let at = RING + DESC_SIZE * u64::from(i);
+        set_desc(
+            mem,
+            at,
+            0x8000 + 0x100 * u64::from(i),
+            len,
+            id,
+            flags | avail,
+        );
+    }
+
+    #[test]
+    fn config_validation() {
+        let mut c = config(SIZE);
+        assert!(PackedQueue::new(&c, 0).is_ok());
+        c.size = 0;
+        assert!(PackedQueue::new(&c, 0).is_err());
+        c.size = 257;
+        assert!(PackedQueue::new(&c, 0).is_err());
+        c.max_size = u16::MAX;
+        c.size = MAX_SIZE + 1;
+        assert!(PackedQueue::new(&c, 0).is_err());
+        c.size = SIZE;
+        c.desc_table = 0x1008;
+        assert!(PackedQueue::new(&c, 0).is_err());
+        c.desc_table = RING;
+        c.avail_ring = 0x2002;
+        assert!(PackedQueue::new(&c, 0).is_err());
+        c.avail_ring = DRIVER;
+        c.used_ring = u64::MAX - 3;
+        assert!(PackedQueue::new(&c, 0).is_err());
+    }
+
+    #[test]
+    fn layout_selection() {
+        let mut c = config(SIZE);
+        c.size = 8;
+        match Queue::new(&c, 1 << VIRTIO_F_RING_PACKED).unwrap() {
+            Queue::Packed(_) => (),
+            q => panic!("unexpected queue {:?}", q),
+        }
+        match Queue::new(&c, 0).unwrap() {
+            Queue::Split(_) => (),
+            q => panic!("unexpected queue {:?}", q),
+        }
+        // Packed queues don't need a power of two size.
+        assert!(Queue::new(&config(SIZE), 1 << VIRTIO_F_RING_PACKED).is_ok());
+        assert!(Queue::new(&config(SIZE), 0).is_err());
+    }
+
+    #[test]
+    fn chains() {
+        let (mut mem, mut q) = queue(0);
+        assert_eq!(None, q.pop(&mem).unwrap());
+
+        avail_desc(&mut mem, 0, true, 0x10, 0, DESC_F_NEXT);
+        avail_desc(&mut mem, 1, true, 0x200, 0, DESC_F_NEXT | DESC_F_WRITE);
+        avail_desc(&mut mem, 2, true, 1, 7, DESC_F_WRITE);
+        avail_desc(&mut mem, 3, true, 0x20, 3, 0);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(7, chain.head());
+        let readable: Vec<_> = chain.readable().map(|d| d.addr).collect();
+        let writable: Vec<_> = chain.writable().map(|d| (d.addr, d.len)).collect();
+        assert_eq!(vec![0x8000], readable);
+        assert_eq!(vec![(0x8100, 0x200), (0x8200, 1)], writable);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(3, chain.head());
+        assert_eq!(
+            vec![&Descriptor {
+                addr: 0x8300,
+                len: 0x20,
+                write: false
+            }],
+            chain.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(None, q.pop(&mem).unwrap());
+
+        // Chains may be returned out of order, each taking the place of as
+        // many ring entries as it had.
+        q.add_used(&mut mem, 3, 0).unwrap();
+        q.add_used(&mut mem, 7, 0x201).unwrap();
+        let used = read_desc(&mem, 0);
+        assert_eq!(
+            (3, 0, DESC_F_AVAIL | DESC_F_USED),
+            (used.id, used.len, used.flags)
+        );
+        let used = read_desc(&mem, 1);
+        assert_eq!(
+            (7, 0x201, DESC_F_AVAIL | DESC_F_USED),
+            (used.id, used.len, used.flags)
+        );
+        assert_eq!(4, q.next_used);
+
+        match q.add_used(&mut mem, 7, 0).unwrap_err() {
+            Error::UnknownId(7) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn id_in_use() {
+        let (mut mem, mut q) = queue(0);
+        avail_desc(&mut mem, 0, true, 4, 2, 0);
+        avail_desc(&mut mem, 1, true, 4, 2, 0);
+        assert_eq!(2, q.pop(&mem).unwrap().unwrap().head());
+        match q.pop(&mem).unwrap_err() {
+            Error::IdInUse(2) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(Some(&1), q.in_flight.get(&2));
+    }
+
+    #[test]
+    fn ring_wrapping() {
+        let (mut mem, mut q) = queue(0);
+        q.next_avail = 4;
+        q.next_used = 4;
+        avail_desc(&mut mem, 4, true, 4, 1, DESC_F_NEXT);
+        avail_desc(&mut mem, 5, true, 4, 1, DESC_F_NEXT);
+        avail_desc(&mut mem, 0, false, 4, 1, 0);
+        avail_desc(&mut mem, 1, false, 4, 2, 0);
+        // Still marked for the first lap, so not available.
+        avail_desc(&mut mem, 2, true, 4, 3, 0);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(1, chain.head());
+        assert_eq!(
+            vec![0x8400, 0x8500, 0x8000],
+            chain.iter().map(|d| d.addr).collect::<Vec<_>>()
+        );
+        assert_eq!(2, q.pop(&mem).unwrap().unwrap().head());
+        assert_eq!(None, q.pop(&mem).unwrap());
+        assert_eq!((2, false), (q.next_avail, q.avail_wrap));
+
+        q.add_used(&mut mem, 1, 0).unwrap();
+        assert_eq!(DESC_F_AVAIL | DESC_F_USED, read_desc(&mem, 4).flags);
+        assert_eq!((1, false), (q.next_used, q.used_wrap));
+        q.add_used(&mut mem, 2, 0).unwrap();
+        // Used descriptors of the second lap have both flags clear.
+        assert_eq!(0, read_desc(&mem, 1).flags);
+    }
+
+    #[test]
+    fn chain_loop() {
+        let (mut mem, mut q) = queue(0);
+        for i in 0..SIZE {
+            avail_desc(&mut mem, i, true, 4, 0, DESC_F_NEXT);
+        }
+        match q.pop(&mem).unwrap_err() {
+            Error::ChainLoop(0) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn indirect() {
+        const TABLE: u64 = 0x4000;
+        let (mut mem, mut q) = queue(1 << VIRTIO_F_INDIRECT_DESC);
+        avail_desc(&mut mem, 0, true, 0x10, 0, DESC_F_NEXT);
+        set_desc(
+            &mut mem,
+            RING + 16,
+            TABLE,
+            32,
+            5,
+            DESC_F_INDIRECT | DESC_F_AVAIL,
+        );
+        set_desc(&mut mem, TABLE, 0x9000, 8, 0, 0);
+        set_desc(&mut mem, TABLE + 16, 0xa000, 0x100, 0, DESC_F_WRITE);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(5, chain.head());
+        let addrs: Vec<_> = chain.iter().map(|d| (d.addr, d.write)).collect();
+        assert_eq!(
+            vec![(0x8000, false), (0x9000, false), (0xa000, true)],
+            addrs
+        );
+        assert_eq!(Some(&2), q.in_flight.get(&5));
+
+        // Nested.
+        set_desc(
+            &mut mem,
+            RING + 32,
+            TABLE,
+            32,
+            6,
+            DESC_F_INDIRECT | DESC_F_AVAIL,
+        );
+        set_desc(&mut mem, TABLE + 16, TABLE, 16, 0, DESC_F_INDIRECT);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(2) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Bad table length.
+        set_desc(
+            &mut mem,
+            RING + 32,
+            TABLE,
+            24,
+            6,
+            DESC_F_INDIRECT | DESC_F_AVAIL,
+        );
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(2) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Not negotiated.
+        let (mut mem, mut q) = queue(0);
+        set_desc(&mut mem, RING, TABLE, 16, 0, DESC_F_INDIRECT | DESC_F_AVAIL);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(0) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn notification_flags() {
+        let (mut mem, mut q) = queue(0);
+        assert!(q.needs_notification(&mem).unwrap());
+        write(&mut mem, DRIVER + 2, &EVENT_F_DISABLE.to_le_bytes());
+        assert!(!q.needs_notification(&mem).unwrap());
+        // Descriptor events aren't used without event indexes.
+        write(&mut mem, DRIVER + 2, &EVENT_F_DESC.to_le_bytes());
+        assert!(q.needs_notification(&mem).unwrap());
+
+        q.disable_notification(&mut mem).unwrap();
+        assert_eq!(EVENT_F_DISABLE, read_u16(&mem, DEVICE + 2).unwrap());
+        assert!(!q.enable_notification(&mut mem).unwrap());
+        assert_eq!(EVENT_F_ENABLE, read_u16(&mem, DEVICE + 2).unwrap());
+
+        avail_desc(&mut mem, 0, true, 4, 0, 0);
+        assert!(q.enable_notification(&mut mem).unwrap());
+    }
+
+    #[test]
+    fn event_idx() {
+        let (mut mem, mut q) = queue(1 << VIRTIO_F_EVENT_IDX);
+        for i in 0..SIZE {
+            avail_desc(&mut mem, i, true, 4, i, 0);
+        }
+        for _ in 0..SIZE {
+            q.pop(&mem).unwrap().unwrap();
+        }
+
+        // The driver wants an interrupt once used entry 2 of the first lap
+        // is written.
+        write(&mut mem, DRIVER, &(2 | WRAP_BIT).to_le_bytes());
+        write(&mut mem, DRIVER + 2, &EVENT_F_DESC.to_le_bytes());
+        q.add_used(&mut mem, 0, 0).unwrap();
+        q.add_used(&mut mem, 1, 0).unwrap();
+        assert!(!q.needs_notification(&mem).unwrap());
+        q.add_used(&mut mem, 2, 0).unwrap();
+        assert!(q.needs_notification(&mem).unwrap());
+        assert!(!q.needs_notification(&mem).unwrap());
+
+        // And then at the last entry of the first lap, which is only checked
+        // once the device wrapped.
+        write(&mut mem, DRIVER, &(5 | WRAP_BIT).to_le_bytes());
+        q.add_used(&mut mem, 3, 0).unwrap();
+        q.add_used(&mut mem, 4, 0).unwrap();
+        assert!(!q.needs_notification(&mem).unwrap());
+        q.add_used(&mut mem, 5, 0).unwrap();
+        assert!(!q.used_wrap);
+        assert!(q.needs_notification(&mem).unwrap());
+
+        // An event in a lap that already passed doesn't interrupt.
+        avail_desc(&mut mem, 0, false, 4, 0, 0);
+        q.pop(&mem).unwrap().unwrap();
+        q.add_used(&mut mem, 0, 0).unwrap();
+        assert!(!q.needs_notification(&mem).unwrap());
+
+        assert!(!q.enable_notification(&mut mem).unwrap());
+        assert_eq!(1, read_u16(&mem, DEVICE).unwrap());
+        assert_eq!(EVENT_F_DESC, read_u16(&mem, DEVICE + 2).unwrap());
+        avail_desc(&mut mem, 1, false, 4, 1, 0);
+        assert!(q.enable_notification(&mut mem).unwrap());
+    }
+}
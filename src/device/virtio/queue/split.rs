@@ -0,0 +1,583 @@
+//! Split virtqueues.
+//!
+//! A split queue has three parts: the descriptor table describing buffers,
+//! the available ring where the driver offers chains of descriptors, and the
+//! used ring where the device returns them.
+
+use super::{
+    need_event, read_indirect, read_u16, write_all, write_u16, DescriptorChain, Error, Layout,
+    RawDescriptor, Result, DESC_F_INDIRECT, DESC_F_NEXT, DESC_SIZE,
+};
+use crate::device::virtio::{QueueConfig, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use crate::memory::Memory;
+use std::sync::atomic::{fence, Ordering};
+
+/// Set by the driver when it doesn't want interrupts.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Set by the device when it doesn't want notifications.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+const USED_ELEM_SIZE: u64 = 8;
+
+/// The device side of a split virtqueue.
+#[derive(Debug)]
+pub struct SplitQueue {
+    size: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    indirect: bool,
+    event_idx: bool,
+    /// Index in the available ring of the next chain to pop.
+    next_avail: u16,
+    /// Index in the used ring of the next chain to return.
+    next_used: u16,
+    /// Whether the chain starting at each descriptor was popped and not yet
+    /// returned.
+    in_flight: Vec<bool>,
+    /// The used index when the driver was last checked for wanting an
+    /// interrupt, or `None` if it hasn't been yet.
+    signalled_used: Option<u16>,
+}
+
+impl SplitQueue {
+    /// Create a queue from its configuration and the negotiated features.
+    pub fn new(config: &QueueConfig, features: u64) -> Result<Self> {
+        let size = config.size;
+        if size == 0 || size > config.max_size || size & (size - 1) != 0 {
+            return Err(Error::InvalidSize(size));
+        }
+        let size64 = u64::from(size);
+        for &(addr, align, len) in [
+            (config.desc_table, 16, DESC_SIZE * size64),
+            (config.avail_ring, 2, 6 + 2 * size64),
+            (config.used_ring, 4, 6 + USED_ELEM_SIZE * size64),
+        ]
+        .iter()
+        {
+            if addr % align != 0 || addr.checked_add(len).is_none() {
+                return Err(Error::InvalidRing(addr));
+            }
+        }
+        Ok(SplitQueue {
+            size,
+            desc_table: config.desc_table,
+            avail_ring: config.avail_ring,
+            used_ring: config.used_ring,
+            indirect: features & (1 << VIRTIO_F_INDIRECT_DESC) != 0,
+            event_idx: features & (1 << VIRTIO_F_EVENT_IDX) != 0,
+            next_avail: 0,
+            next_used: 0,
+            in_flight: vec![false; usize::from(size)],
+            signalled_used: None,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn avail_idx(&self, mem: &dyn Memory) -> Result<u16> {
+        read_u16(mem, self.avail_ring + 2)
+    }
+
+    /// Where the driver says which used index it wants an interrupt at.
+    fn used_event_addr(&self) -> u64 {
+        self.avail_ring + 4 + 2 * u64::from(self.size)
+    }
+
+    /// Where the device says which available index it wants a notification
+    /// at.
+    fn avail_event_addr(&self) -> u64 {
+        self.used_ring + 4 + USED_ELEM_SIZE * u64::from(self.size)
+    }
+
+    /// Take the next chain made available by the driver, if any.
+    ///
+    /// A chain that fails to parse is still taken off the available ring,
+    /// and the queue is unusable until the device is reset.
+    pub fn pop(&mut self, mem: &dyn Memory) -> Result<Option<DescriptorChain>> {
+        let avail_idx = self.avail_idx(mem)?;
+        let pending = avail_idx.wrapping_sub(self.next_avail);
+        if pending == 0 {
+            return Ok(None);
+        }
+        if pending > self.size {
+            return Err(Error::InvalidAvailIdx(avail_idx));
+        }
+        // Don't read the ring entry before the index that covers it.
+        fence(Ordering::Acquire);
+
+        let slot = self.next_avail % self.size;
+        let head = read_u16(mem, self.avail_ring + 4 + 2 * u64::from(slot))?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        let chain = self.read_chain(mem, head)?;
+        let in_flight = &mut self.in_flight[usize::from(head)];
+        if *in_flight {
+            return Err(Error::IdInUse(head));
+        }
+        *in_flight = true;
+        Ok(Some(chain))
+    }
+
+    fn read_chain(&self, mem: &dyn Memory, head: u16) -> Result<DescriptorChain> {
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        // Each descriptor may be visited at most once.
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(Error::InvalidIndex(index));
+            }
+            let addr = self.desc_table + DESC_SIZE * u64::from(index);
+            let raw = RawDescriptor::read(mem, addr, Layout::Split)?;
+            if raw.flags & DESC_F_INDIRECT != 0 {
+                read_indirect(
+                    mem,
+                    Layout::Split,
+                    &raw,
+                    index,
+                    self.indirect,
+                    &mut descriptors,
+                )?;
+                return Ok(DescriptorChain { head, descriptors });
+            }
+            descriptors.push(raw.descriptor()?);
+            if raw.flags & DESC_F_NEXT == 0 {
+                return Ok(DescriptorChain { head, descriptors });
+            }
+            index = raw.next;
+        }
+        Err(Error::ChainLoop(head))
+    }
+
+    /// Return a chain to the driver, with the number of bytes written to it.
+    pub fn add_used(&mut self, mem: &mut dyn Memory, head: u16, len: u32) -> Result<()> {
+        match self.in_flight.get_mut(usize::from(head)) {
+            Some(popped) if *popped => *popped = false,
+            _ => return Err(Error::UnknownId(head)),
+        }
+        let slot = self.next_used % self.size;
+        let mut elem = [0; USED_ELEM_SIZE as usize];
+        elem[..4].copy_from_slice(&u32::from(head).to_le_bytes());
+        elem[4..].copy_from_slice(&len.to_le_bytes());
+        write_all(
+            mem,
+            self.used_ring + 4 + USED_ELEM_SIZE * u64::from(slot),
+            &elem,
+        )?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The element must be visible before the index that covers it.
+        fence(Ordering::Release);
+        write_u16(mem, self.used_ring + 2, self.next_used)
+    }
+
+    /// Whether the driver wants an interrupt for the chains returned since
+    /// the last call.
+    pub fn needs_notification(&mut self, mem: &dyn Memory) -> Result<bool> {
+        // Order the used index write before reading what the driver wants.
+        fence(Ordering::SeqCst);
+        if !self.event_idx {
+            let flags = read_u16(mem, self.avail_ring)?;
+            return Ok(flags & AVAIL_F_NO_INTERRUPT == 0);
+        }
+        let used_event = read_u16(mem, self.used_event_addr())?;
+        let new = self.next_used;
+        let needed = match self.signalled_used {
+            Some(old) => need_event(used_event, new, old),
+            None => true,
+        };
+        self.signalled_used = Some(new);
+        Ok(needed)
+    }
+
+    /// Ask the driver to notify the device of new chains. Returns whether
+    /// chains were made available while notifications were off, which the
+    /// device has to handle without waiting for a notification.
+    pub fn enable_notification(&mut self, mem: &mut dyn Memory) -> Result<bool> {
+        if self.event_idx {
+            write_u16(mem, self.avail_event_addr(), self.next_avail)?;
+        } else {
+            let flags = read_u16(mem, self.used_ring)?;
+            write_u16(mem, self.used_ring, flags & !USED_F_NO_NOTIFY)?;
+        }
+        // Order the write before reading the available index, or a chain
+        // made available in between could be missed.
+        fence(Ordering::SeqCst);
+        Ok(self.avail_idx(mem)? != self.next_avail)
+    }
+
+    /// Ask the driver not to notify the device, while it's processing chains
+    /// anyway. This is only a hint.
+    pub fn disable_notification(&mut self, mem: &mut dyn Memory) -> Result<()> {
+        // With event indexes the driver stops notifying once it's past the
+        // last index written by `enable_notification`.
+        if self.event_idx {
+            return Ok(());
+        }
+        let flags = read_u16(mem, self.used_ring)?;
+        write_u16(mem, self.used_ring, flags | USED_F_NO_NOTIFY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{self, config, write, AVAIL, DESC, MEM_SIZE, USED};
+    use super::super::{Descriptor, DESC_F_WRITE};
+    use super::*;
+    use crate::memory::memorymap::MemoryMmap;
+    use crate::memory::{Addressable, MemoryAddr};
+
+    const SIZE: u16 = 8;
+
+    fn queue(features: u64) -> (MemoryMmap, SplitQueue) {
+        tests::queue(SplitQueue::new, SIZE, features)
+    }
+
+    fn read_u32(mem: &MemoryMmap, addr: u64) -> u32 {
+        let mut buf = [0; 4];
+        mem.read(&mut buf, MemoryAddr(addr as usize)).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    fn set_desc(
+        mem: &mut MemoryMmap,
+        table: u64,
+        i: u16,
+        addr: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let at = table + DESC_SIZE * u64::from(i);
+        write(mem, at, &addr.to_le_bytes());
+        write(mem, at + 8, &len.to_le_bytes());
+        write(mem, at + 12, &flags.to_le_bytes());
+        write(mem, at + 14, &next.to_le_bytes());
+    }
+
+    /// Make `heads` available, starting at avail index `start`.
+    fn make_avail(mem: &mut MemoryMmap, start: u16, heads: &[u16]) {
+        let mut idx = start;
+        for &head in heads {
+            write(
+                mem,
+                AVAIL + 4 + 2 * u64::from(idx % SIZE),
+                &head.to_le_bytes(),
+            );
+            idx = idx.wrapping_add(1);
+        }
+        write(mem, AVAIL + 2, &idx.to_le_bytes());
+    }
+
+    #[test]
+    fn config_validation() {
+        let mut c = config(SIZE);
+        c.size = 0;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.size = 6;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.size = 512;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.size = 256;
+        assert!(SplitQueue::new(&c, 0).is_ok());
+        c.desc_table = 0x1008;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.desc_table = DESC;
+        c.avail_ring = 0x2001;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.avail_ring = AVAIL;
+        c.used_ring = 0x3002;
+        assert!(SplitQueue::new(&c, 0).is_err());
+        c.used_ring = u64::MAX - 3;
+        assert!(SplitQueue::new(&c, 0).is_err());
+    }
+
+    #[test]
+    fn chains() {
+        let (mut mem, mut q) = queue(0);
+        assert_eq!(None, q.pop(&mem).unwrap());
+
+        set_desc(&mut mem, DESC, 3, 0x8000, 0x10, DESC_F_NEXT, 5);
+        set_desc(
+            &mut mem,
+            DESC,
+            5,
+            0x9000,
+            0x200,
+            DESC_F_NEXT | DESC_F_WRITE,
+            1,
+        );
+        set_desc(&mut mem, DESC, 1, 0xa000, 1, DESC_F_WRITE, 0);
+        set_desc(&mut mem, DESC, 0, 0xb000, 0x20, 0, 0);
+        make_avail(&mut mem, 0, &[3, 0]);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(3, chain.head());
+        assert_eq!(3, chain.len());
+        let readable: Vec<_> = chain.readable().map(|d| d.addr).collect();
+        let writable: Vec<_> = chain.writable().map(|d| (d.addr, d.len)).collect();
+        assert_eq!(vec![0x8000], readable);
+        assert_eq!(vec![(0x9000, 0x200), (0xa000, 1)], writable);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(0, chain.head());
+        assert_eq!(
+            vec![&Descriptor {
+                addr: 0xb000,
+                len: 0x20,
+                write: false
+            }],
+            chain.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(None, q.pop(&mem).unwrap());
+
+        q.add_used(&mut mem, 3, 0x201).unwrap();
+        q.add_used(&mut mem, 0, 0).unwrap();
+        assert_eq!(3, read_u32(&mem, USED + 4));
+        assert_eq!(0x201, read_u32(&mem, USED + 8));
+        assert_eq!(0, read_u32(&mem, USED + 12));
+        assert_eq!(2, read_u16(&mem, USED + 2).unwrap());
+
+        // Chains can only be returned once, and only if popped.
+        for &head in [3, 5, SIZE].iter() {
+            match q.add_used(&mut mem, head, 0).unwrap_err() {
+                Error::UnknownId(id) => assert_eq!(head, id),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        assert_eq!(2, read_u16(&mem, USED + 2).unwrap());
+    }
+
+    #[test]
+    fn index_wrapping() {
+        let (mut mem, mut q) = queue(0);
+        q.next_avail = 0xfffe;
+        q.next_used = 0xffff;
+        set_desc(&mut mem, DESC, 0, 0x8000, 4, 0, 0);
+        set_desc(&mut mem, DESC, 1, 0x9000, 4, 0, 0);
+        set_desc(&mut mem, DESC, 2, 0xa000, 4, 0, 0);
+        make_avail(&mut mem, 0xfffe, &[0, 1, 2]);
+
+        let heads: Vec<_> = (0..3)
+            .map(|_| q.pop(&mem).unwrap().unwrap().head())
+            .collect();
+        assert_eq!(vec![0, 1, 2], heads);
+        assert_eq!(None, q.pop(&mem).unwrap());
+
+        q.add_used(&mut mem, 1, 4).unwrap();
+        // Slot 0xffff % 8 is the last one.
+        assert_eq!(1, read_u32(&mem, USED + 4 + 8 * 7));
+        assert_eq!(0, read_u16(&mem, USED + 2).unwrap());
+
+        // Only returned chains may be made available again.
+        make_avail(&mut mem, 1, &[1, 0]);
+        assert_eq!(1, q.pop(&mem).unwrap().unwrap().head());
+        match q.pop(&mem).unwrap_err() {
+            Error::IdInUse(0) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_avail_idx() {
+        let (mut mem, mut q) = queue(0);
+        write(&mut mem, AVAIL + 2, &(SIZE + 1).to_le_bytes());
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidAvailIdx(9) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_chains() {
+        let (mut mem, mut q) = queue(0);
+        // Out of range head, then next.
+        make_avail(&mut mem, 0, &[SIZE, 0]);
+        set_desc(&mut mem, DESC, 0, 0x8000, 4, DESC_F_NEXT, SIZE);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndex(8) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndex(8) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // A loop through every descriptor.
+        for i in 0..SIZE {
+            set_desc(&mut mem, DESC, i, 0x8000, 4, DESC_F_NEXT, (i + 1) % SIZE);
+        }
+        make_avail(&mut mem, 2, &[4]);
+        match q.pop(&mem).unwrap_err() {
+            Error::ChainLoop(4) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // A buffer past the end of the address space.
+        set_desc(&mut mem, DESC, 0, u64::MAX - 1, 4, 0, 0);
+        make_avail(&mut mem, 3, &[0]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidBuffer(_, 4) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn table_outside_memory() {
+        let mut c = config(SIZE);
+        c.desc_table = MEM_SIZE as u64;
+        let mut mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        let mut q = SplitQueue::new(&c, 0).unwrap();
+        make_avail(&mut mem, 0, &[0]);
+        match q.pop(&mem).unwrap_err() {
+            Error::Memory(_) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn indirect() {
+        const TABLE: u64 = 0x4000;
+        let (mut mem, mut q) = queue(1 << VIRTIO_F_INDIRECT_DESC);
+        set_desc(&mut mem, DESC, 2, 0x8000, 0x10, DESC_F_NEXT, 6);
+        set_desc(
+            &mut mem,
+            DESC,
+            6,
+            TABLE,
+            3 * DESC_SIZE as u32,
+            DESC_F_INDIRECT,
+            0,
+        );
+        set_desc(&mut mem, TABLE, 0, 0x9000, 8, DESC_F_NEXT, 2);
+        set_desc(&mut mem, TABLE, 2, 0xa000, 0x100, DESC_F_WRITE, 0);
+        make_avail(&mut mem, 0, &[2]);
+
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(2, chain.head());
+        let addrs: Vec<_> = chain.iter().map(|d| (d.addr, d.write)).collect();
+        assert_eq!(
+            vec![(0x8000, false), (0x9000, false), (0xa000, true)],
+            addrs
+        );
+
+        // Past the end of the table.
+        set_desc(&mut mem, TABLE, 2, 0xa000, 0x100, DESC_F_NEXT, 3);
+        make_avail(&mut mem, 1, &[2]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndex(3) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Looping within the table.
+        set_desc(&mut mem, TABLE, 2, 0xa000, 0x100, DESC_F_NEXT, 0);
+        make_avail(&mut mem, 2, &[2]);
+        match q.pop(&mem).unwrap_err() {
+            Error::ChainLoop(6) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Nested.
+        set_desc(&mut mem, TABLE, 2, TABLE, 16, DESC_F_INDIRECT, 0);
+        make_avail(&mut mem, 3, &[2]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(6) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Bad table length.
+        set_desc(&mut mem, DESC, 6, TABLE, 20, DESC_F_INDIRECT, 0);
+        make_avail(&mut mem, 4, &[6]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(6) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Chained after the table.
+        set_desc(
+            &mut mem,
+            DESC,
+            6,
+            TABLE,
+            16,
+            DESC_F_INDIRECT | DESC_F_NEXT,
+            0,
+        );
+        make_avail(&mut mem, 5, &[6]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(6) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn indirect_not_negotiated() {
+        let (mut mem, mut q) = queue(0);
+        set_desc(&mut mem, DESC, 0, 0x4000, 16, DESC_F_INDIRECT, 0);
+        make_avail(&mut mem, 0, &[0]);
+        match q.pop(&mem).unwrap_err() {
+            Error::InvalidIndirect(0) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn notification_flags() {
+        let (mut mem, mut q) = queue(0);
+        assert!(q.needs_notification(&mem).unwrap());
+        write(&mut mem, AVAIL, &AVAIL_F_NO_INTERRUPT.to_le_bytes());
+        assert!(!q.needs_notification(&mem).unwrap());
+
+        q.disable_notification(&mut mem).unwrap();
+        assert_eq!(USED_F_NO_NOTIFY, read_u16(&mem, USED).unwrap());
+        assert!(!q.enable_notification(&mut mem).unwrap());
+        assert_eq!(0, read_u16(&mem, USED).unwrap());
+
+        make_avail(&mut mem, 0, &[0]);
+        assert!(q.enable_notification(&mut mem).unwrap());
+    }
+
+    #[test]
+    fn event_idx() {
+        let (mut mem, mut q) = queue(1 << VIRTIO_F_EVENT_IDX);
+        let used_event = AVAIL + 4 + 2 * u64::from(SIZE);
+        let avail_event = USED + 4 + 8 * u64::from(SIZE);
+        for i in 0..SIZE {
+            set_desc(&mut mem, DESC, i, 0x8000, 4, 0, 0);
+        }
+        make_avail(&mut mem, 0, &[0, 1, 2, 3, 4]);
+        for _ in 0..5 {
+            q.pop(&mem).unwrap().unwrap();
+        }
+
+        // The first check always interrupts.
+        assert!(q.needs_notification(&mem).unwrap());
+
+        // The driver wants an interrupt once used entry 2 is added.
+        write(&mut mem, used_event, &2u16.to_le_bytes());
+        q.add_used(&mut mem, 0, 0).unwrap();
+        assert!(!q.needs_notification(&mem).unwrap());
+        q.add_used(&mut mem, 1, 0).unwrap();
+        assert!(!q.needs_notification(&mem).unwrap());
+        q.add_used(&mut mem, 2, 0).unwrap();
+        q.add_used(&mut mem, 3, 0).unwrap();
+        assert!(q.needs_notification(&mem).unwrap());
+        assert!(!q.needs_notification(&mem).unwrap());
+
+        // Flags are ignored.
+        write(&mut mem, AVAIL, &AVAIL_F_NO_INTERRUPT.to_le_bytes());
+        write(&mut mem, used_event, &4u16.to_le_bytes());
+        q.add_used(&mut mem, 4, 0).unwrap();
+        assert!(q.needs_notification(&mem).unwrap());
+
+        make_avail(&mut mem, 5, &[5, 6]);
+        q.pop(&mem).unwrap().unwrap();
+        q.disable_notification(&mut mem).unwrap();
+        assert_eq!(0, read_u16(&mem, USED).unwrap());
+        assert!(q.enable_notification(&mut mem).unwrap());
+        assert_eq!(6, read_u16(&mem, avail_event).unwrap());
+        q.pop(&mem).unwrap().unwrap();
+        assert!(!q.enable_notification(&mut mem).unwrap());
+        assert_eq!(7, read_u16(&mem, avail_event).unwrap());
+    }
+}
@@ -0,0 +1,814 @@
+//! A gdb remote stub for debugging guest kernels.
+//!
+//! The stub speaks the gdb remote serial protocol over a connection from
+//! gdb (`target remote HOST:PORT`). Each vcpu is presented as a thread.
+//! Software breakpoints are inserted by writing int3 into guest memory, and
+//! hardware breakpoints and watchpoints use the debug registers. int3s that
+//! gdb didn't insert belong to the guest and are handed back to it.
+
+mod packet;
+pub mod target;
+
+use crate::vm::exit::{DebugExit, VcpuExit};
+use crate::vm::Error as VmError;
+use log::{debug, info};
+use packet::{ack, decode_hex, encode_hex, read_packet, write_packet, Packet};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Vm(VmError),
+    /// The guest virtual address isn't mapped.
+    Unmapped(u64),
+    /// The target isn't stopped, or isn't running, as needed.
+    InvalidState,
+    InvalidVcpu(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<VmError> for Error {
+    fn from(e: VmError) -> Self {
+        Error::Vm(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The registers gdb expects for x86-64, in order.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub eflags: u32,
+    /// cs, ss, ds, es, fs, gs
+    pub segments: [u32; 6],
+}
+
+const REGISTERS_LEN: usize = 16 * 8 + 8 + 4 + 6 * 4;
+
+impl Registers {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(REGISTERS_LEN);
+        for reg in self.gprs.iter().chain(Some(&self.rip)) {
+            bytes.extend_from_slice(&reg.to_le_bytes());
+        }
+        for reg in Some(&self.eflags).into_iter().chain(self.segments.iter()) {
+            bytes.extend_from_slice(&reg.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parse registers sent by gdb. Any registers after the ones above are
+    /// ignored.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < REGISTERS_LEN {
+            return None;
+        }
+        let u64_at = |i: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_le_bytes(b)
+        };
+        let u32_at = |i: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&bytes[i..i + 4]);
+            u32::from_le_bytes(b)
+        };
+        let mut regs = Registers::default();
+        for (i, reg) in regs.gprs.iter_mut().enumerate() {
+            *reg = u64_at(i * 8);
+        }
+        regs.rip = u64_at(128);
+        regs.eflags = u32_at(136);
+        for (i, reg) in regs.segments.iter_mut().enumerate() {
+            *reg = u32_at(140 + i * 4);
+        }
+        Some(regs)
+    }
+}
+
+/// Why a resumed target stopped.
+#[derive(Debug)]
+pub enum Stop {
+    /// A vcpu, given by its index, hit a breakpoint or finished a step.
+    Debug(usize, DebugExit),
+    /// A vcpu, given by its id, exited and the vm can't continue.
+    Exited(u8, std::result::Result<VcpuExit, VmError>),
+}
+
+/// The vm being debugged. Vcpus are given by their index.
+pub trait Target {
+    fn num_vcpus(&self) -> usize;
+
+    fn registers(&mut self, vcpu: usize) -> Result<Registers>;
+
+    fn set_registers(&mut self, vcpu: usize, regs: &Registers) -> Result<()>;
+
+    /// Read memory at a guest virtual address, as seen by the vcpu.
+    fn read_memory(&mut self, vcpu: usize, addr: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn write_memory(&mut self, vcpu: usize, addr: u64, data: &[u8]) -> Result<()>;
+
+    /// Run all vcpus with debugging enabled, single stepping `step` if given.
+    /// int3 is only intercepted if `sw_breakpoints` is set, otherwise it goes
+    /// straight to the guest. `debugreg` holds DR0-DR7 for hardware
+    /// breakpoints.
+    fn resume(
+        &mut self,
+        step: Option<usize>,
+        sw_breakpoints: bool,
+        debugreg: [u64; 8],
+    ) -> Result<()>;
+
+    /// Hand a breakpoint exception that wasn't from one of gdb's breakpoints
+    /// back to the guest, which sees it once the stopped vcpu is resumed.
+    fn reinject_breakpoint(&mut self, vcpu: usize) -> Result<()>;
+
+    /// Wait up to `timeout` for a resumed target to stop. Every vcpu is
+    /// stopped once one of them does.
+    fn wait(&mut self, timeout: Duration) -> Result<Option<Stop>>;
+
+    /// Stop a resumed target.
+    fn interrupt(&mut self) -> Result<()>;
+
+    /// Run all vcpus with debugging disabled.
+    fn detach(&mut self) -> Result<()>;
+}
+
+/// How a debugging session ended.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The vm stopped while running, e.g. because the guest powered off.
+    Exited(u8, std::result::Result<VcpuExit, VmError>),
+    /// gdb detached or disconnected, leaving the target running.
+    Detached,
+    /// gdb asked for the vm to be killed.
+    Killed,
+}
+
+/// How often to check for an interrupt from gdb while the target runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Debug registers, x86 allows four breakpoints.
+const NUM_HW_BREAKPOINTS: usize = 4;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// errno values used in error replies.
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HwKind {
+    Execute,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HwBreakpoint {
+    addr: u64,
+    kind: HwKind,
+    len: u64,
+}
+
+enum Action {
+    Reply(Vec<u8>),
+    Resume(Option<usize>),
+    Detach,
+    Kill,
+}
+
+fn reply(s: &str) -> Result<Action> {
+    Ok(Action::Reply(s.as_bytes().to_vec()))
+}
+
+fn error_reply(errno: u8) -> Result<Action> {
+    reply(&format!("E{:02x}", errno))
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    let s = std::str::from_utf8(s).ok()?;
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Parse "addr,len" with both in hex.
+fn parse_addr_len(s: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = s.splitn(2, |b| *b == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+/// Parse a thread id, where 0 and -1 mean any thread.
+fn parse_thread(s: &[u8]) -> Option<Option<usize>> {
+    if s == b"-1" || s == b"0" {
+        return Some(None);
+    }
+    let id = parse_hex(s)? as usize;
+    Some(Some(id.checked_sub(1)?))
+}
+
+pub struct Stub<T: Target, W: Write> {
+    target: T,
+    packets: Receiver<io::Result<Packet>>,
+    conn: W,
+    /// Original bytes at software breakpoints.
+    sw_breakpoints: BTreeMap<u64, u8>,
+    hw_breakpoints: Vec<HwBreakpoint>,
+    /// Vcpu for register and memory accesses.
+    current: usize,
+    /// Vcpu to single step.
+    step_vcpu: usize,
+    last_stop: Vec<u8>,
+}
+
+impl<T: Target, W: Write> Stub<T, W> {
+    /// Create a stub for a stopped target. Packets are read from `reader`
+    /// on a thread of their own, so that interrupts are seen while the
+    /// target runs.
+    pub fn new<R: Read + Send + 'static>(target: T, mut reader: R, conn: W) -> Self {
+        let (tx, packets) = mpsc::channel();
+        thread::spawn(move || loop {
+            let packet = read_packet(&mut reader);
+            let done = packet.is_err();
+            if tx.send(packet).is_err() || done {
+                break;
+            }
+        });
+        Stub {
+            target,
+            packets,
+            conn,
+            sw_breakpoints: BTreeMap::new(),
+            hw_breakpoints: Vec::new(),
+            current: 0,
+            step_vcpu: 0,
+            last_stop: format!("S{:02x}", SIGTRAP).into_bytes(),
+        }
+    }
+
+    /// Serve gdb until the session ends.
+    pub fn run(mut self) -> Result<(Outcome, T)> {
+        loop {
+            let packet = match self.packets.recv() {
+                Ok(Ok(Packet::Data(data))) => {
+                    ack(&mut self.conn, true)?;
+                    data
+                }
+                Ok(Ok(Packet::Invalid)) => {
+                    ack(&mut self.conn, false)?;
+                    continue;
+                }
+                // Already stopped.
+                Ok(Ok(Packet::Interrupt)) => continue,
+                Ok(Err(_)) | Err(_) => {
+                    info!("gdb disconnected");
+                    self.detach()?;
+                    return Ok((Outcome::Detached, self.target));
+                }
+            };
+            debug!("gdb: {}", String::from_utf8_lossy(&packet));
+
+            match self.handle(&packet)? {
+                Action::Reply(data) => write_packet(&mut self.conn, &data)?,
+                Action::Resume(step) => {
+                    self.resume(step)?;
+                    if let Some(exit) = self.wait_stop(step)? {
+                        // Reported as the process exiting, successfully if
+                        // the guest powered off.
+                        let status = match exit {
+                            Outcome::Exited(_, Ok(VcpuExit::Shutdown)) => b"W00",
+                            _ => b"W01",
+                        };
+                        write_packet(&mut self.conn, status)?;
+                        return Ok((exit, self.target));
+                    }
+                    write_packet(&mut self.conn, &self.last_stop)?;
+                }
+                Action::Detach => {
+                    self.detach()?;
+                    write_packet(&mut self.conn, b"OK")?;
+                    return Ok((Outcome::Detached, self.target));
+                }
+                Action::Kill => return Ok((Outcome::Killed, self.target)),
+            }
+        }
+    }
+
+    fn resume(&mut self, step: Option<usize>) -> Result<()> {
+        let debugreg = self.debugreg();
+        self.target
+            .resume(step, !self.sw_breakpoints.is_empty(), debugreg)
+    }
+
+    /// Wait for a target resumed with `step` to stop, recording why. Returns
+    /// the outcome if the vm exited.
+    fn wait_stop(&mut self, step: Option<usize>) -> Result<Option<Outcome>> {
+        loop {
+            if let Some(stop) = self.target.wait(POLL_INTERVAL)? {
+                match stop {
+                    Stop::Debug(vcpu, exit)
+                        if exit.exception == 3 && !self.gdb_breakpoint(&exit) =>
+                    {
+                        // The guest's own int3, which it expects to handle.
+                        self.target.reinject_breakpoint(vcpu)?;
+                        self.resume(step)?;
+                        continue;
+                    }
+                    Stop::Debug(vcpu, exit) => {
+                        self.current = vcpu;
+                        self.last_stop = self.stop_reply(vcpu, &exit).into_bytes();
+                        return Ok(None);
+                    }
+                    Stop::Exited(id, result) => return Ok(Some(Outcome::Exited(id, result))),
+                }
+            }
+            match self.packets.try_recv() {
+                Ok(Ok(Packet::Interrupt)) => {
+                    self.target.interrupt()?;
+                    self.last_stop =
+                        format!("T{:02x}thread:{:x};", SIGINT, self.current + 1).into_bytes();
+                    return Ok(None);
+                }
+                // Nothing else is expected while running.
+                Ok(Ok(_)) | Err(TryRecvError::Empty) => (),
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))
+                }
+            }
+        }
+    }
+
+    /// Whether the exit is from an int3 inserted by gdb.
+    fn gdb_breakpoint(&self, exit: &DebugExit) -> bool {
+        exit.exception == 3 && self.sw_breakpoints.contains_key(&exit.pc)
+    }
+
+    fn stop_reply(&self, vcpu: usize, exit: &DebugExit) -> String {
+        let mut reply = format!("T{:02x}thread:{:x};", SIGTRAP, vcpu + 1);
+        if self.gdb_breakpoint(exit) {
+            reply.push_str("swbreak:;");
+        }
+        for (i, bp) in self.hw_breakpoints.iter().enumerate() {
+            if exit.dr6 & (1 << i) == 0 {
+                continue;
+            }
+            match bp.kind {
+                HwKind::Execute => reply.push_str("hwbreak:;"),
+                HwKind::Write => reply.push_str(&format!("watch:{:x};", bp.addr)),
+                HwKind::Access => reply.push_str(&format!("awatch:{:x};", bp.addr)),
+            }
+        }
+        reply
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Result<Action> {
+        let (cmd, args) = match packet.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return reply(""),
+        };
+        match cmd {
+            b'?' => Ok(Action::Reply(self.last_stop.clone())),
+            b'g' => {
+                let regs = self.target.registers(self.current)?;
+                reply(&encode_hex(&regs.to_bytes()))
+            }
+            b'G' => match decode_hex(args).and_then(|b| Registers::from_bytes(&b)) {
+                Some(regs) => {
+                    self.target.set_registers(self.current, &regs)?;
+                    reply("OK")
+                }
+                None => error_reply(EINVAL),
+            },
+            b'm' => {
+                let (addr, len) = match parse_addr_len(args) {
+                    Some(v) => v,
+                    None => return error_reply(EINVAL),
+                };
+                let mut buf = vec![0; len.min(0x1000) as usize];
+                match self.target.read_memory(self.current, addr, &mut buf) {
+                    Ok(()) => reply(&encode_hex(&buf)),
+                    Err(Error::Unmapped(_)) => error_reply(EFAULT),
+                    Err(e) => Err(e),
+                }
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |b| *b == b':');
+                let dest = parts.next().and_then(parse_addr_len);
+                let data = parts.next().and_then(decode_hex);
+                match (dest, data) {
+                    (Some((addr, len)), Some(ref data)) if data.len() as u64 == len => {
+                        match self.target.write_memory(self.current, addr, data) {
+                            Ok(()) => reply("OK"),
+                            Err(Error::Unmapped(_)) => error_reply(EFAULT),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    _ => error_reply(EINVAL),
+                }
+            }
+            // Continuing from a given address, or with a signal, isn't
+            // supported, the vcpu just carries on.
+            b'c' | b'C' => Ok(Action::Resume(None)),
+            b's' | b'S' => Ok(Action::Resume(Some(self.step_vcpu))),
+            b'H' => {
+                let (op, thread) = match args.split_first() {
+                    Some((op, thread)) => (*op, parse_thread(thread)),
+                    None => return error_reply(EINVAL),
+                };
+                match thread.map(|vcpu| vcpu.unwrap_or(0)) {
+                    Some(vcpu) if vcpu < self.target.num_vcpus() => {
+                        match op {
+                            b'g' => self.current = vcpu,
+                            b'c' => self.step_vcpu = vcpu,
+                            _ => (),
+                        }
+                        reply("OK")
+                    }
+                    _ => error_reply(EINVAL),
+                }
+            }
+            b'T' => match parse_thread(args) {
+                Some(Some(vcpu)) if vcpu < self.target.num_vcpus() => reply("OK"),
+                _ => error_reply(EINVAL),
+            },
+            b'Z' | b'z' => self.breakpoint(cmd == b'Z', args),
+            b'D' => Ok(Action::Detach),
+            b'k' => Ok(Action::Kill),
+            b'q' => self.query(args),
+            _ => reply(""),
+        }
+    }
+
+    fn query(&mut self, query: &[u8]) -> Result<Action> {
+        if query.starts_with(b"Supported") {
+            return reply("PacketSize=1000;swbreak+;hwbreak+");
+        }
+        match query {
+            b"Attached" => reply("1"),
+            b"C" => reply(&format!("QC{:x}", self.current + 1)),
+            b"fThreadInfo" => {
+                let ids: Vec<String> = (1..=self.target.num_vcpus())
+                    .map(|id| format!("{:x}", id))
+                    .collect();
+                reply(&format!("m{}", ids.join(",")))
+            }
+            b"sThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+
+    /// Insert or remove a breakpoint or watchpoint from a `Z` or `z` packet.
+    fn breakpoint(&mut self, insert: bool, args: &[u8]) -> Result<Action> {
+        let mut parts = args.split(|b| *b == b',');
+        let kind = parts.next().and_then(parse_hex);
+        let addr = parts.next().and_then(parse_hex);
+        let len = parts.next().and_then(parse_hex);
+        let (kind, addr, len) = match (kind, addr, len) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return error_reply(EINVAL),
+        };
+
+        let kind = match kind {
+            0 => return self.sw_breakpoint(insert, addr),
+            1 => HwKind::Execute,
+            2 => HwKind::Write,
+            // x86 can't watch only reads, so reads are watched as accesses.
+            3 | 4 => HwKind::Access,
+            _ => return reply(""),
+        };
+        // Execution breakpoints always have a length of 1.
+        let len = if kind == HwKind::Execute { 1 } else { len };
+        if ![1, 2, 4, 8].contains(&len) || addr % len != 0 {
+            return error_reply(EINVAL);
+        }
+
+        let existing = self
+            .hw_breakpoints
+            .iter()
+            .position(|bp| bp.addr == addr && bp.kind == kind && bp.len == len);
+        match (insert, existing) {
+            (true, Some(_)) | (false, None) => (),
+            (true, None) => {
+                if self.hw_breakpoints.len() == NUM_HW_BREAKPOINTS {
+                    return error_reply(ENOSPC);
+                }
+                self.hw_breakpoints.push(HwBreakpoint { addr, kind, len });
+            }
+            (false, Some(i)) => {
+                self.hw_breakpoints.remove(i);
+            }
+        }
+        reply("OK")
+    }
+
+    fn sw_breakpoint(&mut self, insert: bool, addr: u64) -> Result<Action> {
+        let result = if insert {
+            if self.sw_breakpoints.contains_key(&addr) {
+                return reply("OK");
+            }
+            let mut orig = [0];
+            self.target
+                .read_memory(self.current, addr, &mut orig)
+                .and_then(|_| self.target.write_memory(self.current, addr, &[0xcc]))
+                .map(|_| {
+                    self.sw_breakpoints.insert(addr, orig[0]);
+                })
+        } else {
+            match self.sw_breakpoints.remove(&addr) {
+                Some(orig) => self.target.write_memory(self.current, addr, &[orig]),
+                None => Ok(()),
+            }
+        };
+        match result {
+            Ok(()) => reply("OK"),
+            Err(Error::Unmapped(_)) => error_reply(EFAULT),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Debug register values for the hardware breakpoints.
+    fn debugreg(&self) -> [u64; 8] {
+        let mut debugreg = [0; 8];
+        for (i, bp) in self.hw_breakpoints.iter().enumerate() {
+            debugreg[i] = bp.addr;
+            let rw = match bp.kind {
+                HwKind::Execute => 0b00,
+                HwKind::Write => 0b01,
+                HwKind::Access => 0b11,
+            };
+            let len = match bp.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            // Local enable, then the condition and length fields.
+            debugreg[7] |= 1 << (i * 2);
+            debugreg[7] |= (rw | len << 2) << (16 + i * 4);
+        }
+        debugreg
+    }
+
+    /// Remove all breakpoints and let the target run freely.
+    fn detach(&mut self) -> Result<()> {
+        let current = self.current;
+        for (addr, orig) in std::mem::take(&mut self.sw_breakpoints) {
+            self.target.write_memory(current, addr, &[orig])?;
+        }
+        self.hw_breakpoints.clear();
+        self.target.detach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A target with one page of memory at address 0, where resuming runs
+    /// until the next breakpoint byte or the end of memory.
+    struct FakeTarget {
+        regs: [Registers; 2],
+        mem: Vec<u8>,
+        resumed: Option<(Option<usize>, bool, [u64; 8])>,
+        /// Breakpoints handed back to the guest, whose handler returns past
+        /// the int3.
+        reinjected: usize,
+        detached: bool,
+    }
+
+    impl FakeTarget {
+        fn new() -> Self {
+            FakeTarget {
+                regs: [Registers::default(); 2],
+                mem: vec![0x90; 0x1000],
+                resumed: None,
+                reinjected: 0,
+                detached: false,
+            }
+        }
+    }
+
+    impl Target for FakeTarget {
+        fn num_vcpus(&self) -> usize {
+            2
+        }
+
+        fn registers(&mut self, vcpu: usize) -> Result<Registers> {
+            Ok(self.regs[vcpu])
+        }
+
+        fn set_registers(&mut self, vcpu: usize, regs: &Registers) -> Result<()> {
+            self.regs[vcpu] = *regs;
+            Ok(())
+        }
+
+        fn read_memory(&mut self, _vcpu: usize, addr: u64, buf: &mut [u8]) -> Result<()> {
+            let start = addr as usize;
+            let mem = self
+                .mem
+                .get(start..start + buf.len())
+                .ok_or(Error::Unmapped(addr))?;
+            buf.copy_from_slice(mem);
+            Ok(())
+        }
+
+        fn write_memory(&mut self, _vcpu: usize, addr: u64, data: &[u8]) -> Result<()> {
+            let start = addr as usize;
+            let mem = self
+                .mem
+                .get_mut(start..start + data.len())
+                .ok_or(Error::Unmapped(addr))?;
+            mem.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn resume(
+            &mut self,
+            step: Option<usize>,
+            sw_breakpoints: bool,
+            debugreg: [u64; 8],
+        ) -> Result<()> {
+            self.resumed = Some((step, sw_breakpoints, debugreg));
+            Ok(())
+        }
+
+        fn reinject_breakpoint(&mut self, vcpu: usize) -> Result<()> {
+            self.regs[vcpu].rip += 1;
+            self.reinjected += 1;
+            Ok(())
+        }
+
+        fn wait(&mut self, _timeout: Duration) -> Result<Option<Stop>> {
+            let (step, sw_breakpoints, _) = self.resumed.take().expect("not resumed");
+            let regs = &mut self.regs[0];
+            if step.is_some() {
+                regs.rip += 1;
+                return Ok(Some(Stop::Debug(0, DebugExit::default())));
+            }
+            if !sw_breakpoints {
+                return Ok(Some(Stop::Exited(0, Ok(VcpuExit::Shutdown))));
+            }
+            match self.mem[regs.rip as usize..]
+                .iter()
+                .position(|b| *b == 0xcc)
+            {
+                Some(offset) => {
+                    regs.rip += offset as u64;
+                    let exit = DebugExit {
+                        exception: 3,
+                        pc: regs.rip,
+                        ..Default::default()
+                    };
+                    Ok(Some(Stop::Debug(0, exit)))
+                }
+                None => Ok(Some(Stop::Exited(0, Ok(VcpuExit::Shutdown)))),
+            }
+        }
+
+        fn interrupt(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn detach(&mut self) -> Result<()> {
+            self.detached = true;
+            Ok(())
+        }
+    }
+
+    /// Output shared with the stub, so it can be inspected while the stub
+    /// owns the writer.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packets(cmds: &[&str]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for cmd in cmds.iter() {
+            write_packet(&mut input, cmd.as_bytes()).unwrap();
+        }
+        input
+    }
+
+    /// Run a session, returning the replies and the target.
+    fn session(target: FakeTarget, cmds: &[&str]) -> (Vec<String>, Outcome, FakeTarget) {
+        let out = Output::default();
+        let input = io::Cursor::new(packets(cmds));
+        let (outcome, target) = Stub::new(target, input, out.clone()).run().unwrap();
+        let out = out.0.lock().unwrap();
+        let replies = String::from_utf8_lossy(&out)
+            .split('$')
+            .skip(1)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect();
+        (replies, outcome, target)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut target = FakeTarget::new();
+        target.regs[1].rip = 0x1234;
+        target.mem[0x10..0x14].copy_from_slice(&[1, 2, 3, 4]);
+        let (replies, _, target) =
+            session(target, &["Hg2", "g", "m10,4", "M20,2:abcd", "m2000,1", "D"]);
+        assert_eq!("OK", replies[0]);
+        let regs = Registers::from_bytes(&decode_hex(replies[1].as_bytes()).unwrap()).unwrap();
+        assert_eq!(0x1234, regs.rip);
+        assert_eq!("01020304", replies[2]);
+        assert_eq!("OK", replies[3]);
+        assert_eq!([0xab, 0xcd], target.mem[0x20..0x22]);
+        assert_eq!("E0e", replies[4]);
+        assert!(target.detached);
+    }
+
+    #[test]
+    fn software_breakpoint() {
+        let (replies, outcome, target) =
+            session(FakeTarget::new(), &["Z0,40,1", "c", "z0,40,1", "s", "c"]);
+        assert_eq!("OK", replies[0]);
+        assert_eq!("T05thread:1;swbreak:;", replies[1]);
+        assert_eq!(0x40, target.regs[0].rip - 1);
+        // The original byte is back after removing the breakpoint.
+        assert_eq!(0x90, target.mem[0x40]);
+        assert_eq!("OK", replies[2]);
+        assert_eq!("T05thread:1;", replies[3]);
+        assert_eq!("W00", replies[4]);
+        match outcome {
+            Outcome::Exited(0, Ok(VcpuExit::Shutdown)) => (),
+            o => panic!("unexpected outcome {:?}", o),
+        }
+    }
+
+    #[test]
+    fn guest_breakpoint() {
+        let mut target = FakeTarget::new();
+        target.mem[0x20] = 0xcc;
+        let (replies, _, target) = session(target, &["Z0,40,1", "c", "z0,40,1", "c"]);
+        // Only gdb's breakpoint stops, after the guest's is reinjected.
+        assert_eq!("T05thread:1;swbreak:;", replies[1]);
+        assert_eq!(1, target.reinjected);
+        assert_eq!(0x40, target.regs[0].rip);
+        // Without any of gdb's breakpoints int3 isn't intercepted.
+        assert_eq!("W00", replies[3]);
+    }
+
+    #[test]
+    fn hardware_breakpoints() {
+        let mut stub = Stub::new(FakeTarget::new(), io::empty(), io::sink());
+        stub.breakpoint(true, b"1,1000,1").ok();
+        stub.breakpoint(true, b"2,2000,4").ok();
+        stub.breakpoint(true, b"4,3000,8").ok();
+        let debugreg = stub.debugreg();
+        assert_eq!([0x1000, 0x2000, 0x3000], debugreg[..3]);
+        // Execute with length 1, write with length 4, and access with
+        // length 8, each locally enabled.
+        assert_eq!(0b1011_1101_0000 << 16 | 0b010101, debugreg[7]);
+
+        stub.breakpoint(false, b"2,2000,4").ok();
+        assert_eq!(2, stub.hw_breakpoints.len());
+        match stub.breakpoint(true, b"2,2001,4").unwrap() {
+            Action::Reply(r) => assert_eq!(b"E16", &r[..]),
+            _ => panic!("expected a reply"),
+        }
+    }
+
+    #[test]
+    fn thread_queries() {
+        let (replies, _, _) = session(
+            FakeTarget::new(),
+            &[
+                "qSupported:xmlRegisters=i386",
+                "qfThreadInfo",
+                "qsThreadInfo",
+                "T3",
+                "D",
+            ],
+        );
+        assert_eq!("PacketSize=1000;swbreak+;hwbreak+", replies[0]);
+        assert_eq!("m1,2", replies[1]);
+        assert_eq!("l", replies[2]);
+        assert_eq!("E16", replies[3]);
+    }
+}
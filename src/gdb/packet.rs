@@ -0,0 +1,113 @@
+//! Framing of gdb remote serial protocol packets.
+//!
+//! Packets are sent as `$data#cs`, where `cs` is the modulo 256 sum of the
+//! data in hex. Each packet is acknowledged with `+`, or `-` to ask for it
+//! to be resent. A lone 0x03 byte asks for the running target to be
+//! interrupted.
+
+use std::io::{self, Read, Write};
+
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    Interrupt,
+    /// A packet with a bad checksum.
+    Invalid,
+}
+
+fn read_byte<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Read the next packet, skipping acknowledgements. Data packets need to be
+/// acknowledged with `ack`.
+pub fn read_packet<R: Read>(r: &mut R) -> io::Result<Packet> {
+    loop {
+        match read_byte(r)? {
+            INTERRUPT => return Ok(Packet::Interrupt),
+            b'$' => break,
+            _ => continue,
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        match read_byte(r)? {
+            b'#' => break,
+            b => data.push(b),
+        }
+    }
+    let cs = [read_byte(r)?, read_byte(r)?];
+    let sent = std::str::from_utf8(&cs)
+        .ok()
+        .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+    if sent != Some(checksum(&data)) {
+        return Ok(Packet::Invalid);
+    }
+    Ok(Packet::Data(data))
+}
+
+pub fn ack<W: Write>(w: &mut W, ok: bool) -> io::Result<()> {
+    w.write_all(if ok { b"+" } else { b"-" })?;
+    w.flush()
+}
+
+/// Send a packet. Replies never contain characters that need escaping.
+pub fn write_packet<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    w.write_all(b"$")?;
+    w.write_all(data)?;
+    write!(w, "#{:02x}", checksum(data))?;
+    w.flush()
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let mut out = Vec::new();
+        write_packet(&mut out, b"OK").unwrap();
+        assert_eq!(b"$OK#9a", &out[..]);
+
+        let mut input = &b"+$m10,4#2e-$g#00\x03"[..];
+        assert_eq!(
+            Packet::Data(b"m10,4".to_vec()),
+            read_packet(&mut input).unwrap()
+        );
+        assert_eq!(Packet::Invalid, read_packet(&mut input).unwrap());
+        assert_eq!(Packet::Interrupt, read_packet(&mut input).unwrap());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!("00ff10", encode_hex(&[0, 0xff, 0x10]));
+        assert_eq!(Some(vec![0, 0xff, 0x10]), decode_hex(b"00FF10"));
+        assert_eq!(None, decode_hex(b"0"));
+        assert_eq!(None, decode_hex(b"zz"));
+    }
+}
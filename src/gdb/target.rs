@@ -0,0 +1,263 @@
+//! Debugging a running vm.
+
+use super::{Error, Registers, Result, Stop, Target};
+use crate::device::Bus;
+use crate::memory::paging::{self, PagingMode};
+use crate::memory::{Addressable, Error as MemError, MemoryAddr, Result as MemResult};
+use crate::vm::control::VcpuManager;
+use crate::vm::exit::VcpuExit;
+use crate::vm::Vcpu;
+use kvm_bindings::{
+    KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+enum State {
+    Stopped(Vec<Vcpu>),
+    Running(VcpuManager),
+    /// Only while switching between the other states.
+    Switching,
+}
+
+/// Guest physical memory as seen through the mmio bus, for walking the
+/// guest's page tables.
+struct BusMemory<'a>(&'a Bus);
+
+impl Addressable for BusMemory<'_> {
+    fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> MemResult<usize> {
+        self.0.read(addr, buf).map_err(|_| MemError::OutOfBounds)?;
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8], addr: MemoryAddr) -> MemResult<usize> {
+        self.0.write(addr, buf).map_err(|_| MemError::OutOfBounds)?;
+        Ok(buf.len())
+    }
+}
+
+/// A vm whose vcpus are run by a `VcpuManager` while resumed.
+pub struct VmTarget {
+    state: State,
+    /// Used for guest memory accesses.
+    mmio_bus: Arc<Bus>,
+}
+
+impl VmTarget {
+    /// Debug a vm from its stopped vcpus.
+    pub fn new(vcpus: Vec<Vcpu>, mmio_bus: Arc<Bus>) -> Self {
+        VmTarget {
+            state: State::Stopped(vcpus),
+            mmio_bus,
+        }
+    }
+
+    /// Get the manager of the vcpus once detached. Returns `None` if the
+    /// target isn't running.
+    pub fn into_manager(self) -> Option<VcpuManager> {
+        match self.state {
+            State::Running(manager) => Some(manager),
+            _ => None,
+        }
+    }
+
+    fn vcpu(&self, vcpu: usize) -> Result<&Vcpu> {
+        match &self.state {
+            State::Stopped(vcpus) => vcpus.get(vcpu).ok_or(Error::InvalidVcpu(vcpu)),
+            _ => Err(Error::InvalidState),
+        }
+    }
+
+    /// Start the stopped vcpus with the given debug settings for each.
+    fn start(&mut self, control: impl Fn(usize) -> u32, debugreg: [u64; 8]) -> Result<()> {
+        let vcpus = match std::mem::replace(&mut self.state, State::Switching) {
+            State::Stopped(vcpus) => vcpus,
+            state => {
+                self.state = state;
+                return Err(Error::InvalidState);
+            }
+        };
+        for (i, vcpu) in vcpus.iter().enumerate() {
+            vcpu.set_guest_debug(control(i), debugreg)?;
+        }
+        self.state = State::Running(VcpuManager::start(vcpus)?);
+        Ok(())
+    }
+
+    /// Stop the running vcpus, keeping them for inspection.
+    fn stop(&mut self) {
+        if let State::Running(mut manager) = std::mem::replace(&mut self.state, State::Switching) {
+            manager.pause();
+            self.state = State::Stopped(manager.stop());
+        }
+    }
+
+    /// Access guest virtual memory a page at a time.
+    fn access(
+        &mut self,
+        vcpu: usize,
+        addr: u64,
+        len: usize,
+        mut f: impl FnMut(&Bus, MemoryAddr, std::ops::Range<usize>) -> bool,
+    ) -> Result<()> {
+        let sregs = self.vcpu(vcpu)?.sregs()?;
+        let mode = PagingMode::from_registers(sregs.cr0, sregs.cr4, sregs.efer);
+        let mut done = 0;
+        while done < len {
+            let virt = addr.wrapping_add(done as u64);
+            let phys = match mode {
+                Some(mode) => {
+                    paging::translate(&BusMemory(&self.mmio_bus), sregs.cr3, mode, virt)
+                        .map_err(|_| Error::Unmapped(virt))?
+                        .addr
+                }
+                None => virt,
+            };
+            let chunk = ((PAGE_SIZE - virt % PAGE_SIZE) as usize).min(len - done);
+            if !f(
+                &self.mmio_bus,
+                MemoryAddr(phys as usize),
+                done..done + chunk,
+            ) {
+                return Err(Error::Unmapped(virt));
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Target for VmTarget {
+    fn num_vcpus(&self) -> usize {
+        match &self.state {
+            State::Stopped(vcpus) => vcpus.len(),
+            _ => 0,
+        }
+    }
+
+    fn registers(&mut self, vcpu: usize) -> Result<Registers> {
+        let vcpu = self.vcpu(vcpu)?;
+        let regs = vcpu.regs()?;
+        let sregs = vcpu.sregs()?;
+        Ok(Registers {
+            gprs: [
+                regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+            ],
+            rip: regs.rip,
+            eflags: regs.rflags as u32,
+            segments: [
+                sregs.cs.selector as u32,
+                sregs.ss.selector as u32,
+                sregs.ds.selector as u32,
+                sregs.es.selector as u32,
+                sregs.fs.selector as u32,
+                sregs.gs.selector as u32,
+            ],
+        })
+    }
+
+    /// Segment registers can't be changed, as gdb only knows the selectors.
+    fn set_registers(&mut self, vcpu: usize, regs: &Registers) -> Result<()> {
+        let vcpu = self.vcpu(vcpu)?;
+        let mut kregs = vcpu.regs()?;
+        let g = &regs.gprs;
+        kregs.rax = g[0];
+        kregs.rbx = g[1];
+        kregs.rcx = g[2];
+        kregs.rdx = g[3];
+        kregs.rsi = g[4];
+        kregs.rdi = g[5];
+        kregs.rbp = g[6];
+        kregs.rsp = g[7];
+        kregs.r8 = g[8];
+        kregs.r9 = g[9];
+        kregs.r10 = g[10];
+        kregs.r11 = g[11];
+        kregs.r12 = g[12];
+        kregs.r13 = g[13];
+        kregs.r14 = g[14];
+        kregs.r15 = g[15];
+        kregs.rip = regs.rip;
+        kregs.rflags = u64::from(regs.eflags);
+        vcpu.set_regs(&kregs)?;
+        Ok(())
+    }
+
+    fn read_memory(&mut self, vcpu: usize, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        self.access(vcpu, addr, len, |bus, phys, range| {
+            bus.read(phys, &mut buf[range]).is_ok()
+        })
+    }
+
+    fn write_memory(&mut self, vcpu: usize, addr: u64, data: &[u8]) -> Result<()> {
+        self.access(vcpu, addr, data.len(), |bus, phys, range| {
+            bus.write(phys, &data[range]).is_ok()
+        })
+    }
+
+    fn resume(
+        &mut self,
+        step: Option<usize>,
+        sw_breakpoints: bool,
+        debugreg: [u64; 8],
+    ) -> Result<()> {
+        let mut control = KVM_GUESTDBG_ENABLE;
+        if sw_breakpoints {
+            control |= KVM_GUESTDBG_USE_SW_BP;
+        }
+        if debugreg[7] != 0 {
+            control |= KVM_GUESTDBG_USE_HW_BP;
+        }
+        self.start(
+            |i| {
+                if step == Some(i) {
+                    control | KVM_GUESTDBG_SINGLESTEP
+                } else {
+                    control
+                }
+            },
+            debugreg,
+        )
+    }
+
+    fn reinject_breakpoint(&mut self, vcpu: usize) -> Result<()> {
+        // #BP
+        self.vcpu(vcpu)?.inject_exception(3)?;
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<Option<Stop>> {
+        let (id, result) = match &mut self.state {
+            State::Running(manager) => match manager.wait_exit_timeout(timeout) {
+                Some(exit) => exit,
+                None => return Ok(None),
+            },
+            _ => return Err(Error::InvalidState),
+        };
+        self.stop();
+        match result {
+            Ok(VcpuExit::Debug(debug)) => {
+                let vcpus = match &self.state {
+                    State::Stopped(vcpus) => vcpus,
+                    _ => unreachable!(),
+                };
+                let index = vcpus.iter().position(|v| v.id() == id).unwrap_or(0);
+                Ok(Some(Stop::Debug(index, debug)))
+            }
+            result => Ok(Some(Stop::Exited(id, result))),
+        }
+    }
+
+    fn interrupt(&mut self) -> Result<()> {
+        self.stop();
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        self.start(|_| 0, [0; 8])
+    }
+}
@@ -5,8 +5,9 @@ pub mod gdt;
 extern crate boot_gen;
 extern crate log;
 
+use crate::acpi;
 use crate::memory::{Error as MemoryError, Memory, MemoryAddr};
-use boot_gen::bootparam::setup_header;
+use boot_gen::bootparam::{boot_e820_entry, boot_params, setup_header};
 use kvm_bindings::kvm_sregs;
 use log::debug;
 use std::io;
@@ -24,6 +25,11 @@ pub enum Error {
     ReadStruct(io::Error),
 
     GDTEntryWrite,
+
+    /// The command line is longer than the kernel accepts.
+    CmdlineTooLong,
+    CmdlineWrite(MemoryError),
+    BootParamsWrite(MemoryError),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -35,7 +41,25 @@ const K_64BIT_OFFSET: u16 = 0x0200;
 
 const GDT_BASE: u16 = 0x0500;
 
+/// The boot parameters, or "zero page", passed to the kernel in rsi.
+const ZERO_PAGE: usize = 0x7000;
+const CMDLINE_ADDR: usize = 0x2_0000;
+/// Longest command line accepted by kernels that don't say otherwise.
+const CMDLINE_MAX: u32 = 255;
+
+/// Start of the area below 1 MiB left to the EBDA, ACPI tables and BIOS.
+const EBDA_START: usize = 0x9_fc00;
+const HIGH_MEMORY_START: usize = 0x10_0000;
+
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+/// Loader type for boot loaders without an assigned id.
+const LOADER_UNDEFINED: u8 = 0xff;
+
 pub struct LoadInfo {
+    /// The setup header read from the image.
+    pub header: setup_header,
     pub kernel_start: MemoryAddr,
     pub entry_point: MemoryAddr,
     pub heap_end: MemoryAddr,
@@ -65,19 +89,80 @@ pub fn load_kernel<F: Read + Seek, M: Memory>(mem: &mut M, image: &mut F) -> Res
     kernel_size -= setup_size;
 
     hdr.code32_start = K_BZ_LOAD_ADDR;
+    let kernel_start = MemoryAddr::from(K_BZ_LOAD_ADDR);
 
-    debug!("start: {}, count: {}", hdr.code32_start, kernel_size);
-    mem.read_from(MemoryAddr::from(hdr.code32_start), image, kernel_size)
+    debug!("start: {}, count: {}", kernel_start, kernel_size);
+    mem.read_from(kernel_start, image, kernel_size)
         .map_err(Error::KernelMemoryLoad)?;
 
     let info = LoadInfo {
-        kernel_start: MemoryAddr::from(hdr.code32_start),
-        entry_point: MemoryAddr(hdr.code32_start as usize + K_64BIT_OFFSET as usize),
-        heap_end: MemoryAddr(hdr.code32_start as usize + kernel_size as usize),
+        header: hdr,
+        kernel_start: kernel_start,
+        entry_point: kernel_start.unchecked_add(K_64BIT_OFFSET as usize),
+        heap_end: kernel_start
+            .checked_add(kernel_size)
+            .ok_or(Error::InvalidImage)?,
     };
     Ok(info)
 }
 
+/// Write the command line and boot parameters for a kernel loaded by
+/// `load_kernel` into a vm with `mem_size` bytes of memory, returning the
+/// address of the boot parameters.
+pub fn configure_boot_params(
+    mem: &mut dyn Memory,
+    info: &LoadInfo,
+    cmdline: &str,
+    mem_size: usize,
+) -> Result<MemoryAddr> {
+    // Older kernels leave cmdline_size unset.
+    let cmdline_max = match info.header.cmdline_size {
+        0 => CMDLINE_MAX,
+        size => size,
+    };
+    if cmdline.len() > cmdline_max as usize {
+        return Err(Error::CmdlineTooLong);
+    }
+    let mut bs = cmdline.as_bytes().to_vec();
+    bs.push(0);
+    mem.write(&bs, MemoryAddr(CMDLINE_ADDR))
+        .map_err(Error::CmdlineWrite)?;
+
+    let e820 = [
+        (0, EBDA_START, E820_RAM),
+        (EBDA_START, HIGH_MEMORY_START - EBDA_START, E820_RESERVED),
+        (HIGH_MEMORY_START, mem_size - HIGH_MEMORY_START, E820_RAM),
+    ];
+    let mut params = boot_params {
+        hdr: setup_header {
+            type_of_loader: LOADER_UNDEFINED,
+            cmd_line_ptr: CMDLINE_ADDR as u32,
+            cmdline_size: cmdline.len() as u32,
+            ..info.header
+        },
+        acpi_rsdp_addr: acpi::RSDP_ADDR as u64,
+        e820_entries: e820.len() as u8,
+        ..Default::default()
+    };
+    for (i, &(addr, size, type_)) in e820.iter().enumerate() {
+        params.e820_table[i] = boot_e820_entry {
+            addr: addr as u64,
+            size: size as u64,
+            type_,
+        };
+    }
+
+    let bs = unsafe {
+        std::slice::from_raw_parts(
+            &params as *const boot_params as *const u8,
+            mem::size_of::<boot_params>(),
+        )
+    };
+    mem.write(bs, MemoryAddr(ZERO_PAGE))
+        .map_err(Error::BootParamsWrite)?;
+    Ok(MemoryAddr(ZERO_PAGE))
+}
+
 pub fn configure_gdt_table(mem: &mut Memory, sregs: &mut kvm_sregs) -> Result<()> {
     let gdt_table: [gdt::Entry; 3] = [
         gdt::Entry::new(0, 0, 0, 0),                 // null
@@ -86,7 +171,7 @@ pub fn configure_gdt_table(mem: &mut Memory, sregs: &mut kvm_sregs) -> Result<()
     ];
 
     for (i, entry) in gdt_table.iter().enumerate() {
-        let addr = MemoryAddr(GDT_BASE as usize + i * mem::size_of::<u64>());
+        let addr = MemoryAddr::from(u32::from(GDT_BASE)).unchecked_add(i * mem::size_of::<u64>());
         write_gdt_entry(mem, entry, addr)?
     }
 
@@ -124,6 +209,7 @@ unsafe fn read_struct<F: Read, T>(f: &mut F, s: &mut T) -> Result<()> {
 mod test {
     use super::*;
     use crate::memory::memorymap::MemoryMmap;
+    use crate::memory::Addressable;
     use std::io::Cursor;
 
     fn read_bzimage() -> Vec<u8> {
@@ -137,6 +223,42 @@ mod test {
         MemoryMmap::new(SIZE).unwrap()
     }
 
+    #[test]
+    fn boot_params() {
+        let mut mem = new_memory_map();
+        let mut info = LoadInfo {
+            header: setup_header::default(),
+            kernel_start: MemoryAddr(K_BZ_LOAD_ADDR as usize),
+            entry_point: MemoryAddr(K_BZ_LOAD_ADDR as usize),
+            heap_end: MemoryAddr(K_BZ_LOAD_ADDR as usize),
+        };
+        let addr = configure_boot_params(&mut mem, &info, "console=ttyS0", 10 << 20).unwrap();
+        assert_eq!(MemoryAddr(ZERO_PAGE), addr);
+
+        let mut bs = vec![0; mem::size_of::<boot_params>()];
+        mem.read(&mut bs, addr).unwrap();
+        let mut params = boot_params::default();
+        unsafe { read_struct(&mut Cursor::new(&bs), &mut params).unwrap() };
+        assert_eq!(CMDLINE_ADDR as u32, { params.hdr.cmd_line_ptr });
+        assert_eq!(13, { params.hdr.cmdline_size });
+        assert_eq!(acpi::RSDP_ADDR as u64, { params.acpi_rsdp_addr });
+        assert_eq!(3, params.e820_entries);
+        let high = params.e820_table[2];
+        assert_eq!(
+            (0x10_0000, 9 << 20, E820_RAM),
+            ({ high.addr }, { high.size }, { high.type_ })
+        );
+        let mut cmdline = [0; 14];
+        mem.read(&mut cmdline, MemoryAddr(CMDLINE_ADDR)).unwrap();
+        assert_eq!(b"console=ttyS0\0", &cmdline);
+
+        info.header.cmdline_size = 4;
+        match configure_boot_params(&mut mem, &info, "console=ttyS0", 10 << 20) {
+            Err(Error::CmdlineTooLong) => (),
+            r => panic!("expected CmdlineTooLong, got {:?}", r),
+        }
+    }
+
     #[test]
     fn no_panic() {
         let mut mem = new_memory_map();
@@ -1,49 +1,951 @@
+mod acpi;
 mod device;
+mod gdb;
 mod loader;
 mod memory;
+mod monitor;
+mod snapshot;
 mod vm;
+mod vmcore;
 
+use device::i8042::I8042;
 use device::legacy::Serial;
+use device::memhp::{self, MemoryHotplug};
+use device::sleep::SleepControl;
+use device::virtio::mmio::{self, MmioTransport};
+use device::virtio::VirtioDevice;
+use device::{Bus, Stateful};
 use env_logger;
-use log::debug;
-use memory::{Addressable, Region};
-use std::io::Cursor;
+use gdb::target::VmTarget;
+use gdb::{Outcome, Stub};
+use kvm_bindings::KVM_SYSTEM_EVENT_CRASH;
+use log::{debug, error, info, warn};
+use memory::memorymap::MemoryMmap;
+use memory::{Addressable, MemoryAddr, MemoryRange, Region};
+use monitor::{Command, Monitor};
+use snapshot::migration::{self, Endpoint};
+use snapshot::{BusKind, DeviceKind, DeviceSnapshot, MemoryFormat, Snapshot, VcpuSnapshot};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vm::control::VcpuManager;
+use vm::exit::VcpuExit;
+use vm::msr::{self, MsrStore, MSR_KERNEL_GS_BASE};
+
+/// Number of vcpus to give the guest unless `--cpus` is used.
+const DEFAULT_VCPUS: u8 = 1;
+
+/// Memory is sent while the guest runs for at most this many rounds after
+/// the first, or until no more than `MIGRATION_STOP_PAGES` were written
+/// during a round.
+const MIGRATION_MAX_ROUNDS: usize = 30;
+const MIGRATION_STOP_PAGES: u32 = 256;
+
+/// How often to check for core dump requests and control commands while
+/// the guest runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size of guest memory for a freshly booted vm.
+const MEMORY_SIZE: usize = 1 << 30;
+
+/// Upper bound on the memory of a vm migrated to this process.
+const MAX_INCOMING_MEMORY: usize = 64 << 30;
+
+/// Hotplugged memory is placed from here up, clear of main memory and the
+/// 32-bit mmio hole.
+const HOTPLUG_BASE: usize = 1 << 32;
+
+/// Kernel command line, followed by a parameter for each virtio device.
+const CMDLINE: &str = "console=ttyS0 reboot=k panic=1";
+
+/// Virtio devices each get `mmio::MMIO_LEN` bytes of mmio space from here,
+/// and one of these interrupts, which are unused by legacy devices.
+const VIRTIO_MMIO_BASE: usize = 0xd000_0000;
+const VIRTIO_IRQS: std::ops::Range<u32> = 10..24;
+
+const SERIAL_BASE: usize = 0x3f8;
+const SERIAL_LEN: usize = 8;
+
+/// Process exit statuses for the ways the guest can stop.
+const EXIT_POWEROFF: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_RESET: i32 = 2;
+const EXIT_CRASH: i32 = 3;
+
+#[derive(Debug)]
+enum Error {
+    Vm(vm::Error),
+    Device(device::Error),
+    Snapshot(snapshot::Error),
+    Gdb(gdb::Error),
+    Core(vmcore::Error),
+    Memory(memory::Error),
+    Loader(loader::Error),
+    /// Hotplugged memory isn't saved in snapshots.
+    HotpluggedMemory,
+    /// There are more virtio devices than interrupts for them.
+    TooManyDevices,
+    /// A vcpu exited while the vm was being paused for a snapshot.
+    VcpuExited,
+    /// The snapshot doesn't describe a vm that can be restored.
+    InvalidSnapshot(&'static str),
+}
+
+impl From<vm::Error> for Error {
+    fn from(e: vm::Error) -> Self {
+        Error::Vm(e)
+    }
+}
+
+impl From<device::Error> for Error {
+    fn from(e: device::Error) -> Self {
+        Error::Device(e)
+    }
+}
+
+impl From<snapshot::Error> for Error {
+    fn from(e: snapshot::Error) -> Self {
+        Error::Snapshot(e)
+    }
+}
+
+impl From<gdb::Error> for Error {
+    fn from(e: gdb::Error) -> Self {
+        Error::Gdb(e)
+    }
+}
+
+impl From<vmcore::Error> for Error {
+    fn from(e: vmcore::Error) -> Self {
+        Error::Core(e)
+    }
+}
+
+impl From<memory::Error> for Error {
+    fn from(e: memory::Error) -> Self {
+        Error::Memory(e)
+    }
+}
+
+impl From<loader::Error> for Error {
+    fn from(e: loader::Error) -> Self {
+        Error::Loader(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Snapshot(snapshot::Error::Io(e))
+    }
+}
+
+/// When to save snapshots of a running vm.
+struct SnapshotSchedule {
+    interval: Duration,
+    path: PathBuf,
+    /// Keep running and saving snapshots after the first. Later snapshots
+    /// only hold the memory written since the one before (see `diff_path`).
+    repeat: bool,
+}
+
+#[derive(Default)]
+struct Options {
+    /// Reboot the guest rather than exiting when it resets.
+    restart_on_reset: bool,
+    /// Number of vcpus for a freshly booted vm, `DEFAULT_VCPUS` if not given.
+    cpus: Option<u8>,
+    snapshot: Option<SnapshotSchedule>,
+    /// Restore the vm from a snapshot rather than booting the kernel.
+    restore: Option<PathBuf>,
+    /// Migrate the vm to another process after it has run for a while.
+    migrate: Option<(Duration, Endpoint)>,
+    /// Receive a vm migrated from another process rather than booting the
+    /// kernel.
+    incoming: Option<Endpoint>,
+    /// Leave holes in the memory file for zero pages.
+    sparse: bool,
+    /// Wait for gdb to connect on this address before running the guest.
+    gdb: Option<String>,
+    /// Write a core dump of the guest here on SIGUSR1.
+    core: Option<PathBuf>,
+    /// Accept commands on a unix socket at this path, see `monitor`.
+    control: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: Vec<String>) -> Self {
+        let mut opts = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--restart-on-reset" => opts.restart_on_reset = true,
+                "--sparse" => opts.sparse = true,
+                "--cpus" => match args.next().and_then(|s| s.parse().ok()) {
+                    Some(n) if n > 0 => opts.cpus = Some(n),
+                    _ => usage(),
+                },
+                "--snapshot-after" | "--snapshot-every" => {
+                    let secs = args.next().and_then(|s| s.parse().ok());
+                    match (secs, args.next()) {
+                        (Some(secs), Some(path)) => {
+                            opts.snapshot = Some(SnapshotSchedule {
+                                interval: Duration::from_secs(secs),
+                                path: PathBuf::from(path),
+                                repeat: arg == "--snapshot-every",
+                            })
+                        }
+                        _ => usage(),
+                    }
+                }
+                "--restore" => match args.next() {
+                    Some(path) => opts.restore = Some(PathBuf::from(path)),
+                    None => usage(),
+                },
+                "--migrate-after" => {
+                    let secs = args.next().and_then(|s| s.parse().ok());
+                    let addr = args.next().and_then(|s| Endpoint::parse(&s));
+                    match (secs, addr) {
+                        (Some(secs), Some(addr)) => {
+                            opts.migrate = Some((Duration::from_secs(secs), addr))
+                        }
+                        _ => usage(),
+                    }
+                }
+                "--core" => match args.next() {
+                    Some(path) => opts.core = Some(PathBuf::from(path)),
+                    None => usage(),
+                },
+                "--control" => match args.next() {
+                    Some(path) => opts.control = Some(PathBuf::from(path)),
+                    None => usage(),
+                },
+                "--gdb" => match args.next() {
+                    Some(addr) => opts.gdb = Some(addr),
+                    None => usage(),
+                },
+                "--incoming" => match args.next().and_then(|s| Endpoint::parse(&s)) {
+                    Some(addr) => opts.incoming = Some(addr),
+                    None => usage(),
+                },
+                _ => usage(),
+            }
+        }
+        if (opts.snapshot.is_some() && opts.migrate.is_some())
+            || (opts.restore.is_some() && opts.incoming.is_some())
+            || (opts.cpus.is_some() && (opts.restore.is_some() || opts.incoming.is_some()))
+            || (opts.gdb.is_some() && (opts.snapshot.is_some() || opts.migrate.is_some()))
+        {
+            usage();
+        }
+        opts
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: submarine [--restart-on-reset] [--cpus N] [--restore PATH | --incoming ADDR] \
+         [--snapshot-after|--snapshot-every SECS PATH [--sparse] | \
+         --migrate-after SECS ADDR | --gdb HOST:PORT] [--core PATH] [--control PATH]\n       \
+         submarine merge BASE_MEMORY DIFF_MEMORY..."
+    );
+    process::exit(EXIT_ERROR);
+}
 
 fn main() {
     env_logger::init();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("merge") {
+        match merge(&args[1..]) {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                error!("merge failed: {:?}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    let mut opts = Options::parse(args);
+    if opts.core.is_some() {
+        if let Err(e) = vmcore::register_signal() {
+            error!("failed to register core dump signal: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    let monitor = match opts.control {
+        Some(ref path) => match Monitor::listen(path) {
+            Ok(monitor) => Some(monitor),
+            Err(e) => {
+                error!("failed to listen on {}: {}", path.display(), e);
+                process::exit(EXIT_ERROR);
+            }
+        },
+        None => None,
+    };
     let k = vm::KvmContext::new().unwrap();
-    let mut v = vm::Vm::new(&k).unwrap();
+    let status = loop {
+        match run_vm(&k, &opts, monitor.as_ref()) {
+            Ok(VcpuExit::Reset) if opts.restart_on_reset => {
+                info!("guest reset, restarting");
+                // A reset reboots the kernel, even for a restored vm.
+                opts.restore = None;
+                opts.incoming = None;
+            }
+            Ok(VcpuExit::Shutdown) => break EXIT_POWEROFF,
+            Ok(VcpuExit::Reset) => break EXIT_RESET,
+            Ok(VcpuExit::SystemEvent(KVM_SYSTEM_EVENT_CRASH, _)) => {
+                error!("guest crashed");
+                break EXIT_CRASH;
+            }
+            Ok(exit) => {
+                error!("unexpected vcpu exit: {:?}", exit);
+                break EXIT_ERROR;
+            }
+            Err(e) => {
+                error!("vm failed: {:?}", e);
+                break EXIT_ERROR;
+            }
+        }
+    };
+    process::exit(status);
+}
+
+/// Apply a chain of memory diffs, in order, to the full memory file of the
+/// snapshot they started from. The result replaces the last diff, so that the
+/// snapshot it was saved with can be restored, while the base is left as it
+/// was.
+fn merge(paths: &[String]) -> Result<(), Error> {
+    let (base, diffs) = match paths.split_first() {
+        Some((base, diffs)) if !diffs.is_empty() => (base, diffs),
+        _ => usage(),
+    };
+    let target = Path::new(diffs.last().unwrap());
+    let mut name = target.as_os_str().to_owned();
+    name.push(".merging");
+    let merging = PathBuf::from(name);
+
+    let result = merge_into(Path::new(base), diffs, &merging);
+    if result.is_err() {
+        let _ = fs::remove_file(&merging);
+    }
+    result?;
+    fs::rename(&merging, target)?;
+    info!("wrote merged memory to {}", target.display());
+    Ok(())
+}
+
+fn merge_into(base: &Path, diffs: &[String], merged: &Path) -> Result<(), Error> {
+    fs::copy(base, merged)?;
+    let mut merged = OpenOptions::new().read(true).write(true).open(merged)?;
+    for path in diffs.iter() {
+        let mut diff = BufReader::new(File::open(path)?);
+        snapshot::merge_memory_diff(&mut merged, &mut diff)?;
+        info!("merged {}", path);
+    }
+    merged.sync_all()?;
+    Ok(())
+}
+
+/// Path of the `n`th snapshot after the first when saving repeatedly.
+fn diff_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// How guest memory is saved in a snapshot.
+enum SnapshotMemory<'a> {
+    Full(MemoryFormat),
+    /// Only the pages set in the dirty bitmap.
+    Diff(&'a [u64]),
+}
+
+/// The parts of a vm that are created differently when booting and when
+/// restoring.
+struct Machine {
+    vm: vm::Vm,
+    mem: MemoryMmap,
+    mem_slot: u32,
+    vcpus: Vec<vm::Vcpu>,
+    /// Saved device state, when restoring.
+    devices: Vec<DeviceSnapshot>,
+    /// Virtio devices to add to the mmio bus.
+    virtio: Vec<(MemoryRange, Arc<Mutex<MmioTransport>>)>,
+}
+
+/// Create a fresh vm that will boot the kernel.
+fn boot(
+    k: &vm::KvmContext,
+    num_vcpus: u8,
+    devices: Vec<Box<dyn VirtioDevice>>,
+) -> Result<Machine, Error> {
+    let mut v = vm::Vm::new(k)?;
 
-    let mut mem = memory::memorymap::MemoryMmap::new(1 << 30).unwrap();
+    let mut mem = MemoryMmap::new(MEMORY_SIZE).unwrap();
+
+    let mut cmdline = CMDLINE.to_string();
+    let mut virtio = Vec::new();
+    for (i, device) in devices.into_iter().enumerate() {
+        let irq = Some(VIRTIO_IRQS.start + i as u32)
+            .filter(|irq| VIRTIO_IRQS.contains(irq))
+            .ok_or(Error::TooManyDevices)?;
+        let addr = MemoryAddr(VIRTIO_MMIO_BASE + i * mmio::MMIO_LEN);
+        let range = MemoryRange::new(addr, mmio::MMIO_LEN);
+        let line = Box::new(v.irq_line(irq)?);
+        cmdline.push(' ');
+        cmdline.push_str(&mmio::cmdline_param(range, irq));
+        virtio.push((
+            range,
+            Arc::new(Mutex::new(MmioTransport::new(device, line))),
+        ));
+    }
 
     let mut img = Vec::new();
     img.extend_from_slice(include_bytes!("/boot/vmlinuz-linux"));
     let info = loader::load_kernel(&mut mem, &mut Cursor::new(&img)).unwrap();
+    let boot_params = loader::configure_boot_params(&mut mem, &info, &cmdline, MEMORY_SIZE)?;
+    acpi::write_tables(&mut mem, num_vcpus)?;
+
+    let mem_slot = v.init_memory(&mem, k)?;
+
+    let cpuid = k.supported_cpuid()?;
+    let config = vm::BootConfig {
+        num_vcpus,
+        supported_cpuid: &cpuid,
+        cpuid_template: None,
+        entry_point: info.entry_point,
+        heap_end: info.heap_end,
+        boot_params,
+    };
+    let mut vcpus = Vec::new();
+    for id in 0..num_vcpus {
+        let vcpu = vm::Vcpu::new(&v, id)?;
+        vcpu.configure(&v, &mut mem, &config)?;
+        vcpus.push(vcpu);
+    }
+    emulate_msrs(k, &v, &mut vcpus)?;
+
+    Ok(Machine {
+        vm: v,
+        mem,
+        mem_slot,
+        vcpus,
+        devices: Vec::new(),
+        virtio,
+    })
+}
+
+/// Route guest accesses to the MSRs in `msr::emulated_msrs` to userspace.
+/// Without support for this in KVM, it handles them as usual.
+fn emulate_msrs(k: &vm::KvmContext, v: &vm::Vm, vcpus: &mut [vm::Vcpu]) -> Result<(), Error> {
+    let msrs = msr::emulated_msrs();
+    let indices: Vec<_> = msrs.iter().map(|(index, _)| *index).collect();
+    match v.filter_msrs(k, &indices) {
+        Ok(()) => (),
+        Err(vm::Error::MsrFilterUnsupported) => {
+            warn!("msr filtering unsupported, not emulating msrs");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    for vcpu in vcpus.iter_mut() {
+        vcpu.set_msr_handler(Box::new(MsrStore::read_only(&msrs)));
+    }
+    Ok(())
+}
+
+/// Recreate a vm from a snapshot saved by `save_snapshot`.
+fn restore(k: &vm::KvmContext, path: &Path) -> Result<Machine, Error> {
+    let snap = Snapshot::load(&mut BufReader::new(File::open(path)?))?;
+    let mut file = File::open(Snapshot::memory_path(path))?;
+    if snapshot::is_memory_diff(&mut file)? {
+        return Err(Error::InvalidSnapshot(
+            "memory is a diff, merge the diffs up to it into a copy of the base first",
+        ));
+    }
+    let mem = snapshot::load_memory(&mut file, &snap.memory)?;
+    let machine = restore_from(k, snap, mem)?;
+    info!("restored vm from {}", path.display());
+    Ok(machine)
+}
+
+/// Wait for a vm to be migrated from another process, and recreate it.
+fn incoming(k: &vm::KvmContext, addr: &Endpoint) -> Result<Machine, Error> {
+    let mut receiver = migration::Receiver::new(addr.accept()?, MAX_INCOMING_MEMORY);
+    let (snap, mem) = receiver.receive()?;
+    let machine = restore_from(k, snap, mem)?;
+    // Only now can the sender safely exit.
+    receiver.ack()?;
+    info!("received vm migrated from {:?}", addr);
+    Ok(machine)
+}
+
+fn restore_from(
+    k: &vm::KvmContext,
+    snap: Snapshot,
+    mut mem: Vec<MemoryMmap>,
+) -> Result<Machine, Error> {
+    match snap.memory.as_slice() {
+        [range] if range.start() == MemoryAddr(0) && mem.len() == 1 => (),
+        _ => return Err(Error::InvalidSnapshot("expected one memory region")),
+    }
+    let mem = mem.pop().unwrap();
+
+    let mut v = vm::Vm::new(k)?;
+    let mem_slot = v.init_memory(&mem, k)?;
+    v.set_state(&snap.vm)?;
+
+    let mut vcpus = Vec::new();
+    for saved in snap.vcpus.iter() {
+        let vcpu = vm::Vcpu::new(&v, saved.id)?;
+        // Cpuid determines which parts of the state can be set.
+        vcpu.set_cpuid_entries(&saved.cpuid)?;
+        vcpu.set_state(&saved.state)?;
+        vcpus.push(vcpu);
+    }
+    emulate_msrs(k, &v, &mut vcpus)?;
+
+    Ok(Machine {
+        vm: v,
+        mem,
+        mem_slot,
+        vcpus,
+        devices: snap.devices,
+        // Virtio devices aren't saved in snapshots yet.
+        virtio: Vec::new(),
+    })
+}
+
+/// Capture the state of the vm, other than memory. All vcpus must be stopped.
+fn capture(
+    v: &vm::Vm,
+    vcpus: &[vm::Vcpu],
+    mem: &MemoryMmap,
+    serial: &Serial,
+    memhp: &MemoryHotplug,
+) -> Result<Snapshot, Error> {
+    if memhp.plugged() > 0 {
+        return Err(Error::HotpluggedMemory);
+    }
+
+    let mut snap = Snapshot {
+        vm: v.state()?,
+        vcpus: Vec::new(),
+        devices: vec![
+            DeviceSnapshot {
+                kind: DeviceKind::Serial,
+                bus: BusKind::Pio,
+                range: MemoryRange::new(MemoryAddr(SERIAL_BASE), SERIAL_LEN),
+                state: serial.save_state(),
+            },
+            DeviceSnapshot {
+                kind: DeviceKind::MemoryHotplug,
+                bus: BusKind::Pio,
+                range: MemoryRange::new(MemoryAddr(memhp::REGISTER_BASE), memhp::REGISTER_LEN),
+                state: memhp.save_state(),
+            },
+        ],
+        memory: vec![MemoryRange::new(MemoryAddr(0), mem.len())],
+    };
+    for vcpu in vcpus.iter() {
+        snap.vcpus.push(VcpuSnapshot {
+            id: vcpu.id(),
+            cpuid: vcpu.cpuid_entries()?,
+            state: vcpu.state()?,
+        });
+    }
+    Ok(snap)
+}
+
+/// Save a snapshot to `path`, and its memory alongside it.
+fn save_snapshot(
+    path: &Path,
+    snap: &Snapshot,
+    mem: &MemoryMmap,
+    memory: SnapshotMemory,
+) -> Result<(), Error> {
+    let mut w = BufWriter::new(File::create(path)?);
+    snap.save(&mut w)?;
+    w.flush()?;
+    let mut file = File::create(Snapshot::memory_path(path))?;
+    match memory {
+        SnapshotMemory::Full(format) => snapshot::save_memory(&mut file, &[mem], format)?,
+        SnapshotMemory::Diff(dirty) => snapshot::save_memory_diff(&mut file, &[(mem, dirty)])?,
+    }
+    Ok(())
+}
 
-    v.init_memory(&mem, &k).unwrap();
+/// Send memory to `addr` while the guest runs, until few enough pages are
+/// being written between rounds to stop the vm.
+fn precopy(
+    addr: &Endpoint,
+    v: &vm::Vm,
+    mem_slot: u32,
+    mem: &Mutex<MemoryMmap>,
+) -> Result<migration::Sender, Error> {
+    let len = mem.lock().unwrap().len();
+    let mut sender =
+        migration::Sender::new(addr.connect()?, &[MemoryRange::new(MemoryAddr(0), len)])?;
 
-    let mut vcpu = vm::Vcpu::new(&v).unwrap();
-    vcpu.configure_kernel_load(&v, &mut mem, info.entry_point, info.heap_end)
-        .unwrap();
+    // Pages written from here on are sent again in later rounds.
+    v.dirty_pages(mem_slot)?;
+    {
+        let mem = mem.lock().unwrap();
+        sender.send_memory(&[(&mem, &snapshot::nonzero_pages(&mem)?)])?;
+    }
+    for round in 1..=MIGRATION_MAX_ROUNDS {
+        let dirty = v.dirty_pages(mem_slot)?;
+        let pages: u32 = dirty.iter().map(|word| word.count_ones()).sum();
+        debug!("migration round {}: {} dirty pages", round, pages);
+        sender.send_memory(&[(&mem.lock().unwrap(), &dirty)])?;
+        if pages <= MIGRATION_STOP_PAGES {
+            break;
+        }
+    }
+    Ok(sender)
+}
+
+/// Send the memory written since `precopy` and the rest of the vm's state.
+/// All vcpus must be stopped.
+fn finish_migration(
+    mut sender: migration::Sender,
+    v: &vm::Vm,
+    mem_slot: u32,
+    vcpus: &[vm::Vcpu],
+    mem: &Mutex<MemoryMmap>,
+    serial: &Mutex<Serial>,
+    memhp: &Mutex<MemoryHotplug>,
+) -> Result<(), Error> {
+    let dirty = v.dirty_pages(mem_slot)?;
+    let mem = mem.lock().unwrap();
+    sender.send_memory(&[(&mem, &dirty)])?;
+    let snap = capture(
+        v,
+        vcpus,
+        &mem,
+        &serial.lock().unwrap(),
+        &memhp.lock().unwrap(),
+    )?;
+    sender.finish(&snap)?;
+    Ok(())
+}
+
+/// Write a core dump of the stopped vcpus and all guest memory to `path`.
+fn write_core(
+    path: &Path,
+    vcpus: &[vm::Vcpu],
+    mem: &Mutex<MemoryMmap>,
+    memhp: &Mutex<MemoryHotplug>,
+) -> Result<(), Error> {
+    let mut cpus = Vec::new();
+    for vcpu in vcpus.iter() {
+        cpus.push(vmcore::CpuState {
+            id: vcpu.id(),
+            regs: vcpu.regs()?,
+            sregs: vcpu.sregs()?,
+            kernel_gs_base: vcpu.get_msrs(&[MSR_KERNEL_GS_BASE])?[0].data,
+        });
+    }
+
+    let mem = mem.lock().unwrap();
+    let hotplugged = memhp.lock().unwrap().regions();
+    let hotplugged: Vec<_> = hotplugged
+        .iter()
+        .map(|(addr, mem)| (*addr, mem.lock().unwrap()))
+        .collect();
+    let mut regions = vec![(MemoryAddr(0), &*mem)];
+    regions.extend(hotplugged.iter().map(|(addr, mem)| (*addr, &**mem)));
 
-    let mut mmio_bus = device::Bus::new();
-    let dev = Arc::new(Mutex::new(mem));
-    let len = dev.lock().unwrap().len();
-    mmio_bus
-        .insert(device::Range(memory::MemoryAddr(0), len), dev)
-        .unwrap();
-    let mut pio_bus = device::Bus::new();
+    let mut w = BufWriter::new(File::create(path)?);
+    vmcore::write(&mut w, &cpus, &regions)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The id and exit of a vcpu that stopped on its own.
+type StoppedVcpu = (u8, Result<VcpuExit, vm::Error>);
+
+/// Pause and stop all vcpus so the vm can be saved, returning them so they
+/// may be started again. If a vcpu stopped on its own first, its exit is
+/// returned instead.
+fn stop_vcpus(mut manager: VcpuManager) -> Result<Result<Vec<vm::Vcpu>, StoppedVcpu>, Error> {
+    manager.pause();
+    if let Some(exit) = manager.take_exit() {
+        manager.stop();
+        return Ok(Err(exit));
+    }
+    let num_vcpus = manager.num_vcpus();
+    let vcpus = manager.stop();
+    if vcpus.len() != num_vcpus {
+        return Err(Error::VcpuExited);
+    }
+    Ok(Ok(vcpus))
+}
+
+/// Wait for gdb to connect and let it debug the vm until it detaches,
+/// returning the manager of the still running vcpus. If the guest stops
+/// during the session, or gdb kills it, its exit is returned instead.
+fn debug(
+    addr: &str,
+    vcpus: Vec<vm::Vcpu>,
+    mmio_bus: Arc<Bus>,
+) -> Result<Result<VcpuManager, VcpuExit>, Error> {
+    let listener = TcpListener::bind(addr)?;
+    info!("waiting for gdb on {}", addr);
+    let (conn, peer) = listener.accept()?;
+    info!("gdb connected from {}", peer);
+    conn.set_nodelay(true)?;
+    let stub = Stub::new(VmTarget::new(vcpus, mmio_bus), conn.try_clone()?, conn);
+    match stub.run()? {
+        (Outcome::Detached, target) => Ok(Ok(target
+            .into_manager()
+            .expect("detached target isn't running"))),
+        (Outcome::Exited(id, result), _) => {
+            info!("vcpu {} stopped while debugging: {:?}", id, result);
+            Ok(Err(result?))
+        }
+        (Outcome::Killed, _) => {
+            info!("killed by gdb");
+            Ok(Err(VcpuExit::Shutdown))
+        }
+    }
+}
+
+/// Add memory to the running guest above any plugged so far.
+fn plug_memory(v: &vm::Vm, memhp: &Mutex<MemoryHotplug>, size: usize) {
+    let mut memhp = memhp.lock().unwrap();
+    let addr = memhp.next_addr(MemoryAddr(HOTPLUG_BASE));
+    let mem = match MemoryMmap::new(size) {
+        Ok(mem) => mem,
+        Err(e) => {
+            error!("failed to allocate hotplugged memory: {:?}", e);
+            return;
+        }
+    };
+    match memhp.plug(v, addr, mem) {
+        Ok(()) => info!("plugged {:#x} bytes of memory at {}", size, addr),
+        Err(e) => error!("failed to plug memory: {:?}", e),
+    }
+}
+
+/// Create the vm, booting or restoring it as requested, and run it until the
+/// guest stops.
+fn run_vm(
+    k: &vm::KvmContext,
+    opts: &Options,
+    monitor: Option<&Monitor>,
+) -> Result<VcpuExit, Error> {
+    let Machine {
+        vm: v,
+        mem,
+        mem_slot,
+        mut vcpus,
+        devices,
+        virtio,
+    } = match (&opts.restore, &opts.incoming) {
+        (Some(path), _) => restore(k, path)?,
+        (None, Some(addr)) => incoming(k, addr)?,
+        // No virtio devices are implemented yet.
+        (None, None) => boot(k, opts.cpus.unwrap_or(DEFAULT_VCPUS), Vec::new())?,
+    };
+
+    let mmio_bus = Arc::new(Bus::new());
+    let len = mem.len();
+    let mem = Arc::new(Mutex::new(mem));
+    mmio_bus.insert(MemoryRange::new(MemoryAddr(0), len), mem.clone())?;
+    for (range, transport) in virtio {
+        mmio_bus.insert(range, transport)?;
+    }
+
+    let pio_bus = Arc::new(Bus::new());
     let serial = Arc::new(Mutex::new(Serial::new()));
-    pio_bus
-        .insert(device::Range(memory::MemoryAddr(0x3f8), 8), serial)
-        .unwrap();
-    vcpu.set_mmio_bus(mmio_bus);
-    vcpu.set_pio_bus(pio_bus);
+    let memhp = Arc::new(Mutex::new(MemoryHotplug::new(mmio_bus.clone())));
+    if devices.is_empty() {
+        pio_bus.insert(
+            MemoryRange::new(MemoryAddr(SERIAL_BASE), SERIAL_LEN),
+            serial.clone(),
+        )?;
+        pio_bus.insert(
+            MemoryRange::new(MemoryAddr(memhp::REGISTER_BASE), memhp::REGISTER_LEN),
+            memhp.clone(),
+        )?;
+    }
+    for saved in devices.iter() {
+        let dev: Arc<Mutex<Addressable>> = match saved.kind {
+            DeviceKind::Serial => {
+                serial.lock().unwrap().restore_state(&saved.state)?;
+                serial.clone()
+            }
+            DeviceKind::MemoryHotplug => {
+                memhp.lock().unwrap().restore_state(&saved.state)?;
+                memhp.clone()
+            }
+        };
+        let bus = match saved.bus {
+            BusKind::Pio => &pio_bus,
+            BusKind::Mmio => &mmio_bus,
+        };
+        bus.insert(saved.range, dev)?;
+    }
+
+    for vcpu in vcpus.iter_mut() {
+        vcpu.set_mmio_bus(mmio_bus.clone());
+        vcpu.set_pio_bus(pio_bus.clone());
+        vcpu.add_exit_handler(Box::new(I8042::new()));
+        vcpu.add_exit_handler(Box::new(SleepControl::new()));
+    }
+
+    let mut manager = match opts.gdb {
+        Some(ref addr) => match debug(addr, vcpus, mmio_bus.clone())? {
+            Ok(manager) => manager,
+            Err(exit) => return Ok(exit),
+        },
+        None => VcpuManager::start(vcpus)?,
+    };
+    // The vm stops as soon as any vcpu does.
+    let mut saved = 0;
+    // Time waited towards the next snapshot or migration.
+    let mut waited = Duration::from_secs(0);
+    let (id, result) = loop {
+        let interval = match (&opts.snapshot, &opts.migrate) {
+            (Some(schedule), _) => Some(schedule.interval),
+            (None, Some((after, _))) => Some(*after),
+            (None, None) => None,
+        };
+        // Wake up regularly to check for core dump requests and commands.
+        let poll = opts.core.is_some() || monitor.is_some();
+        let timeout = match (interval, poll) {
+            (Some(interval), false) => interval - waited,
+            (Some(interval), true) => (interval - waited).min(POLL_INTERVAL),
+            (None, true) => POLL_INTERVAL,
+            (None, false) => {
+                let exit = manager.wait_exit();
+                manager.stop();
+                break exit;
+            }
+        };
+        if let Some(exit) = manager.wait_exit_timeout(timeout) {
+            manager.stop();
+            break exit;
+        }
+
+        while let Some(command) = monitor.and_then(Monitor::try_next) {
+            match command {
+                Command::PlugMemory(size) => plug_memory(&v, &memhp, size),
+                Command::DumpCore if opts.core.is_none() => {
+                    warn!("no core dump path given, see --core")
+                }
+                Command::DumpCore => vmcore::request_dump(),
+            }
+        }
+        if let Some(ref path) = opts.core {
+            if vmcore::dump_requested() {
+                let vcpus = match stop_vcpus(manager)? {
+                    Ok(vcpus) => vcpus,
+                    Err(exit) => break exit,
+                };
+                match write_core(path, &vcpus, &mem, &memhp) {
+                    Ok(()) => info!("wrote core dump to {}", path.display()),
+                    Err(e) => error!("failed to write core dump: {:?}", e),
+                }
+                manager = VcpuManager::start(vcpus)?;
+            }
+        }
+        waited += timeout;
+        match interval {
+            Some(interval) if waited >= interval => waited = Duration::from_secs(0),
+            _ => continue,
+        }
+
+        if let Some((_, ref addr)) = opts.migrate {
+            let sender = match precopy(addr, &v, mem_slot, &mem) {
+                Ok(sender) => sender,
+                Err(e) => {
+                    error!("migration failed, retrying later: {:?}", e);
+                    continue;
+                }
+            };
+            let vcpus = match stop_vcpus(manager)? {
+                Ok(vcpus) => vcpus,
+                Err(exit) => break exit,
+            };
+            let result = finish_migration(sender, &v, mem_slot, &vcpus, &mem, &serial, &memhp);
+            match result {
+                Ok(()) => {
+                    info!("migrated vm to {:?}", addr);
+                    return Ok(VcpuExit::Shutdown);
+                }
+                // The receiver hasn't taken over, so the guest carries on
+                // here.
+                Err(e) => error!("migration failed, resuming: {:?}", e),
+            }
+            manager = VcpuManager::start(vcpus)?;
+            continue;
+        }
 
-    loop {
-        vcpu.run().unwrap();
-        debug!("exited");
+        let schedule = opts.snapshot.as_ref().unwrap();
+        let vcpus = match stop_vcpus(manager)? {
+            Ok(vcpus) => vcpus,
+            Err(exit) => break exit,
+        };
+        let mem = mem.lock().unwrap();
+        let snap = capture(
+            &v,
+            &vcpus,
+            &mem,
+            &serial.lock().unwrap(),
+            &memhp.lock().unwrap(),
+        );
+        let snap = match snap {
+            Ok(snap) => snap,
+            // E.g. after memory was hotplugged. The guest carries on.
+            Err(e) => {
+                error!("failed to take snapshot, resuming: {:?}", e);
+                manager = VcpuManager::start(vcpus)?;
+                continue;
+            }
+        };
+        // Always taken so that the next diff only holds pages written after
+        // this snapshot.
+        let dirty = v.dirty_pages(mem_slot)?;
+        let (path, memory) = if saved == 0 {
+            let format = if opts.sparse {
+                MemoryFormat::Sparse
+            } else {
+                MemoryFormat::Full
+            };
+            (schedule.path.clone(), SnapshotMemory::Full(format))
+        } else {
+            (
+                diff_path(&schedule.path, saved),
+                SnapshotMemory::Diff(&dirty),
+            )
+        };
+        match save_snapshot(&path, &snap, &mem, memory) {
+            Ok(()) => {
+                info!("saved snapshot to {}", path.display());
+                saved += 1;
+                if !schedule.repeat {
+                    return Ok(VcpuExit::Shutdown);
+                }
+            }
+            // The dirty log was reset anyway, so a later diff would miss
+            // pages written before this snapshot. Start over with a full one.
+            Err(e) => {
+                error!("failed to save snapshot, resuming: {:?}", e);
+                saved = 0;
+            }
+        }
+        manager = VcpuManager::start(vcpus)?;
+    };
+    info!("vcpu {} stopped: {:?}", id, result);
+    for (port, count) in pio_bus.unmapped_accesses() {
+        debug!("unmapped io port {:#x}: {} accesses", port.0, count);
     }
+    Ok(result?)
 }
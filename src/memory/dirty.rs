@@ -0,0 +1,70 @@
+use super::{MemoryAddr, MemoryRange};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Size of the pages tracked by a dirty bitmap. Matches the granularity of
+/// the KVM dirty log.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Tracks pages written to by userspace, e.g. by device emulation.
+///
+/// Bits are laid out the same as the bitmap returned by `KVM_GET_DIRTY_LOG`
+/// so the two can be merged directly.
+pub struct DirtyBitmap {
+    words: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    /// Create a bitmap covering `size` bytes of memory.
+    pub fn new(size: usize) -> Self {
+        let pages = size.div_ceil(PAGE_SIZE);
+        let words = (0..pages.div_ceil(64)).map(|_| AtomicU64::new(0)).collect();
+        DirtyBitmap { words }
+    }
+
+    /// Mark every page touched by the `len` bytes starting at `addr` as dirty.
+    pub fn mark(&self, addr: &MemoryAddr, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = addr.page_number();
+        let last = MemoryRange::new(*addr, len)
+            .end()
+            .unchecked_sub(1)
+            .page_number();
+        for page in first..=last {
+            if let Some(word) = self.words.get(page / 64) {
+                word.fetch_or(1 << (page % 64), Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Return the current bitmap, clearing it.
+    pub fn take(&self) -> Vec<u64> {
+        self.words
+            .iter()
+            .map(|word| word.swap(0, Ordering::SeqCst))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark_spanning_pages() {
+        let bitmap = DirtyBitmap::new(PAGE_SIZE * 70);
+        bitmap.mark(&MemoryAddr(PAGE_SIZE - 1), 2);
+        bitmap.mark(&MemoryAddr(PAGE_SIZE * 65), 1);
+        let bits = bitmap.take();
+        assert_eq!(vec![0b11, 0b10], bits);
+        assert_eq!(vec![0, 0], bitmap.take());
+    }
+
+    #[test]
+    fn mark_out_of_range() {
+        let bitmap = DirtyBitmap::new(PAGE_SIZE);
+        bitmap.mark(&MemoryAddr(PAGE_SIZE * 128), 1);
+        assert_eq!(vec![0], bitmap.take());
+    }
+}
@@ -1,23 +1,221 @@
 use std::convert::From;
 use std::fmt;
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
+/// Page size used for page number calculations.
+const PAGE_SHIFT: usize = 12;
+
+/// A guest physical address.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Hash)]
 pub struct MemoryAddr(pub usize);
 
-impl fmt::Display for MemoryAddr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:x}", self.0)
+/// An address in the host (vmm) virtual address space, e.g. the start of a
+/// mapping backing guest memory.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Hash)]
+pub struct HostAddr(pub usize);
+
+macro_rules! impl_addr {
+    ($t:ident) => {
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:x}", self.0)
+            }
+        }
+
+        impl $t {
+            /// Add an offset. Like `+`, overflow is only caught in debug
+            /// builds.
+            pub fn add_offset(&self, offset: usize) -> $t {
+                $t(self.0 + offset)
+            }
+
+            pub fn checked_add(&self, offset: usize) -> Option<$t> {
+                self.0.checked_add(offset).map($t)
+            }
+
+            pub fn checked_sub(&self, offset: usize) -> Option<$t> {
+                self.0.checked_sub(offset).map($t)
+            }
+
+            pub fn wrapping_add(&self, offset: usize) -> $t {
+                $t(self.0.wrapping_add(offset))
+            }
+
+            pub fn wrapping_sub(&self, offset: usize) -> $t {
+                $t(self.0.wrapping_sub(offset))
+            }
+
+            /// Add an offset. Should only be used when the result is known
+            /// not to overflow; panics if it does.
+            pub fn unchecked_add(&self, offset: usize) -> $t {
+                self.checked_add(offset).expect("address overflow")
+            }
+
+            /// Subtract an offset. Should only be used when the result is
+            /// known not to underflow; panics if it does.
+            pub fn unchecked_sub(&self, offset: usize) -> $t {
+                self.checked_sub(offset).expect("address underflow")
+            }
+
+            /// Distance from `base` to this address, if this address is not
+            /// below `base`.
+            pub fn offset_from(&self, base: $t) -> Option<usize> {
+                self.0.checked_sub(base.0)
+            }
+
+            /// Round up to a multiple of `align`, which must be a power of
+            /// two. Returns `None` on overflow.
+            pub fn align_up(&self, align: usize) -> Option<$t> {
+                debug_assert!(align.is_power_of_two());
+                self.0.checked_add(align - 1).map(|n| $t(n & !(align - 1)))
+            }
+
+            /// Round down to a multiple of `align`, which must be a power of
+            /// two.
+            pub fn align_down(&self, align: usize) -> $t {
+                debug_assert!(align.is_power_of_two());
+                $t(self.0 & !(align - 1))
+            }
+
+            pub fn is_aligned(&self, align: usize) -> bool {
+                debug_assert!(align.is_power_of_two());
+                self.0 & (align - 1) == 0
+            }
+
+            /// Number of the 4K page containing this address.
+            pub fn page_number(&self) -> usize {
+                self.0 >> PAGE_SHIFT
+            }
+        }
+
+        impl From<u32> for $t {
+            fn from(n: u32) -> Self {
+                $t(n as usize)
+            }
+        }
+
+        impl From<u64> for $t {
+            fn from(n: u64) -> Self {
+                $t(n as usize)
+            }
+        }
+
+        impl From<$t> for u64 {
+            fn from(addr: $t) -> Self {
+                addr.0 as u64
+            }
+        }
+    };
+}
+
+impl_addr!(MemoryAddr);
+impl_addr!(HostAddr);
+
+impl<T> From<*mut T> for HostAddr {
+    fn from(ptr: *mut T) -> Self {
+        HostAddr(ptr as usize)
     }
 }
 
-impl MemoryAddr {
-    pub fn add_offset(&self, offset: usize) -> MemoryAddr {
-        MemoryAddr(self.0 + offset)
+/// A range of guest physical memory.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
+pub struct MemoryRange {
+    start: MemoryAddr,
+    len: usize,
+}
+
+impl MemoryRange {
+    pub fn new(start: MemoryAddr, len: usize) -> Self {
+        MemoryRange { start, len }
+    }
+
+    pub fn start(&self) -> MemoryAddr {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The first address past the end of the range. Saturates at the top of
+    /// the address space.
+    pub fn end(&self) -> MemoryAddr {
+        self.start
+            .checked_add(self.len)
+            .unwrap_or(MemoryAddr(usize::MAX))
+    }
+
+    pub fn contains(&self, addr: MemoryAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn overlaps(&self, other: &MemoryRange) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+
+    /// Offset of the address from the start of the range, if the address is
+    /// within the range.
+    pub fn offset_of(&self, addr: MemoryAddr) -> Option<usize> {
+        if self.contains(addr) {
+            addr.offset_from(self.start)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for MemoryRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {})", self.start, self.end())
     }
 }
 
-impl From<u32> for MemoryAddr {
-    fn from(n: u32) -> Self {
-        MemoryAddr(n as usize)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic() {
+        let addr = MemoryAddr(usize::MAX - 1);
+        assert_eq!(None, addr.checked_add(2));
+        assert_eq!(Some(MemoryAddr(usize::MAX)), addr.checked_add(1));
+        assert_eq!(MemoryAddr(0), addr.wrapping_add(2));
+        assert_eq!(None, MemoryAddr(1).checked_sub(2));
+        assert_eq!(MemoryAddr(usize::MAX), MemoryAddr(0).wrapping_sub(1));
+        assert_eq!(MemoryAddr(usize::MAX), addr.unchecked_add(1));
+        assert_eq!(MemoryAddr(0x10), MemoryAddr(0x30).unchecked_sub(0x20));
+        assert_eq!(Some(0x10), MemoryAddr(0x30).offset_from(MemoryAddr(0x20)));
+        assert_eq!(None, MemoryAddr(0x10).offset_from(MemoryAddr(0x20)));
+    }
+
+    #[test]
+    fn alignment() {
+        assert_eq!(
+            Some(MemoryAddr(0x2000)),
+            MemoryAddr(0x1001).align_up(0x1000)
+        );
+        assert_eq!(
+            Some(MemoryAddr(0x1000)),
+            MemoryAddr(0x1000).align_up(0x1000)
+        );
+        assert_eq!(None, MemoryAddr(usize::MAX).align_up(0x1000));
+        assert_eq!(MemoryAddr(0x1000), MemoryAddr(0x1fff).align_down(0x1000));
+        assert!(MemoryAddr(0x3000).is_aligned(0x1000));
+        assert!(!MemoryAddr(0x3001).is_aligned(0x1000));
+        assert_eq!(3, MemoryAddr(0x3fff).page_number());
+    }
+
+    #[test]
+    fn range() {
+        let r = MemoryRange::new(MemoryAddr(0x10), 0x10);
+        assert!(r.contains(MemoryAddr(0x10)));
+        assert!(r.contains(MemoryAddr(0x1f)));
+        assert!(!r.contains(MemoryAddr(0x20)));
+        assert_eq!(Some(4), r.offset_of(MemoryAddr(0x14)));
+        assert_eq!(None, r.offset_of(MemoryAddr(0x4)));
+        assert!(r.overlaps(&MemoryRange::new(MemoryAddr(0x1f), 1)));
+        assert!(!r.overlaps(&MemoryRange::new(MemoryAddr(0x20), 1)));
+
+        let top = MemoryRange::new(MemoryAddr(usize::MAX - 1), 0x10);
+        assert!(top.contains(MemoryAddr(usize::MAX - 1)));
     }
 }
@@ -1,20 +1,134 @@
-use super::memoryaddr::MemoryAddr;
+use super::dirty::DirtyBitmap;
+use super::memoryaddr::{HostAddr, MemoryAddr};
 use super::{Addressable, Error, Memory, Region, Result};
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::io;
 use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Not exported by libc.
+const MAP_HUGE_SHIFT: libc::c_int = 26;
+
+/// Size of the huge pages backing a mapping.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    fn mmap_flags(self) -> libc::c_int {
+        let shift = match self {
+            HugePageSize::Size2M => 21,
+            HugePageSize::Size1G => 30,
+        };
+        libc::MAP_HUGETLB | (shift << MAP_HUGE_SHIFT)
+    }
+}
+
+/// Where the pages of a mapping come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backing {
+    /// Anonymous shared memory using the default page size.
+    Anonymous,
+    /// Anonymous memory from the kernel's pool of reserved huge pages.
+    HugeTlb(HugePageSize),
+    /// A file created in the given hugetlbfs mount. The page size must match
+    /// the one the mount was configured with.
+    HugeTlbFs(PathBuf, HugePageSize),
+}
+
+/// Options for creating guest memory.
+#[derive(Clone, Debug)]
+pub struct MmapOptions {
+    pub backing: Backing,
+    /// Advise the kernel to back the mapping with transparent huge pages. Only
+    /// meaningful for anonymous backings.
+    pub transparent_hugepages: bool,
+}
+
+impl Default for MmapOptions {
+    fn default() -> Self {
+        MmapOptions {
+            backing: Backing::Anonymous,
+            transparent_hugepages: false,
+        }
+    }
+}
 
 pub struct MemoryMmap {
     region: RegionMmap,
+    /// Page numbers (in units of the region's page size) that have been
+    /// given back to the host.
+    inflated: BTreeSet<usize>,
 }
 
 impl MemoryMmap {
     pub fn new(size: usize) -> Result<Self> {
-        let region = RegionMmap::new(size)?;
-        Ok(MemoryMmap { region })
+        Self::with_options(size, &MmapOptions::default())
+    }
+
+    pub fn with_options(size: usize, opts: &MmapOptions) -> Result<Self> {
+        let region = RegionMmap::with_options(size, opts)?;
+        Ok(MemoryMmap {
+            region,
+            inflated: BTreeSet::new(),
+        })
     }
 
     pub fn as_ptr(&self) -> *mut u8 {
         self.region.as_ptr()
     }
+
+    pub fn host_addr(&self) -> HostAddr {
+        HostAddr::from(self.region.as_ptr())
+    }
+
+    pub fn dirty_bitmap(&self) -> Arc<DirtyBitmap> {
+        self.region.dirty_bitmap()
+    }
+
+    /// Give the pages in the range back to the host, recording them as
+    /// inflated. The range must be aligned to the page size of the backing.
+    pub fn inflate(&mut self, addr: MemoryAddr, len: usize) -> Result<()> {
+        self.region.discard(addr, len)?;
+        let page_size = self.region.page_size();
+        let first = addr.0 / page_size;
+        self.inflated.extend(first..first + len / page_size);
+        Ok(())
+    }
+
+    /// Return pages in the range to the guest. The contents of previously
+    /// inflated pages will be zero when next accessed.
+    pub fn deflate(&mut self, addr: MemoryAddr, len: usize) -> Result<()> {
+        self.region.check_range(&addr, len)?;
+        let page_size = self.region.page_size();
+        let first = addr.0 / page_size;
+        for page in first..first + len / page_size {
+            self.inflated.remove(&page);
+        }
+        Ok(())
+    }
+
+    /// Number of bytes currently held by the balloon.
+    pub fn inflated_bytes(&self) -> usize {
+        self.inflated.len() * self.region.page_size()
+    }
+
+    /// Check if the page containing addr is currently inflated.
+    pub fn is_inflated(&self, addr: &MemoryAddr) -> bool {
+        self.inflated.contains(&(addr.0 / self.region.page_size()))
+    }
 }
 
 impl Memory for MemoryMmap {}
@@ -47,25 +161,88 @@ impl Addressable for MemoryMmap {
 pub struct RegionMmap {
     addr: *mut u8,
     size: usize,
+    page_size: usize,
+    dirty: Arc<DirtyBitmap>,
 }
 
 impl RegionMmap {
     pub fn new(size: usize) -> Result<Self> {
-        let load_addr: *mut u8 = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
+        Self::with_options(size, &MmapOptions::default())
+    }
+
+    /// Map a new region according to the provided options.
+    ///
+    /// Huge page backed mappings are not created with MAP_NORESERVE so that a
+    /// lack of available huge pages is reported here instead of as a SIGBUS
+    /// when the guest first touches the memory.
+    pub fn with_options(size: usize, opts: &MmapOptions) -> Result<Self> {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let addr = match &opts.backing {
+            Backing::Anonymous => mmap(
                 size,
-                libc::PROT_READ | libc::PROT_WRITE,
+                prot,
                 libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
                 -1,
-                0,
-            ) as *mut u8
+            )
+            .map_err(Error::MmapFailed)?,
+            Backing::HugeTlb(page_size) => {
+                if size % page_size.bytes() != 0 {
+                    return Err(Error::UnalignedSize);
+                }
+                mmap(
+                    size,
+                    prot,
+                    libc::MAP_ANONYMOUS | libc::MAP_SHARED | page_size.mmap_flags(),
+                    -1,
+                )
+                .map_err(Error::HugePagesUnavailable)?
+            }
+            Backing::HugeTlbFs(dir, page_size) => {
+                let mount_page_size =
+                    hugetlbfs_page_size(dir).map_err(Error::HugePagesUnavailable)?;
+                if mount_page_size != page_size.bytes() {
+                    return Err(Error::InvalidOptions);
+                }
+                if size % page_size.bytes() != 0 {
+                    return Err(Error::UnalignedSize);
+                }
+                let fd = hugetlbfs_file(dir, size).map_err(Error::HugePagesUnavailable)?;
+                let res = mmap(size, prot, libc::MAP_SHARED | libc::MAP_POPULATE, fd);
+                // The mapping keeps the file alive.
+                unsafe { libc::close(fd) };
+                res.map_err(Error::HugePagesUnavailable)?
+            }
         };
 
-        Ok(RegionMmap {
-            addr: load_addr,
-            size: size,
-        })
+        let page_size = match &opts.backing {
+            Backing::Anonymous => 4096,
+            Backing::HugeTlb(page_size) | Backing::HugeTlbFs(_, page_size) => page_size.bytes(),
+        };
+
+        let region = RegionMmap {
+            addr,
+            size,
+            page_size,
+            dirty: Arc::new(DirtyBitmap::new(size)),
+        };
+
+        if opts.transparent_hugepages {
+            if opts.backing != Backing::Anonymous {
+                return Err(Error::InvalidOptions);
+            }
+            let ret = unsafe {
+                libc::madvise(
+                    region.addr as *mut libc::c_void,
+                    region.size,
+                    libc::MADV_HUGEPAGE,
+                )
+            };
+            if ret < 0 {
+                return Err(Error::HugePagesUnavailable(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(region)
     }
 
     fn check_bounds(&self, addr: &MemoryAddr) -> Result<()> {
@@ -75,28 +252,89 @@ impl RegionMmap {
         Ok(())
     }
 
+    /// Check that the range is within the region and page aligned.
+    fn check_range(&self, addr: &MemoryAddr, len: usize) -> Result<()> {
+        if !addr.is_aligned(self.page_size) || len % self.page_size != 0 {
+            return Err(Error::UnalignedAddr);
+        }
+        match addr.checked_add(len) {
+            Some(end) if end.0 <= self.size => (),
+            _ => return Err(Error::OutOfBounds),
+        }
+        Ok(())
+    }
+
     pub fn as_ptr(&self) -> *mut u8 {
         self.addr
     }
 
+    /// Size of the pages backing this region.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Release the backing pages for the range to the host. Subsequent
+    /// accesses will see zeroed memory.
+    ///
+    /// All of our mappings are shared, so MADV_REMOVE is needed to actually
+    /// free the pages. MADV_DONTNEED is used as a fallback for backings that
+    /// don't support punching holes.
+    pub fn discard(&mut self, addr: MemoryAddr, len: usize) -> Result<()> {
+        self.check_range(&addr, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let ptr = unsafe { self.addr.add(addr.0) } as *mut libc::c_void;
+        let mut ret = unsafe { libc::madvise(ptr, len, libc::MADV_REMOVE) };
+        if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+            ret = unsafe { libc::madvise(ptr, len, libc::MADV_DONTNEED) };
+        }
+        if ret < 0 {
+            return Err(Error::DiscardFailed(io::Error::last_os_error()));
+        }
+        // Contents changed from the guest's point of view.
+        self.dirty.mark(&addr, len);
+        Ok(())
+    }
+
+    /// Bitmap of pages written to through this region. Writes made by the
+    /// guest directly are not tracked here.
+    pub fn dirty_bitmap(&self) -> Arc<DirtyBitmap> {
+        self.dirty.clone()
+    }
+
     unsafe fn as_mut_slice(&self) -> &mut [u8] {
         std::slice::from_raw_parts_mut(self.addr, self.size)
     }
 }
 
+// The mapping is owned by the region and isn't aliased by any other host
+// thread without going through the region.
+unsafe impl Send for RegionMmap {}
+
+impl Drop for RegionMmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
 impl Region for RegionMmap {
     fn len(&self) -> usize {
         self.size
     }
 
     fn read_from<F: Read>(&mut self, addr: MemoryAddr, f: &mut F, count: usize) -> Result<usize> {
-        let end = addr.0 + count - 1;
-        if end >= self.size {
-            return Err(Error::OutOfBounds);
-        }
+        let end = match addr.checked_add(count) {
+            Some(end) if end.0 <= self.size => end.0,
+            _ => return Err(Error::OutOfBounds),
+        };
         unsafe {
             let slice = &mut self.as_mut_slice()[addr.0..end];
-            Ok(f.read(slice).map_err(Error::ReadFailed)?)
+            let n = f.read(slice).map_err(Error::ReadFailed)?;
+            self.dirty.mark(&addr, n);
+            Ok(n)
         }
     }
 
@@ -119,11 +357,65 @@ impl Addressable for RegionMmap {
         self.check_bounds(&addr)?;
         unsafe {
             let mut slice = &mut self.as_mut_slice()[addr.0..];
-            Ok(slice.write(buf).map_err(Error::WriteFailed)?)
+            let n = slice.write(buf).map_err(Error::WriteFailed)?;
+            self.dirty.mark(&addr, n);
+            Ok(n)
         }
     }
 }
 
+fn mmap(
+    size: usize,
+    prot: libc::c_int,
+    flags: libc::c_int,
+    fd: libc::c_int,
+) -> io::Result<*mut u8> {
+    let addr = unsafe { libc::mmap(std::ptr::null_mut(), size, prot, flags, fd, 0) };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr as *mut u8)
+}
+
+/// The page size of the hugetlbfs mount containing `dir`. Fails if `dir` isn't
+/// on a hugetlbfs mount.
+fn hugetlbfs_page_size(dir: &Path) -> io::Result<usize> {
+    const HUGETLBFS_MAGIC: libc::c_long = 0x9584_58f6;
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if buf.f_type != HUGETLBFS_MAGIC {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(buf.f_bsize as usize)
+}
+
+/// Create an unlinked file of the given size in a hugetlbfs directory,
+/// returning its descriptor.
+fn hugetlbfs_file(dir: &Path, size: usize) -> io::Result<libc::c_int> {
+    let template = dir.join("submarine-XXXXXX");
+    let template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let raw = template.into_raw();
+    let fd = unsafe { libc::mkstemp(raw) };
+    let path = unsafe { CString::from_raw(raw) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        libc::unlink(path.as_ptr());
+        if libc::ftruncate(fd, size as libc::off_t) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+    }
+    Ok(fd)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -139,11 +431,89 @@ mod tests {
         let mut m = RegionMmap::new(4).unwrap();
         let to_write = &[4, 5, 4, 3];
         let addr = MemoryAddr(0);
-        let n = m.write(to_write, addr.clone()).unwrap();
+        let n = m.write(to_write, addr).unwrap();
         assert_eq!(4, n);
         let buf = &mut [0; 4];
         let n = m.read(buf, addr).unwrap();
         assert_eq!(4, n);
         assert_eq!(to_write, buf);
     }
+
+    #[test]
+    fn write_marks_dirty() {
+        let mut m = RegionMmap::new(4096 * 2).unwrap();
+        m.write(&[1], MemoryAddr(4096)).unwrap();
+        assert_eq!(vec![0b10], m.dirty_bitmap().take());
+    }
+
+    #[test]
+    fn discard_zeroes() {
+        let mut m = RegionMmap::new(4096 * 2).unwrap();
+        m.write(&[1; 8], MemoryAddr(4096)).unwrap();
+        m.dirty_bitmap().take();
+        m.discard(MemoryAddr(4096), 4096).unwrap();
+        let buf = &mut [1; 8];
+        m.read(buf, MemoryAddr(4096)).unwrap();
+        assert_eq!(&[0; 8], buf);
+        assert_eq!(vec![0b10], m.dirty_bitmap().take());
+    }
+
+    #[test]
+    fn discard_unaligned() {
+        let mut m = RegionMmap::new(4096 * 2).unwrap();
+        match m.discard(MemoryAddr(10), 4096) {
+            Err(Error::UnalignedAddr) => (),
+            _ => panic!("expected unaligned error"),
+        }
+    }
+
+    #[test]
+    fn inflate_deflate() {
+        let mut m = MemoryMmap::new(4096 * 4).unwrap();
+        m.inflate(MemoryAddr(0), 4096 * 2).unwrap();
+        assert_eq!(4096 * 2, m.inflated_bytes());
+        assert!(m.is_inflated(&MemoryAddr(4096 + 10)));
+        m.deflate(MemoryAddr(4096), 4096).unwrap();
+        assert_eq!(4096, m.inflated_bytes());
+        assert!(!m.is_inflated(&MemoryAddr(4096)));
+    }
+
+    #[test]
+    fn hugetlb_unaligned_size() {
+        let opts = MmapOptions {
+            backing: Backing::HugeTlb(HugePageSize::Size2M),
+            transparent_hugepages: false,
+        };
+        match RegionMmap::with_options(4096, &opts) {
+            Err(Error::UnalignedSize) => (),
+            _ => panic!("expected unaligned size error"),
+        }
+    }
+
+    #[test]
+    fn hugetlbfs_not_a_mount() {
+        let opts = MmapOptions {
+            backing: Backing::HugeTlbFs(std::env::temp_dir(), HugePageSize::Size2M),
+            transparent_hugepages: false,
+        };
+        match RegionMmap::with_options(2 << 20, &opts) {
+            Err(Error::HugePagesUnavailable(_)) => (),
+            _ => panic!("expected hugetlbfs mount error"),
+        }
+    }
+
+    #[test]
+    fn transparent_hugepages() {
+        let opts = MmapOptions {
+            transparent_hugepages: true,
+            ..Default::default()
+        };
+        // THP may be disabled on the host, but it must never be a hard
+        // failure to create the mapping for any other reason.
+        match RegionMmap::with_options(4 << 20, &opts) {
+            Ok(m) => assert_eq!(4 << 20, m.len()),
+            Err(Error::HugePagesUnavailable(_)) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
 }
@@ -1,7 +1,9 @@
 mod memoryaddr;
-pub use memoryaddr::MemoryAddr;
+pub use memoryaddr::{HostAddr, MemoryAddr, MemoryRange};
 
+pub mod dirty;
 pub mod memorymap;
+pub mod paging;
 
 use std::io;
 use std::io::{Read, Write};
@@ -9,6 +11,12 @@ use std::io::{Read, Write};
 #[derive(Debug)]
 pub enum Error {
     OutOfBounds,
+    MmapFailed(io::Error),
+    HugePagesUnavailable(io::Error),
+    UnalignedSize,
+    UnalignedAddr,
+    InvalidOptions,
+    DiscardFailed(io::Error),
     ReadFailed(io::Error),
     WriteFailed(io::Error),
 }
@@ -34,7 +42,7 @@ pub trait Region: Addressable {
         Self: Sized;
 }
 
-pub trait Addressable {
+pub trait Addressable: Send {
     /// Read from memory into the provided buffer start at address. The amount
     /// read will be returned.
     fn read(&self, buf: &mut [u8], addr: MemoryAddr) -> Result<usize>;
@@ -0,0 +1,375 @@
+//! Translating guest virtual addresses by walking the guest's page tables.
+
+use super::{Addressable, Error as MemError, MemoryAddr};
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const PAGE_SIZE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// Bits 51:12 of 64-bit entries hold the address of the next table or page.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const LEGACY_ADDR_MASK: u64 = 0xffff_f000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The address isn't mapped, with the level of the missing entry
+    /// (1 for a page table entry).
+    NotPresent(u64, usize),
+    /// Bits above the translated range aren't a sign extension of the top
+    /// translated bit.
+    NonCanonical(u64),
+    /// A page table is outside of memory.
+    Memory(MemError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// 32-bit paging with two levels of 4-byte entries. 4 MiB pages are
+    /// always honoured, as if CR4.PSE is set.
+    Legacy,
+    /// 32-bit addresses with three levels of 8-byte entries.
+    Pae,
+    /// 48-bit addresses with four levels.
+    Level4,
+    /// 57-bit addresses with five levels.
+    Level5,
+}
+
+impl PagingMode {
+    /// Get the paging mode from the control registers and EFER, or `None` if
+    /// paging is disabled.
+    pub fn from_registers(cr0: u64, cr4: u64, efer: u64) -> Option<Self> {
+        if cr0 & CR0_PG == 0 {
+            return None;
+        }
+        let mode = if efer & EFER_LMA != 0 {
+            if cr4 & CR4_LA57 != 0 {
+                PagingMode::Level5
+            } else {
+                PagingMode::Level4
+            }
+        } else if cr4 & CR4_PAE != 0 {
+            PagingMode::Pae
+        } else {
+            PagingMode::Legacy
+        };
+        Some(mode)
+    }
+
+    /// Shift of the lowest address bit indexed at each level, from the top.
+    fn shifts(self) -> &'static [u32] {
+        match self {
+            PagingMode::Legacy => &[22, 12],
+            PagingMode::Pae => &[30, 21, 12],
+            PagingMode::Level4 => &[39, 30, 21, 12],
+            PagingMode::Level5 => &[48, 39, 30, 21, 12],
+        }
+    }
+
+    fn entry_size(self) -> u64 {
+        match self {
+            PagingMode::Legacy => 4,
+            _ => 8,
+        }
+    }
+
+    /// Number of bits of virtual address translated.
+    fn address_bits(self) -> u32 {
+        match self {
+            PagingMode::Legacy | PagingMode::Pae => 32,
+            PagingMode::Level4 => 48,
+            PagingMode::Level5 => 57,
+        }
+    }
+}
+
+/// A translated address and the access allowed to its page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub addr: u64,
+    /// Size of the page the address is in, 4 KiB or larger.
+    pub page_size: u64,
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+}
+
+/// Translate a guest virtual address using the page tables at `cr3`.
+///
+/// Permissions are combined across every level, the way the cpu checks
+/// them. Protection keys and the supervisor protection bits in CR0 and CR4
+/// aren't taken into account. In 32-bit modes only the low 32 bits of the
+/// address are used.
+pub fn translate(
+    mem: &dyn Addressable,
+    cr3: u64,
+    mode: PagingMode,
+    addr: u64,
+) -> Result<Translation> {
+    let addr = match mode {
+        PagingMode::Legacy | PagingMode::Pae => addr & 0xffff_ffff,
+        _ => {
+            // Bits above the translated ones must all equal the top one.
+            let unused = 64 - mode.address_bits();
+            if ((addr << unused) as i64 >> unused) as u64 != addr {
+                return Err(Error::NonCanonical(addr));
+            }
+            addr
+        }
+    };
+
+    let mut table = match mode {
+        PagingMode::Legacy => cr3 & LEGACY_ADDR_MASK,
+        // The PDPT is only 32-byte aligned.
+        PagingMode::Pae => cr3 & 0xffff_ffe0,
+        _ => cr3 & ADDR_MASK,
+    };
+    let shifts = mode.shifts();
+    let mut writable = true;
+    let mut user = true;
+    let mut executable = true;
+    for (i, &shift) in shifts.iter().enumerate() {
+        let level = shifts.len() - i;
+        let index_bits = match mode {
+            PagingMode::Legacy => 10,
+            PagingMode::Pae if i == 0 => 2,
+            _ => 9,
+        };
+        let index = (addr >> shift) & ((1 << index_bits) - 1);
+        let entry = read_entry(mem, table + index * mode.entry_size(), mode.entry_size())?;
+        if entry & PRESENT == 0 {
+            return Err(Error::NotPresent(addr, level));
+        }
+
+        // PAE page directory pointers have no permission or size bits.
+        let pae_pdpte = mode == PagingMode::Pae && i == 0;
+        if !pae_pdpte {
+            writable &= entry & WRITABLE != 0;
+            user &= entry & USER != 0;
+            if mode != PagingMode::Legacy {
+                executable &= entry & NO_EXECUTE == 0;
+            }
+        }
+
+        // Large pages can be mapped at the 1 GiB, 2 MiB and 4 MiB levels.
+        let large = !pae_pdpte && level > 1 && shift <= 30 && entry & PAGE_SIZE != 0;
+        if level == 1 || large {
+            let page_size = 1u64 << shift;
+            let base = if mode == PagingMode::Legacy && large {
+                // Bits 20:13 hold bits 39:32 of the address, with PSE-36.
+                (entry & 0xffc0_0000) | ((entry >> 13) & 0xff) << 32
+            } else if mode == PagingMode::Legacy {
+                entry & LEGACY_ADDR_MASK
+            } else {
+                entry & ADDR_MASK & !(page_size - 1)
+            };
+            return Ok(Translation {
+                addr: base | (addr & (page_size - 1)),
+                page_size,
+                writable,
+                user,
+                executable,
+            });
+        }
+
+        table = match mode {
+            PagingMode::Legacy => entry & LEGACY_ADDR_MASK,
+            _ => entry & ADDR_MASK,
+        };
+    }
+    unreachable!("the last level always maps a page")
+}
+
+fn read_entry(mem: &dyn Addressable, addr: u64, size: u64) -> Result<u64> {
+    let mut buf = [0; 8];
+    let buf = &mut buf[..size as usize];
+    let n = mem
+        .read(buf, MemoryAddr(addr as usize))
+        .map_err(Error::Memory)?;
+    if n != buf.len() {
+        return Err(Error::Memory(MemError::OutOfBounds));
+    }
+    let mut bytes = [0; 8];
+    bytes[..buf.len()].copy_from_slice(buf);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::memorymap::MemoryMmap;
+    use crate::memory::Addressable;
+
+    const MEM_SIZE: usize = 1 << 20;
+
+    fn write_entry(mem: &mut MemoryMmap, addr: u64, entry: u64, size: usize) {
+        mem.write(&entry.to_le_bytes()[..size], MemoryAddr(addr as usize))
+            .unwrap();
+    }
+
+    /// Build 4-level tables at 0x1000 mapping:
+    /// - 0x40_0000 to 0x8_0000 as a read-only, user, 4 KiB page
+    /// - 0x60_0000 to 0x20_0000 as a writable, no-execute, 2 MiB page
+    /// - 0x4000_0000 to 0xc000_0000 as a writable 1 GiB page
+    fn level4_tables(mem: &mut MemoryMmap, top: u64) {
+        let rwu = PRESENT | WRITABLE | USER;
+        write_entry(mem, top, 0x2000 | rwu, 8);
+        // PDPT: index 0 to the PD, index 1 a 1 GiB page.
+        write_entry(mem, 0x2000, 0x3000 | rwu, 8);
+        write_entry(mem, 0x2008, 0xc000_0000 | PRESENT | WRITABLE | PAGE_SIZE, 8);
+        // PD: index 2 to a PT, index 3 a 2 MiB page.
+        write_entry(mem, 0x3010, 0x4000 | rwu, 8);
+        write_entry(
+            mem,
+            0x3018,
+            0x20_0000 | PRESENT | WRITABLE | PAGE_SIZE | NO_EXECUTE,
+            8,
+        );
+        write_entry(mem, 0x4000, 0x8_0000 | PRESENT | USER, 8);
+    }
+
+    #[test]
+    fn level4() {
+        let mut mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        level4_tables(&mut mem, 0x1000);
+        let mode = PagingMode::Level4;
+
+        let t = translate(&mem, 0x1000, mode, 0x40_0123).unwrap();
+        assert_eq!(
+            Translation {
+                addr: 0x8_0123,
+                page_size: 0x1000,
+                writable: false,
+                user: true,
+                executable: true,
+            },
+            t
+        );
+
+        let t = translate(&mem, 0x1000, mode, 0x6f_ffff).unwrap();
+        assert_eq!((0x2f_ffff, 0x20_0000), (t.addr, t.page_size));
+        assert!(t.writable && !t.user && !t.executable);
+
+        let t = translate(&mem, 0x1000, mode, 0x4123_4567).unwrap();
+        assert_eq!((0xc123_4567, 1 << 30), (t.addr, t.page_size));
+        assert!(t.writable && !t.user && t.executable);
+
+        match translate(&mem, 0x1000, mode, 0x40_1000) {
+            Err(Error::NotPresent(0x40_1000, 1)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+        match translate(&mem, 0x1000, mode, 0x8000_0000_0000) {
+            Err(Error::NonCanonical(_)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+        // The upper half is canonical, but unmapped.
+        match translate(&mem, 0x1000, mode, 0xffff_8000_0000_0000) {
+            Err(Error::NotPresent(_, 4)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+    }
+
+    #[test]
+    fn level5() {
+        let mut mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        level4_tables(&mut mem, 0x1000);
+        // The last PML5 entry points to the PML4, mapping the top of the
+        // address space.
+        write_entry(&mut mem, 0x5000 + 511 * 8, 0x1000 | PRESENT | WRITABLE, 8);
+        let t = translate(&mem, 0x5000, PagingMode::Level5, 0xffff_0000_0040_0010).unwrap();
+        assert_eq!(0x8_0010, t.addr);
+        assert!(!t.user && !t.writable);
+        match translate(&mem, 0x5000, PagingMode::Level5, 0x40_0010) {
+            Err(Error::NotPresent(_, 5)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+    }
+
+    #[test]
+    fn pae() {
+        let mut mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        // PDPT at 0x1020, with the entry for 0xc000_0000 used.
+        write_entry(&mut mem, 0x1020 + 3 * 8, 0x2000 | PRESENT, 8);
+        write_entry(&mut mem, 0x2000, 0x3000 | PRESENT | WRITABLE, 8);
+        write_entry(&mut mem, 0x2008, 0x40_0000 | PRESENT | PAGE_SIZE, 8);
+        write_entry(
+            &mut mem,
+            0x3008,
+            0x9000 | PRESENT | WRITABLE | NO_EXECUTE,
+            8,
+        );
+
+        let t = translate(&mem, 0x1020, PagingMode::Pae, 0xc000_1abc).unwrap();
+        assert_eq!((0x9abc, 0x1000), (t.addr, t.page_size));
+        assert!(t.writable && !t.user && !t.executable);
+
+        let t = translate(&mem, 0x1020, PagingMode::Pae, 0xc02f_0000).unwrap();
+        assert_eq!((0x4f_0000, 0x20_0000), (t.addr, t.page_size));
+        assert!(!t.writable);
+
+        match translate(&mem, 0x1020, PagingMode::Pae, 0x1000) {
+            Err(Error::NotPresent(_, 3)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+    }
+
+    #[test]
+    fn legacy() {
+        let mut mem = MemoryMmap::new(MEM_SIZE).unwrap();
+        // 0xc000_0000 through a page table, 0x0040_0000 as a 4 MiB page
+        // above 4 GiB.
+        write_entry(&mut mem, 0x1000 + 0x300 * 4, 0x2000 | PRESENT | WRITABLE, 4);
+        write_entry(&mut mem, 0x2000 + 5 * 4, 0x7000 | PRESENT | USER, 4);
+        write_entry(
+            &mut mem,
+            0x1000 + 4,
+            0x80_0000 | 0x1 << 13 | PRESENT | USER | PAGE_SIZE,
+            4,
+        );
+
+        let t = translate(&mem, 0x1000, PagingMode::Legacy, 0xc000_5123).unwrap();
+        assert_eq!((0x7123, 0x1000), (t.addr, t.page_size));
+        assert!(!t.writable && !t.user && t.executable);
+
+        let t = translate(&mem, 0x1000, PagingMode::Legacy, 0x4f_0000).unwrap();
+        assert_eq!((0x1_008f_0000, 0x40_0000), (t.addr, t.page_size));
+
+        // Tables outside of memory.
+        write_entry(&mut mem, 0x1008, 0xf000_0000 | PRESENT, 4);
+        match translate(&mem, 0x1000, PagingMode::Legacy, 0x80_0000) {
+            Err(Error::Memory(_)) => (),
+            r => panic!("unexpected translation {:?}", r),
+        }
+    }
+
+    #[test]
+    fn mode_from_registers() {
+        assert_eq!(None, PagingMode::from_registers(0x11, 0x20, 0x500));
+        let cr0 = CR0_PG | 1;
+        assert_eq!(
+            Some(PagingMode::Legacy),
+            PagingMode::from_registers(cr0, 0, 0)
+        );
+        assert_eq!(
+            Some(PagingMode::Pae),
+            PagingMode::from_registers(cr0, CR4_PAE, 0)
+        );
+        assert_eq!(
+            Some(PagingMode::Level4),
+            PagingMode::from_registers(cr0, CR4_PAE, EFER_LMA | 1 << 8)
+        );
+        assert_eq!(
+            Some(PagingMode::Level5),
+            PagingMode::from_registers(cr0, CR4_PAE | CR4_LA57, EFER_LMA)
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Control socket for a running vm.
+//!
+//! Commands are read one per line from connections to a unix socket and
+//! queued for the main loop, which picks them up with `Monitor::try_next`.
+//! Each line is answered with `ok` once queued, or with an error.
+//!
+//!   plug-memory MIB    add MIB mebibytes of memory to the guest
+//!   dump-core          write a core dump of the guest, see `--core`
+
+use log::warn;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Add this many bytes of memory to the guest.
+    PlugMemory(usize),
+    /// Write a core dump of the guest.
+    DumpCore,
+}
+
+impl Command {
+    fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("plug-memory", Some(mib)) => {
+                let mib: usize = mib.parse().ok().filter(|mib| *mib > 0)?;
+                Command::PlugMemory(mib.checked_mul(1 << 20)?)
+            }
+            ("dump-core", None) => return Some(Command::DumpCore),
+            _ => return None,
+        };
+        match words.next() {
+            Some(_) => None,
+            None => Some(command),
+        }
+    }
+}
+
+pub struct Monitor {
+    commands: Receiver<Command>,
+}
+
+impl Monitor {
+    /// Listen for connections on the given path. A socket left there by an
+    /// earlier run is replaced.
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)?;
+        let (tx, commands) = mpsc::channel();
+        thread::Builder::new()
+            .name("monitor".to_string())
+            .spawn(move || serve(listener, tx))?;
+        Ok(Monitor { commands })
+    }
+
+    /// Get the next queued command, if any.
+    pub fn try_next(&self) -> Option<Command> {
+        self.commands.try_recv().ok()
+    }
+}
+
+fn serve(listener: UnixListener, tx: Sender<Command>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("monitor accept failed: {}", e);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &tx) {
+                warn!("monitor connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn handle(stream: UnixStream, tx: &Sender<Command>) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        match Command::parse(&line?) {
+            Some(command) => {
+                // The main loop has gone, so the vm is stopping.
+                if tx.send(command).is_err() {
+                    return Ok(());
+                }
+                writeln!(out, "ok")?;
+            }
+            None => writeln!(out, "error: invalid command")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::Shutdown;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Some(Command::PlugMemory(128 << 20)),
+            Command::parse("plug-memory 128")
+        );
+        assert_eq!(None, Command::parse("plug-memory"));
+        assert_eq!(None, Command::parse("plug-memory 0"));
+        assert_eq!(None, Command::parse("plug-memory 1 2"));
+        assert_eq!(None, Command::parse("plug-memory -1"));
+        assert_eq!(None, Command::parse("unplug-memory 1"));
+        assert_eq!(Some(Command::DumpCore), Command::parse("dump-core"));
+        assert_eq!(None, Command::parse("dump-core now"));
+        assert_eq!(None, Command::parse(""));
+    }
+
+    #[test]
+    fn connection() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        client.write_all(b"plug-memory 1\nbogus\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        handle(server, &tx).unwrap();
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!("ok\nerror: invalid command\n", replies);
+        assert_eq!(Ok(Command::PlugMemory(1 << 20)), rx.try_recv());
+        assert!(rx.try_recv().is_err());
+    }
+}
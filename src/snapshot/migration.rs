@@ -0,0 +1,269 @@
+//! Moving a running vm to another process over a socket.
+//!
+//! Migration is pre-copy: memory is sent while the guest keeps running, then
+//! the pages written in the meantime are sent again until few enough remain.
+//! The vm is then stopped, and the last dirty pages are sent along with the
+//! rest of its state. The receiver acknowledges once it has recreated the vm,
+//! after which the sender can exit.
+//!
+//! Each message starts with a tag byte. Memory is sent in the format of
+//! `save_memory_diff`, and state in the format of `Snapshot::save`.
+
+use super::{
+    apply_memory_diff, read_range, save_memory_diff, write_range, Error, Result, Snapshot,
+};
+use crate::memory::memorymap::MemoryMmap;
+use crate::memory::MemoryRange;
+use crate::vm::pod;
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const MAGIC: u64 = 0x0052_4749_4d42_5553; // "SUBMIGR\0"
+const VERSION: u32 = 1;
+
+const MSG_MEMORY: u8 = 1;
+const MSG_STATE: u8 = 2;
+const ACK: u8 = 0x06;
+
+/// Upper bound on the number of memory regions in a migration.
+const MAX_MEMORY_REGIONS: usize = 64;
+
+/// A connection between the sending and receiving process.
+pub trait Channel: Read + Write + Send {}
+
+impl Channel for TcpStream {}
+impl Channel for UnixStream {}
+
+/// Where to migrate to, either `tcp:HOST:PORT` or `unix:PATH`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(String),
+}
+
+impl Endpoint {
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            Some(Endpoint::Tcp(addr.to_string()))
+        } else {
+            s.strip_prefix("unix:")
+                .map(|path| Endpoint::Unix(path.to_string()))
+        }
+    }
+
+    pub fn connect(&self) -> Result<Box<dyn Channel>> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr)?),
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path)?),
+        })
+    }
+
+    /// Wait for a single connection from a sender. A unix socket left at the
+    /// path by an earlier receiver is replaced.
+    pub fn accept(&self) -> Result<Box<dyn Channel>> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Box::new(TcpListener::bind(addr)?.accept()?.0),
+            Endpoint::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+                    _ => (),
+                }
+                Box::new(UnixListener::bind(path)?.accept()?.0)
+            }
+        })
+    }
+}
+
+/// The sending side of a migration.
+pub struct Sender {
+    w: BufWriter<Box<dyn Channel>>,
+}
+
+impl Sender {
+    /// Start a migration of a vm with the given memory regions.
+    pub fn new(channel: Box<dyn Channel>, memory: &[MemoryRange]) -> Result<Self> {
+        let mut w = BufWriter::new(channel);
+        pod::write(&mut w, &MAGIC)?;
+        pod::write(&mut w, &VERSION)?;
+        pod::write(&mut w, &(memory.len() as u32))?;
+        for range in memory.iter() {
+            write_range(&mut w, range)?;
+        }
+        Ok(Sender { w })
+    }
+
+    /// Send the pages set in each region's dirty bitmap.
+    pub fn send_memory(&mut self, regions: &[(&MemoryMmap, &[u64])]) -> Result<()> {
+        pod::write(&mut self.w, &MSG_MEMORY)?;
+        save_memory_diff(&mut self.w, regions)?;
+        self.w.flush()?;
+        Ok(())
+    }
+
+    /// Send the rest of the vm's state, returning once the receiver has
+    /// taken over the vm.
+    pub fn finish(mut self, snapshot: &Snapshot) -> Result<()> {
+        pod::write(&mut self.w, &MSG_STATE)?;
+        snapshot.save(&mut self.w)?;
+        self.w.flush()?;
+        match pod::read::<_, u8>(self.w.get_mut())? {
+            ACK => Ok(()),
+            _ => Err(Error::InvalidData("unexpected reply")),
+        }
+    }
+}
+
+/// The receiving side of a migration.
+pub struct Receiver {
+    channel: Box<dyn Channel>,
+    max_memory: usize,
+}
+
+impl Receiver {
+    /// Receive a vm with at most `max_memory` bytes of memory in total.
+    pub fn new(channel: Box<dyn Channel>, max_memory: usize) -> Self {
+        Receiver {
+            channel,
+            max_memory,
+        }
+    }
+
+    /// Receive memory and state until the sender stops the vm, returning the
+    /// final state and memory.
+    pub fn receive(&mut self) -> Result<(Snapshot, Vec<MemoryMmap>)> {
+        let max_memory = self.max_memory;
+        let mut r = BufReader::new(&mut self.channel);
+        let magic: u64 = pod::read(&mut r)?;
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version: u32 = pod::read(&mut r)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let count: u32 = pod::read(&mut r)?;
+        if count as usize > MAX_MEMORY_REGIONS {
+            return Err(Error::InvalidData("count too large"));
+        }
+        let mut ranges = Vec::new();
+        let mut total: usize = 0;
+        for _ in 0..count {
+            let range = read_range(&mut r)?;
+            total = total
+                .checked_add(range.len())
+                .filter(|total| *total <= max_memory)
+                .ok_or(Error::InvalidData("memory too large"))?;
+            ranges.push(range);
+        }
+        let mut memory = Vec::new();
+        for range in ranges.iter() {
+            memory.push(MemoryMmap::new(range.len()).map_err(Error::Memory)?);
+        }
+
+        loop {
+            match pod::read::<_, u8>(&mut r)? {
+                MSG_MEMORY => apply_memory_diff(&mut r, &mut memory)?,
+                MSG_STATE => return Ok((Snapshot::load(&mut r)?, memory)),
+                _ => return Err(Error::InvalidData("unknown message")),
+            }
+        }
+    }
+
+    /// Tell the sender that the vm has been recreated here.
+    pub fn ack(mut self) -> Result<()> {
+        pod::write(&mut self.channel, &ACK)?;
+        self.channel.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::dirty::PAGE_SIZE;
+    use crate::memory::{Addressable, MemoryAddr};
+    use crate::vm::{KvmContext, Vm};
+    use std::thread;
+
+    #[test]
+    fn endpoint() {
+        assert_eq!(
+            Some(Endpoint::Tcp("127.0.0.1:4444".to_string())),
+            Endpoint::parse("tcp:127.0.0.1:4444")
+        );
+        assert_eq!(
+            Some(Endpoint::Unix("/tmp/sock".to_string())),
+            Endpoint::parse("unix:/tmp/sock")
+        );
+        assert_eq!(None, Endpoint::parse("/tmp/sock"));
+    }
+
+    #[test]
+    fn precopy() {
+        let kvm = KvmContext::new().unwrap();
+        let vm = Vm::new(&kvm).unwrap();
+        let snapshot = Snapshot {
+            vm: vm.state().unwrap(),
+            vcpus: Vec::new(),
+            devices: Vec::new(),
+            memory: vec![MemoryRange::new(MemoryAddr(0), PAGE_SIZE * 4)],
+        };
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut receiver = Receiver::new(Box::new(b), PAGE_SIZE * 4);
+            let received = receiver.receive().unwrap();
+            receiver.ack().unwrap();
+            received
+        });
+
+        let mut mem = MemoryMmap::new(PAGE_SIZE * 4).unwrap();
+        mem.write(&[1], MemoryAddr(0)).unwrap();
+        let mut sender = Sender::new(Box::new(a), &snapshot.memory).unwrap();
+        sender.send_memory(&[(&mem, &[0b1111])]).unwrap();
+        // Pages written after the first round are sent again.
+        mem.write(&[2], MemoryAddr(PAGE_SIZE * 2)).unwrap();
+        sender.send_memory(&[(&mem, &[0b100])]).unwrap();
+        sender.finish(&snapshot).unwrap();
+
+        let (received, memory) = receiver.join().unwrap();
+        assert_eq!(snapshot.memory, received.memory);
+        let buf = &mut [0; 1];
+        memory[0].read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!([1], *buf);
+        memory[0].read(buf, MemoryAddr(PAGE_SIZE * 2)).unwrap();
+        assert_eq!([2], *buf);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let memory = [
+            MemoryRange::new(MemoryAddr(0), PAGE_SIZE * 4),
+            MemoryRange::new(MemoryAddr(PAGE_SIZE * 4), PAGE_SIZE),
+        ];
+        Sender::new(Box::new(a), &memory).unwrap();
+        let mut receiver = Receiver::new(Box::new(b), PAGE_SIZE * 4);
+        match receiver.receive() {
+            Err(Error::InvalidData(_)) => (),
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = format!("/tmp/submarine-migration-{}.sock", std::process::id());
+        drop(UnixListener::bind(&path).unwrap());
+        let endpoint = Endpoint::Unix(path.clone());
+        let receiver = thread::spawn(move || endpoint.accept().map(|_| ()));
+        // Connecting to the stale socket fails until it's been replaced.
+        while UnixStream::connect(&path).is_err() {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        receiver.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+//! Saving a running vm to disk and restoring it, possibly in another process.
+//!
+//! A snapshot is made up of two files. The state file holds the state of the
+//! vm, vcpus and devices, along with the layout of guest memory. The memory
+//! file holds the contents of each memory region, one after the other.
+//!
+//! The memory file may instead be a diff, holding only the pages written
+//! since the previous snapshot. A chain of diffs is merged into the full
+//! memory file of the first snapshot before restoring.
+
+pub mod migration;
+
+use crate::memory::dirty::PAGE_SIZE;
+use crate::memory::memorymap::MemoryMmap;
+use crate::memory::{Addressable, Error as MemError, MemoryAddr, MemoryRange, Region};
+use crate::vm::pod;
+use crate::vm::state::{VcpuState, VmState};
+use kvm_bindings::kvm_cpuid_entry2;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: u64 = 0x0050_414e_5342_5553; // "SUBSNAP\0"
+const VERSION: u32 = 1;
+const DIFF_MAGIC: u64 = 0x0046_4649_4442_5553; // "SUBDIFF\0"
+
+/// Upper bounds on counts read from a state file.
+const MAX_VCPUS: usize = 256;
+const MAX_CPUID_ENTRIES: usize = 256;
+const MAX_DEVICES: usize = 256;
+const MAX_DEVICE_STATE: usize = 1 << 20;
+const MAX_MEMORY_REGIONS: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Memory(MemError),
+    BadMagic,
+    UnsupportedVersion(u32),
+    InvalidData(&'static str),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusKind {
+    Pio,
+    Mmio,
+}
+
+/// The kinds of devices that may be saved in a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Serial,
+    MemoryHotplug,
+}
+
+pub struct DeviceSnapshot {
+    pub kind: DeviceKind,
+    pub bus: BusKind,
+    pub range: MemoryRange,
+    pub state: Vec<u8>,
+}
+
+pub struct VcpuSnapshot {
+    pub id: u8,
+    pub cpuid: Vec<kvm_cpuid_entry2>,
+    pub state: VcpuState,
+}
+
+pub struct Snapshot {
+    pub vm: VmState,
+    pub vcpus: Vec<VcpuSnapshot>,
+    pub devices: Vec<DeviceSnapshot>,
+    /// Guest memory regions, in the order they're stored in the memory file.
+    pub memory: Vec<MemoryRange>,
+}
+
+/// How guest memory is written to the memory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFormat {
+    /// Write every page.
+    Full,
+    /// Leave holes in the file for pages that are entirely zero.
+    Sparse,
+}
+
+impl Snapshot {
+    /// The memory file belonging to the state file at `path`.
+    pub fn memory_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".mem");
+        PathBuf::from(name)
+    }
+
+    pub fn save<W: Write>(&self, w: &mut W) -> Result<()> {
+        pod::write(w, &MAGIC)?;
+        pod::write(w, &VERSION)?;
+        self.vm.serialize(w)?;
+
+        pod::write(w, &(self.vcpus.len() as u32))?;
+        for vcpu in self.vcpus.iter() {
+            pod::write(w, &vcpu.id)?;
+            pod::write_slice(w, &vcpu.cpuid)?;
+            vcpu.state.serialize(w)?;
+        }
+
+        pod::write(w, &(self.devices.len() as u32))?;
+        for dev in self.devices.iter() {
+            let kind: u32 = match dev.kind {
+                DeviceKind::Serial => 1,
+                DeviceKind::MemoryHotplug => 2,
+            };
+            let bus: u8 = match dev.bus {
+                BusKind::Pio => 0,
+                BusKind::Mmio => 1,
+            };
+            pod::write(w, &kind)?;
+            pod::write(w, &bus)?;
+            write_range(w, &dev.range)?;
+            pod::write_slice(w, &dev.state)?;
+        }
+
+        pod::write(w, &(self.memory.len() as u32))?;
+        for range in self.memory.iter() {
+            write_range(w, range)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<R: Read>(r: &mut R) -> Result<Self> {
+        let magic: u64 = pod::read(r)?;
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version: u32 = pod::read(r)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let vm = VmState::deserialize(r)?;
+
+        let mut vcpus = Vec::new();
+        for _ in 0..read_count(r, MAX_VCPUS)? {
+            vcpus.push(VcpuSnapshot {
+                id: pod::read(r)?,
+                cpuid: pod::read_vec(r, MAX_CPUID_ENTRIES)?,
+                state: VcpuState::deserialize(r)?,
+            });
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..read_count(r, MAX_DEVICES)? {
+            let kind = match pod::read::<_, u32>(r)? {
+                1 => DeviceKind::Serial,
+                2 => DeviceKind::MemoryHotplug,
+                _ => return Err(Error::InvalidData("unknown device kind")),
+            };
+            let bus = match pod::read::<_, u8>(r)? {
+                0 => BusKind::Pio,
+                1 => BusKind::Mmio,
+                _ => return Err(Error::InvalidData("unknown bus")),
+            };
+            devices.push(DeviceSnapshot {
+                kind,
+                bus,
+                range: read_range(r)?,
+                state: pod::read_vec(r, MAX_DEVICE_STATE)?,
+            });
+        }
+
+        let mut memory = Vec::new();
+        for _ in 0..read_count(r, MAX_MEMORY_REGIONS)? {
+            memory.push(read_range(r)?);
+        }
+
+        Ok(Snapshot {
+            vm,
+            vcpus,
+            devices,
+            memory,
+        })
+    }
+}
+
+fn write_range<W: Write>(w: &mut W, range: &MemoryRange) -> io::Result<()> {
+    pod::write(w, &u64::from(range.start()))?;
+    pod::write(w, &(range.len() as u64))
+}
+
+fn read_range<R: Read>(r: &mut R) -> io::Result<MemoryRange> {
+    let start: u64 = pod::read(r)?;
+    let len: u64 = pod::read(r)?;
+    Ok(MemoryRange::new(MemoryAddr::from(start), len as usize))
+}
+
+fn read_count<R: Read>(r: &mut R, max: usize) -> Result<usize> {
+    let count: u32 = pod::read(r)?;
+    if count as usize > max {
+        return Err(Error::InvalidData("count too large"));
+    }
+    Ok(count as usize)
+}
+
+fn is_zero(page: &[u8]) -> bool {
+    page.iter().all(|b| *b == 0)
+}
+
+/// Write the contents of each region to the memory file, one after the other.
+pub fn save_memory(file: &mut File, regions: &[&MemoryMmap], format: MemoryFormat) -> Result<()> {
+    let mut page = vec![0; PAGE_SIZE];
+    let mut total = 0;
+    for mem in regions.iter() {
+        for offset in (0..mem.len()).step_by(PAGE_SIZE) {
+            let n = mem
+                .read(&mut page, MemoryAddr(offset))
+                .map_err(Error::Memory)?;
+            let page = &page[..n];
+            if format == MemoryFormat::Sparse && is_zero(page) {
+                file.seek(SeekFrom::Current(n as i64))?;
+            } else {
+                file.write_all(page)?;
+            }
+        }
+        total += mem.len() as u64;
+    }
+    // Trailing holes aren't part of the file until its length is set.
+    file.set_len(total)?;
+    Ok(())
+}
+
+/// Read regions saved with `save_memory`, returning new memory for each of
+/// the ranges.
+pub fn load_memory(file: &mut File, ranges: &[MemoryRange]) -> Result<Vec<MemoryMmap>> {
+    let mut page = vec![0; PAGE_SIZE];
+    let mut regions = Vec::new();
+    for range in ranges.iter() {
+        let mut mem = MemoryMmap::new(range.len()).map_err(Error::Memory)?;
+        for offset in (0..range.len()).step_by(PAGE_SIZE) {
+            let page = &mut page[..PAGE_SIZE.min(range.len() - offset)];
+            file.read_exact(page)?;
+            // Untouched pages of fresh memory are already zero, and are left
+            // unallocated.
+            if !is_zero(page) {
+                mem.write(page, MemoryAddr(offset)).map_err(Error::Memory)?;
+            }
+        }
+        regions.push(mem);
+    }
+    Ok(regions)
+}
+
+/// Write the pages set in each region's dirty bitmap, as returned by
+/// `Vm::dirty_pages`.
+pub fn save_memory_diff<W: Write>(w: &mut W, regions: &[(&MemoryMmap, &[u64])]) -> Result<()> {
+    pod::write(w, &DIFF_MAGIC)?;
+    pod::write(w, &VERSION)?;
+    pod::write(w, &(regions.len() as u32))?;
+    let mut page = vec![0; PAGE_SIZE];
+    for (mem, bitmap) in regions.iter() {
+        pod::write(w, &(mem.len() as u64))?;
+        pod::write_slice(w, bitmap)?;
+        for offset in dirty_offsets(bitmap, mem.len()) {
+            let n = mem
+                .read(&mut page, MemoryAddr(offset))
+                .map_err(Error::Memory)?;
+            w.write_all(&page[..n])?;
+        }
+    }
+    Ok(())
+}
+
+/// A dirty bitmap with every page of `mem` that isn't entirely zero set,
+/// for saving the whole region as a diff.
+pub fn nonzero_pages(mem: &MemoryMmap) -> Result<Vec<u64>> {
+    let pages = mem.len().div_ceil(PAGE_SIZE);
+    let mut bitmap = vec![0; pages.div_ceil(64)];
+    let mut page = vec![0; PAGE_SIZE];
+    for n in 0..pages {
+        let len = mem
+            .read(&mut page, MemoryAddr(n * PAGE_SIZE))
+            .map_err(Error::Memory)?;
+        if !is_zero(&page[..len]) {
+            bitmap[n / 64] |= 1 << (n % 64);
+        }
+    }
+    Ok(bitmap)
+}
+
+/// Check whether a memory file was written by `save_memory_diff`, leaving the
+/// file positioned at its start.
+pub fn is_memory_diff(file: &mut File) -> Result<bool> {
+    let mut magic = [0; 8];
+    let diff = match file.read_exact(&mut magic) {
+        Ok(()) => u64::from_le_bytes(magic) == DIFF_MAGIC,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(Error::Io(e)),
+    };
+    file.seek(SeekFrom::Start(0))?;
+    Ok(diff)
+}
+
+/// Apply a diff to a full memory file, after which `base` holds the memory as
+/// it was when the diff was saved. Diffs must be applied in the order they
+/// were saved.
+///
+/// The diff's regions must exactly cover the file. Pages are never written
+/// past its end, but on error some may have been written.
+pub fn merge_memory_diff<R: Read>(base: &mut File, diff: &mut R) -> Result<()> {
+    let base_len = base.metadata()?.len();
+    let diff_len = read_memory_diff(diff, |page| {
+        if page.file_offset + page.data.len() as u64 > base_len {
+            return Err(Error::InvalidData("diff doesn't match base memory"));
+        }
+        base.seek(SeekFrom::Start(page.file_offset))?;
+        base.write_all(page.data)?;
+        Ok(())
+    })?;
+    if diff_len != base_len {
+        return Err(Error::InvalidData("diff doesn't match base memory"));
+    }
+    Ok(())
+}
+
+/// Apply a diff to memory regions, which must be the regions it was saved
+/// from.
+pub fn apply_memory_diff<R: Read>(diff: &mut R, regions: &mut [MemoryMmap]) -> Result<()> {
+    read_memory_diff(diff, |page| {
+        let mem = regions
+            .get_mut(page.region)
+            .filter(|mem| mem.len() == page.region_len)
+            .ok_or(Error::InvalidData("diff doesn't match memory regions"))?;
+        mem.write(page.data, MemoryAddr(page.offset))
+            .map_err(Error::Memory)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// A page read from a diff.
+struct DiffPage<'a> {
+    region: usize,
+    region_len: usize,
+    /// Offset of the page in its region.
+    offset: usize,
+    /// Offset of the page in a full memory file.
+    file_offset: u64,
+    data: &'a [u8],
+}
+
+/// Read a diff written by `save_memory_diff`, calling `f` for each page.
+/// Returns the total length of the regions the diff was saved from.
+fn read_memory_diff<R, F>(diff: &mut R, mut f: F) -> Result<u64>
+where
+    R: Read,
+    F: FnMut(DiffPage) -> Result<()>,
+{
+    let magic: u64 = pod::read(diff)?;
+    if magic != DIFF_MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let version: u32 = pod::read(diff)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut page = vec![0; PAGE_SIZE];
+    let mut start = 0;
+    for region in 0..read_count(diff, MAX_MEMORY_REGIONS)? {
+        let len = pod::read::<_, u64>(diff)? as usize;
+        let words = len.div_ceil(PAGE_SIZE * 64);
+        let bitmap: Vec<u64> = pod::read_vec(diff, words)?;
+        for offset in dirty_offsets(&bitmap, len) {
+            let data = &mut page[..PAGE_SIZE.min(len - offset)];
+            diff.read_exact(data)?;
+            f(DiffPage {
+                region,
+                region_len: len,
+                offset,
+                file_offset: start + offset as u64,
+                data,
+            })?;
+        }
+        start += len as u64;
+    }
+    Ok(start)
+}
+
+/// Offsets of the pages set in a dirty bitmap for a region of `len` bytes.
+fn dirty_offsets(bitmap: &[u64], len: usize) -> impl Iterator<Item = usize> + '_ {
+    bitmap
+        .iter()
+        .enumerate()
+        .flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+        .map(|page| page * PAGE_SIZE)
+        .filter(move |offset| *offset < len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    fn temp_file() -> File {
+        let mut template = *b"/tmp/submarine-snapshot-XXXXXX\0";
+        unsafe {
+            let fd = libc::mkstemp(template.as_mut_ptr() as *mut libc::c_char);
+            assert!(fd >= 0);
+            libc::unlink(template.as_ptr() as *const libc::c_char);
+            std::os::unix::io::FromRawFd::from_raw_fd(fd)
+        }
+    }
+
+    #[test]
+    fn memory_roundtrip() {
+        for format in [MemoryFormat::Full, MemoryFormat::Sparse].iter() {
+            let mut mem = MemoryMmap::new(PAGE_SIZE * 64).unwrap();
+            mem.write(&[1, 2, 3], MemoryAddr(PAGE_SIZE * 3 + 5))
+                .unwrap();
+            let mut other = MemoryMmap::new(PAGE_SIZE).unwrap();
+            other.write(&[9], MemoryAddr(0)).unwrap();
+
+            let mut file = temp_file();
+            save_memory(&mut file, &[&mem, &other], *format).unwrap();
+            let meta = file.metadata().unwrap();
+            assert_eq!(PAGE_SIZE as u64 * 65, meta.len());
+            if *format == MemoryFormat::Sparse {
+                assert!(meta.blocks() * 512 < meta.len());
+            }
+
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let ranges = [
+                MemoryRange::new(MemoryAddr(0), PAGE_SIZE * 64),
+                MemoryRange::new(MemoryAddr(1 << 32), PAGE_SIZE),
+            ];
+            let restored = load_memory(&mut file, &ranges).unwrap();
+            let buf = &mut [0; 3];
+            restored[0]
+                .read(buf, MemoryAddr(PAGE_SIZE * 3 + 5))
+                .unwrap();
+            assert_eq!([1, 2, 3], *buf);
+            restored[1].read(buf, MemoryAddr(0)).unwrap();
+            assert_eq!([9, 0, 0], *buf);
+        }
+    }
+
+    #[test]
+    fn merge_diff() {
+        let mut mem = MemoryMmap::new(PAGE_SIZE * 70).unwrap();
+        mem.write(&[1], MemoryAddr(0)).unwrap();
+        let mut base = temp_file();
+        save_memory(&mut base, &[&mem], MemoryFormat::Sparse).unwrap();
+        assert!(!is_memory_diff(&mut base).unwrap());
+
+        mem.write(&[2], MemoryAddr(PAGE_SIZE * 66 + 1)).unwrap();
+        let mut diff = temp_file();
+        let bitmap = [0, 1 << 2];
+        save_memory_diff(&mut diff, &[(&mem, &bitmap)]).unwrap();
+        diff.seek(SeekFrom::Start(0)).unwrap();
+        assert!(is_memory_diff(&mut diff).unwrap());
+
+        merge_memory_diff(&mut base, &mut diff).unwrap();
+        base.seek(SeekFrom::Start(0)).unwrap();
+        let ranges = [MemoryRange::new(MemoryAddr(0), PAGE_SIZE * 70)];
+        let merged = load_memory(&mut base, &ranges).unwrap();
+        let buf = &mut [0; 2];
+        merged[0].read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!([1, 0], *buf);
+        merged[0].read(buf, MemoryAddr(PAGE_SIZE * 66)).unwrap();
+        assert_eq!([0, 2], *buf);
+    }
+
+    #[test]
+    fn merge_mismatched_diff() {
+        let mem = MemoryMmap::new(PAGE_SIZE * 4).unwrap();
+        let mut base = temp_file();
+        save_memory(&mut base, &[&mem], MemoryFormat::Full).unwrap();
+
+        // Too long for the base, and would write past its end.
+        let longer = MemoryMmap::new(PAGE_SIZE * 8).unwrap();
+        let mut diff = Vec::new();
+        save_memory_diff(&mut diff, &[(&longer, &[1 << 6])]).unwrap();
+        match merge_memory_diff(&mut base, &mut &diff[..]) {
+            Err(Error::InvalidData(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(PAGE_SIZE as u64 * 4, base.metadata().unwrap().len());
+
+        // Too few regions, even though no page is out of bounds.
+        let shorter = MemoryMmap::new(PAGE_SIZE * 2).unwrap();
+        let mut diff = Vec::new();
+        save_memory_diff(&mut diff, &[(&shorter, &[1])]).unwrap();
+        match merge_memory_diff(&mut base, &mut &diff[..]) {
+            Err(Error::InvalidData(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut diff = Vec::new();
+        save_memory_diff(&mut diff, &[(&shorter, &[1]), (&shorter, &[0])]).unwrap();
+        merge_memory_diff(&mut base, &mut &diff[..]).unwrap();
+    }
+
+    #[test]
+    fn bad_magic() {
+        let buf = [0; 16];
+        match Snapshot::load(&mut &buf[..]) {
+            Err(Error::BadMagic) => (),
+            _ => panic!("expected bad magic"),
+        }
+    }
+
+    #[test]
+    fn memory_path() {
+        assert_eq!(
+            PathBuf::from("/tmp/vm.snap.mem"),
+            Snapshot::memory_path(Path::new("/tmp/vm.snap"))
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Running vcpus on their own threads, under the control of a manager.
+//!
+//! Each vcpu thread listens for commands between runs. A thread blocked in
+//! KVM_RUN is kicked with `run::kick_signal`, which makes KVM_RUN return
+//! (or, if the thread wasn't in KVM_RUN yet, return as soon as it enters)
+//! so that the command is seen promptly.
+
+use super::exit::{DebugExit, VcpuExit};
+use super::{run, Error, Result, Vcpu};
+use log::{debug, error};
+use std::collections::VecDeque;
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VcpuCommand {
+    /// Stop running the guest until resumed.
+    Pause,
+    Resume,
+    /// Stop running the guest and end the thread.
+    Stop,
+}
+
+/// Sent by vcpu threads to the manager.
+#[derive(Debug)]
+enum VcpuEvent {
+    Paused(u8),
+    /// The vcpu hit a breakpoint or finished a single step, and is paused
+    /// until resumed.
+    Debug(u8, DebugExit),
+    /// The vcpu stopped on its own, and its thread has ended.
+    Exited(u8, Result<VcpuExit>),
+}
+
+struct VcpuHandle {
+    id: u8,
+    commands: Sender<VcpuCommand>,
+    thread: JoinHandle<Option<Vcpu>>,
+    exited: bool,
+}
+
+impl VcpuHandle {
+    fn send(&self, cmd: VcpuCommand) {
+        // The thread may have already exited.
+        let _ = self.commands.send(cmd);
+        let ret = unsafe { libc::pthread_kill(self.thread.as_pthread_t(), run::kick_signal()) };
+        if ret != 0 {
+            debug!("failed to kick vcpu {}: {}", self.id, ret);
+        }
+    }
+}
+
+/// Owns the threads of running vcpus.
+pub struct VcpuManager {
+    handles: Vec<VcpuHandle>,
+    events: Receiver<VcpuEvent>,
+    /// Exits received while waiting for other events.
+    exits: VecDeque<(u8, Result<VcpuExit>)>,
+}
+
+impl VcpuManager {
+    /// Start running each vcpu on a thread of its own.
+    pub fn start(vcpus: Vec<Vcpu>) -> Result<Self> {
+        run::register_kick_handler().map_err(Error::KickSignal)?;
+        let (event_tx, events) = mpsc::channel();
+        let mut handles = Vec::new();
+        for vcpu in vcpus.into_iter() {
+            let id = vcpu.id();
+            let (commands, command_rx) = mpsc::channel();
+            let event_tx = event_tx.clone();
+            let thread = thread::Builder::new()
+                .name(format!("vcpu{}", id))
+                .spawn(move || vcpu_thread(vcpu, command_rx, event_tx))
+                .map_err(Error::SpawnThread)?;
+            handles.push(VcpuHandle {
+                id,
+                commands,
+                thread,
+                exited: false,
+            });
+        }
+        Ok(VcpuManager {
+            handles,
+            events,
+            exits: VecDeque::new(),
+        })
+    }
+
+    /// Number of vcpus started, including any that have since exited.
+    pub fn num_vcpus(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Pause all vcpus, returning once none of them are running the guest.
+    pub fn pause(&mut self) {
+        let mut waiting = 0;
+        for handle in self.handles.iter().filter(|h| !h.exited) {
+            handle.send(VcpuCommand::Pause);
+            waiting += 1;
+        }
+        // Every vcpu either acknowledges the pause, or exits without seeing
+        // it.
+        while waiting > 0 {
+            self.next_event();
+            waiting -= 1;
+        }
+    }
+
+    pub fn resume(&self) {
+        for handle in self.handles.iter().filter(|h| !h.exited) {
+            handle.send(VcpuCommand::Resume);
+        }
+    }
+
+    /// Wait for a vcpu to stop on its own, e.g. because the guest powered
+    /// off, returning its id and exit. A vcpu stopping at a breakpoint
+    /// returns `VcpuExit::Debug` and stays paused, while other vcpus keep
+    /// running.
+    pub fn wait_exit(&mut self) -> (u8, Result<VcpuExit>) {
+        loop {
+            if let Some(exit) = self.exits.pop_front() {
+                return exit;
+            }
+            self.next_event();
+        }
+    }
+
+    /// Like `wait_exit`, but giving up after `timeout`.
+    pub fn wait_exit_timeout(&mut self, timeout: Duration) -> Option<(u8, Result<VcpuExit>)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(exit) = self.exits.pop_front() {
+                return Some(exit);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            match self.events.recv_timeout(deadline - now) {
+                Ok(event) => self.handle_event(event),
+                // Every vcpu has already exited.
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            }
+        }
+    }
+
+    /// Take an exit that arrived while waiting for other events, such as a
+    /// vcpu stopping on its own during `pause`.
+    pub fn take_exit(&mut self) -> Option<(u8, Result<VcpuExit>)> {
+        self.exits.pop_front()
+    }
+
+    /// Stop all vcpus and wait for their threads to end. Vcpus that hadn't
+    /// exited on their own are returned, and may be saved or started again.
+    pub fn stop(self) -> Vec<Vcpu> {
+        for handle in self.handles.iter().filter(|h| !h.exited) {
+            handle.send(VcpuCommand::Stop);
+        }
+        let mut vcpus = Vec::new();
+        for handle in self.handles.into_iter() {
+            match handle.thread.join() {
+                Ok(Some(vcpu)) => vcpus.push(vcpu),
+                Ok(None) => (),
+                Err(_) => error!("vcpu {} thread panicked", handle.id),
+            }
+        }
+        vcpus
+    }
+
+    /// Wait for the next event, recording exits.
+    fn next_event(&mut self) {
+        // The manager holds no senders, so this only fails if every thread has
+        // ended, which they only do after sending an exit or being stopped.
+        let event = self.events.recv().expect("vcpu threads disconnected");
+        self.handle_event(event);
+    }
+
+    fn handle_event(&mut self, event: VcpuEvent) {
+        match event {
+            VcpuEvent::Paused(id) => debug!("vcpu {} paused", id),
+            // Reported like an exit, but the thread lives on.
+            VcpuEvent::Debug(id, debug) => self.exits.push_back((id, Ok(VcpuExit::Debug(debug)))),
+            VcpuEvent::Exited(id, result) => {
+                if let Some(handle) = self.handles.iter_mut().find(|h| h.id == id) {
+                    handle.exited = true;
+                }
+                self.exits.push_back((id, result));
+            }
+        }
+    }
+}
+
+/// Run the vcpu until it exits, returning it if stopped by the manager.
+fn vcpu_thread(
+    mut vcpu: Vcpu,
+    commands: Receiver<VcpuCommand>,
+    events: Sender<VcpuEvent>,
+) -> Option<Vcpu> {
+    vcpu.bind_to_thread();
+    loop {
+        match commands.try_recv() {
+            Ok(VcpuCommand::Pause) => {
+                // A paused vcpu may be saved, so its state must be complete.
+                complete_io(&mut vcpu);
+                let _ = events.send(VcpuEvent::Paused(vcpu.id()));
+                match wait_resume(vcpu.id(), &commands, &events) {
+                    VcpuCommand::Stop => return Some(vcpu),
+                    _ => continue,
+                }
+            }
+            Ok(VcpuCommand::Stop) => {
+                complete_io(&mut vcpu);
+                return Some(vcpu);
+            }
+            // Drained any commands, or the manager is gone.
+            Ok(VcpuCommand::Resume) | Err(_) => (),
+        }
+
+        let result = match vcpu.run() {
+            Ok(VcpuExit::Continue) | Ok(VcpuExit::Halt) | Ok(VcpuExit::Interrupted) => continue,
+            Ok(VcpuExit::Debug(debug)) => {
+                let _ = events.send(VcpuEvent::Debug(vcpu.id(), debug));
+                match wait_resume(vcpu.id(), &commands, &events) {
+                    VcpuCommand::Stop => return Some(vcpu),
+                    _ => continue,
+                }
+            }
+            r => r,
+        };
+        if result.is_err() {
+            match vcpu.state() {
+                Ok(state) => error!("vcpu {} state:\n{}", vcpu.id(), state),
+                Err(e) => error!("failed to get vcpu {} state: {:?}", vcpu.id(), e),
+            }
+        }
+        let _ = events.send(VcpuEvent::Exited(vcpu.id(), result));
+        return None;
+    }
+}
+
+fn complete_io(vcpu: &mut Vcpu) {
+    if let Err(e) = vcpu.complete_io() {
+        error!("vcpu {} failed to complete io: {:?}", vcpu.id(), e);
+    }
+}
+
+/// Block while paused, returning the command that ended the pause.
+fn wait_resume(
+    id: u8,
+    commands: &Receiver<VcpuCommand>,
+    events: &Sender<VcpuEvent>,
+) -> VcpuCommand {
+    loop {
+        match commands.recv() {
+            Ok(VcpuCommand::Pause) => {
+                let _ = events.send(VcpuEvent::Paused(id));
+            }
+            Ok(cmd) => return cmd,
+            Err(_) => return VcpuCommand::Stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::{real_mode_vcpu, PowerOff};
+    use super::super::{KvmContext, Vm};
+    use super::*;
+
+    #[test]
+    fn pause_resume_stop() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [0xeb, 0xfe /* jmp . */];
+        let (_mem, vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+
+        let mut manager = VcpuManager::start(vec![vcpu]).unwrap();
+        manager.pause();
+        // Pausing twice is fine.
+        manager.pause();
+        manager.resume();
+        assert_eq!(1, manager.stop().len());
+    }
+
+    #[test]
+    fn wait_exit() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [0xe6, 0x30 /* out %al, $0x30 */];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+        vcpu.add_exit_handler(Box::new(PowerOff(0x30)));
+
+        let mut manager = VcpuManager::start(vec![vcpu]).unwrap();
+        match manager.wait_exit() {
+            (0, Ok(VcpuExit::Shutdown)) => (),
+            r => panic!("unexpected exit: {:?}", r),
+        }
+        assert!(manager
+            .wait_exit_timeout(Duration::from_millis(10))
+            .is_none());
+        // Pausing an exited vcpu doesn't wait on it.
+        manager.pause();
+        assert!(manager.stop().is_empty());
+    }
+}
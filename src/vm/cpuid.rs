@@ -0,0 +1,303 @@
+//! Filtering of the cpuid leaves exposed to the guest.
+//!
+//! KVM reports every feature it's able to virtualize, which isn't quite what
+//! a guest should see. Leaves describing topology need to be made consistent
+//! with the vcpus we actually create, and some features need to be hidden.
+
+use kvm_bindings::kvm_cpuid_entry2;
+
+// Bits in leaf 0x1.
+const LEAF1_ECX_MONITOR: u32 = 1 << 3;
+const LEAF1_ECX_VMX: u32 = 1 << 5;
+const LEAF1_ECX_HYPERVISOR: u32 = 1 << 31;
+const LEAF1_EDX_HTT: u32 = 1 << 28;
+
+const KVM_SIGNATURE_LEAF: u32 = 0x4000_0000;
+const KVM_FEATURES_LEAF: u32 = 0x4000_0001;
+/// "KVMKVMKVM\0\0\0"
+const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+
+/// Flag indicating the leaf uses its index (subleaf).
+const KVM_CPUID_FLAG_SIGNIFICANT_INDEX: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuidReg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Bits to clear from a cpuid register.
+#[derive(Clone, Debug)]
+pub struct CpuidMask {
+    pub function: u32,
+    /// Subleaf index. Ignored for leaves without a significant index.
+    pub index: u32,
+    pub reg: CpuidReg,
+    pub clear: u32,
+}
+
+/// A set of features to hide from the guest, used to present the same cpu
+/// model regardless of the host the vm is running on.
+#[derive(Clone, Debug, Default)]
+pub struct CpuidTemplate {
+    pub masks: Vec<CpuidMask>,
+}
+
+impl CpuidTemplate {
+    /// A conservative template hiding features that commonly differ between
+    /// hosts: AVX-512, TSX, PKU, and a few of the newer instructions.
+    pub fn baseline() -> Self {
+        let mask = |function, index, reg, clear| CpuidMask {
+            function,
+            index,
+            reg,
+            clear,
+        };
+        CpuidTemplate {
+            masks: vec![
+                // xsaves, xgetbv1, xsavec
+                mask(0xd, 1, CpuidReg::Eax, 0b1110),
+                // hle, rtm, avx512{f,dq,ifma,pf,er,cd,bw,vl}, sha
+                mask(
+                    0x7,
+                    0,
+                    CpuidReg::Ebx,
+                    (1 << 4)
+                        | (1 << 11)
+                        | (1 << 16)
+                        | (1 << 17)
+                        | (1 << 21)
+                        | (1 << 26)
+                        | (1 << 27)
+                        | (1 << 28)
+                        | (1 << 29)
+                        | (1 << 30)
+                        | (1 << 31),
+                ),
+                // avx512vbmi, pku, ospke, avx512vbmi2, gfni, vaes,
+                // vpclmulqdq, avx512vnni, avx512bitalg, avx512vpopcntdq,
+                // rdpid
+                mask(
+                    0x7,
+                    0,
+                    CpuidReg::Ecx,
+                    (1 << 1)
+                        | (1 << 3)
+                        | (1 << 4)
+                        | (1 << 6)
+                        | (1 << 8)
+                        | (1 << 9)
+                        | (1 << 10)
+                        | (1 << 11)
+                        | (1 << 12)
+                        | (1 << 14)
+                        | (1 << 22),
+                ),
+                // avx512_4vnniw, avx512_4fmaps
+                mask(0x7, 0, CpuidReg::Edx, (1 << 2) | (1 << 3)),
+            ],
+        }
+    }
+
+    fn apply(&self, entry: &mut kvm_cpuid_entry2) {
+        for mask in self.masks.iter() {
+            if mask.function != entry.function {
+                continue;
+            }
+            if entry.flags & KVM_CPUID_FLAG_SIGNIFICANT_INDEX != 0 && mask.index != entry.index {
+                continue;
+            }
+            match mask.reg {
+                CpuidReg::Eax => entry.eax &= !mask.clear,
+                CpuidReg::Ebx => entry.ebx &= !mask.clear,
+                CpuidReg::Ecx => entry.ecx &= !mask.clear,
+                CpuidReg::Edx => entry.edx &= !mask.clear,
+            }
+        }
+    }
+}
+
+/// Patch the entries reported by KVM for the vcpu with the given id.
+pub fn filter_entries(
+    entries: &mut Vec<kvm_cpuid_entry2>,
+    vcpu_id: u8,
+    num_vcpus: u8,
+    template: Option<&CpuidTemplate>,
+) {
+    if !entries.iter().any(|e| e.function == KVM_SIGNATURE_LEAF) {
+        entries.push(kvm_cpuid_entry2 {
+            function: KVM_SIGNATURE_LEAF,
+            ..Default::default()
+        });
+    }
+
+    // Number of bits needed to represent an apic id within the package.
+    let core_bits = 32 - (u32::from(num_vcpus.max(1)) - 1).leading_zeros();
+
+    for entry in entries.iter_mut() {
+        match entry.function {
+            0x1 => {
+                entry.ebx &= 0x0000_ffff;
+                entry.ebx |= u32::from(vcpu_id) << 24;
+                entry.ebx |= u32::from(num_vcpus) << 16;
+                if num_vcpus > 1 {
+                    entry.edx |= LEAF1_EDX_HTT;
+                } else {
+                    entry.edx &= !LEAF1_EDX_HTT;
+                }
+                entry.ecx &= !(LEAF1_ECX_MONITOR | LEAF1_ECX_VMX);
+                entry.ecx |= LEAF1_ECX_HYPERVISOR;
+            }
+            0x4 => {
+                // Deterministic cache parameters. Each vcpu is a core in a
+                // single package, with only the last level cache shared.
+                let level = (entry.eax >> 5) & 0x7;
+                let sharing = if level >= 3 {
+                    u32::from(num_vcpus.max(1)) - 1
+                } else {
+                    0
+                };
+                entry.eax &= 0x3fff;
+                entry.eax |= (u32::from(num_vcpus.max(1)) - 1) << 26;
+                entry.eax |= sharing << 14;
+            }
+            0x6 | 0xa => {
+                // No thermal/power management or performance monitoring.
+                entry.eax = 0;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+            }
+            0xb => {
+                // Extended topology: one thread per core.
+                entry.edx = u32::from(vcpu_id);
+                match entry.index {
+                    0 => {
+                        entry.eax = 0;
+                        entry.ebx = 1;
+                        entry.ecx = (1 << 8) | entry.index;
+                    }
+                    1 => {
+                        entry.eax = core_bits;
+                        entry.ebx = u32::from(num_vcpus);
+                        entry.ecx = (2 << 8) | entry.index;
+                    }
+                    _ => {
+                        entry.eax = 0;
+                        entry.ebx = 0;
+                        entry.ecx = entry.index;
+                    }
+                }
+            }
+            KVM_SIGNATURE_LEAF => {
+                entry.eax = KVM_FEATURES_LEAF;
+                entry.ebx = KVM_SIGNATURE[0];
+                entry.ecx = KVM_SIGNATURE[1];
+                entry.edx = KVM_SIGNATURE[2];
+            }
+            _ => (),
+        }
+
+        if let Some(template) = template {
+            template.apply(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            ..Default::default()
+        }
+    }
+
+    fn find(entries: &[kvm_cpuid_entry2], function: u32, index: u32) -> kvm_cpuid_entry2 {
+        *entries
+            .iter()
+            .find(|e| e.function == function && e.index == index)
+            .unwrap()
+    }
+
+    #[test]
+    fn leaf1_apic_id() {
+        let mut entries = vec![kvm_cpuid_entry2 {
+            ebx: 0xff00_0800,
+            ecx: LEAF1_ECX_MONITOR,
+            ..entry(0x1, 0)
+        }];
+        filter_entries(&mut entries, 3, 4, None);
+        let leaf = find(&entries, 0x1, 0);
+        assert_eq!(0x0304_0800, leaf.ebx);
+        assert_eq!(LEAF1_ECX_HYPERVISOR, leaf.ecx);
+        assert_eq!(LEAF1_EDX_HTT, leaf.edx);
+    }
+
+    #[test]
+    fn kvm_signature_added() {
+        let mut entries = vec![];
+        filter_entries(&mut entries, 0, 1, None);
+        let leaf = find(&entries, KVM_SIGNATURE_LEAF, 0);
+        let mut sig = Vec::new();
+        for reg in [leaf.ebx, leaf.ecx, leaf.edx].iter() {
+            sig.extend_from_slice(&reg.to_le_bytes());
+        }
+        assert_eq!(b"KVMKVMKVM\0\0\0", &sig[..]);
+    }
+
+    #[test]
+    fn cache_topology() {
+        let mut entries = vec![
+            kvm_cpuid_entry2 {
+                eax: (1 << 5) | 0x1 | (7 << 14),
+                ..entry(0x4, 0)
+            },
+            kvm_cpuid_entry2 {
+                eax: (3 << 5) | 0x3,
+                ..entry(0x4, 3)
+            },
+        ];
+        filter_entries(&mut entries, 0, 2, None);
+        assert_eq!((1 << 26) | (1 << 5) | 0x1, find(&entries, 0x4, 0).eax);
+        assert_eq!(
+            (1 << 26) | (1 << 14) | (3 << 5) | 0x3,
+            find(&entries, 0x4, 3).eax
+        );
+    }
+
+    #[test]
+    fn extended_topology() {
+        let mut entries = vec![entry(0xb, 0), entry(0xb, 1), entry(0xb, 2)];
+        filter_entries(&mut entries, 2, 3, None);
+        let core = find(&entries, 0xb, 1);
+        assert_eq!(2, core.eax);
+        assert_eq!(3, core.ebx);
+        assert_eq!(2, core.edx);
+        assert_eq!(0, find(&entries, 0xb, 2).ebx);
+    }
+
+    #[test]
+    fn template_masks() {
+        let mut entries = vec![kvm_cpuid_entry2 {
+            flags: KVM_CPUID_FLAG_SIGNIFICANT_INDEX,
+            ebx: 0xffff_ffff,
+            ..entry(0x7, 0)
+        }];
+        let template = CpuidTemplate {
+            masks: vec![CpuidMask {
+                function: 0x7,
+                index: 0,
+                reg: CpuidReg::Ebx,
+                clear: 0xffff_0000,
+            }],
+        };
+        filter_entries(&mut entries, 0, 1, Some(&template));
+        assert_eq!(0x0000_ffff, find(&entries, 0x7, 0).ebx);
+    }
+}
@@ -0,0 +1,42 @@
+//! Results of running a vcpu, and hooks for intercepting exits.
+
+use super::run::KvmExit;
+
+/// The outcome of handling a single vcpu exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuExit {
+    /// The exit was handled and the vcpu may be run again.
+    Continue,
+    /// The vcpu executed hlt.
+    Halt,
+    /// The guest powered off.
+    Shutdown,
+    /// The guest requested a reset, or triple faulted.
+    Reset,
+    /// Any other system event, with its type and flags.
+    SystemEvent(u32, u64),
+    /// A debug exception or breakpoint was hit while guest debugging was
+    /// enabled (see `Vcpu::set_guest_debug`).
+    Debug(DebugExit),
+    /// The vcpu was kicked out of the guest, see `control`.
+    Interrupted,
+}
+
+/// Details of a debug exit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebugExit {
+    /// 1 for a debug exception (single step or hardware breakpoint), or 3 for
+    /// a software breakpoint.
+    pub exception: u32,
+    /// Linear address of the instruction.
+    pub pc: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+}
+
+/// Intercepts vcpu exits before they're handled by the vcpu.
+pub trait ExitHandler: Send {
+    /// Handle the exit, returning `None` to pass it on to the next handler,
+    /// or to the vcpu's default handling.
+    fn handle(&mut self, vcpu_id: u8, exit: &mut KvmExit) -> Option<VcpuExit>;
+}
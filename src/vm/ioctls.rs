@@ -0,0 +1,135 @@
+//! KVM ioctls that aren't exposed by kvm-ioctls.
+
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid2, kvm_debugregs, kvm_enable_cap, kvm_guest_debug, kvm_irqchip,
+    kvm_mp_state, kvm_msrs, kvm_pit_state2, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+};
+use std::io;
+use std::mem::size_of;
+use std::os::raw::{c_int, c_ulong};
+use std::os::unix::io::AsRawFd;
+
+const KVMIO: c_ulong = 0xae;
+
+const IOC_NONE: c_ulong = 0;
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+const fn ioc(dir: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    (dir << 30) | ((size as c_ulong) << 16) | (KVMIO << 8) | nr
+}
+
+pub const KVM_CHECK_EXTENSION: c_ulong = ioc(IOC_NONE, 0x03, 0);
+pub const KVM_SET_IDENTITY_MAP_ADDR: c_ulong = ioc(IOC_WRITE, 0x48, size_of::<u64>());
+pub const KVM_GET_IRQCHIP: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x62, size_of::<kvm_irqchip>());
+// Defined as _IOR in linux/kvm.h, even though it's used to set state.
+pub const KVM_SET_IRQCHIP: c_ulong = ioc(IOC_READ, 0x63, size_of::<kvm_irqchip>());
+pub const KVM_SET_CLOCK: c_ulong = ioc(IOC_WRITE, 0x7b, size_of::<kvm_clock_data>());
+pub const KVM_GET_CLOCK: c_ulong = ioc(IOC_READ, 0x7c, size_of::<kvm_clock_data>());
+pub const KVM_RUN: c_ulong = ioc(IOC_NONE, 0x80, 0);
+pub const KVM_GET_CPUID2: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x91, size_of::<kvm_cpuid2>());
+pub const KVM_GET_MSRS: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x88, size_of::<kvm_msrs>());
+pub const KVM_SET_MSRS: c_ulong = ioc(IOC_WRITE, 0x89, size_of::<kvm_msrs>());
+pub const KVM_GET_MP_STATE: c_ulong = ioc(IOC_READ, 0x98, size_of::<kvm_mp_state>());
+pub const KVM_SET_MP_STATE: c_ulong = ioc(IOC_WRITE, 0x99, size_of::<kvm_mp_state>());
+pub const KVM_SET_GUEST_DEBUG: c_ulong = ioc(IOC_WRITE, 0x9b, size_of::<kvm_guest_debug>());
+pub const KVM_GET_PIT2: c_ulong = ioc(IOC_READ, 0x9f, size_of::<kvm_pit_state2>());
+pub const KVM_SET_PIT2: c_ulong = ioc(IOC_WRITE, 0xa0, size_of::<kvm_pit_state2>());
+pub const KVM_GET_VCPU_EVENTS: c_ulong = ioc(IOC_READ, 0x9f, size_of::<kvm_vcpu_events>());
+pub const KVM_SET_VCPU_EVENTS: c_ulong = ioc(IOC_WRITE, 0xa0, size_of::<kvm_vcpu_events>());
+pub const KVM_GET_DEBUGREGS: c_ulong = ioc(IOC_READ, 0xa1, size_of::<kvm_debugregs>());
+pub const KVM_SET_DEBUGREGS: c_ulong = ioc(IOC_WRITE, 0xa2, size_of::<kvm_debugregs>());
+pub const KVM_ENABLE_CAP: c_ulong = ioc(IOC_WRITE, 0xa3, size_of::<kvm_enable_cap>());
+pub const KVM_GET_XSAVE: c_ulong = ioc(IOC_READ, 0xa4, size_of::<kvm_xsave>());
+pub const KVM_SET_XSAVE: c_ulong = ioc(IOC_WRITE, 0xa5, size_of::<kvm_xsave>());
+pub const KVM_GET_XCRS: c_ulong = ioc(IOC_READ, 0xa6, size_of::<kvm_xcrs>());
+pub const KVM_SET_XCRS: c_ulong = ioc(IOC_WRITE, 0xa7, size_of::<kvm_xcrs>());
+pub const KVM_X86_SET_MSR_FILTER: c_ulong = ioc(IOC_WRITE, 0xc6, size_of::<KvmMsrFilter>());
+
+pub const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+pub const KVM_MSR_EXIT_REASON_FILTER: u64 = 1 << 2;
+
+pub const KVM_MSR_FILTER_MAX_RANGES: usize = 16;
+pub const KVM_MSR_FILTER_READ: u32 = 1 << 0;
+pub const KVM_MSR_FILTER_WRITE: u32 = 1 << 1;
+
+/// struct kvm_msr_filter_range
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct KvmMsrFilterRange {
+    pub flags: u32,
+    pub nmsrs: u32,
+    pub base: u32,
+    pub bitmap: *const u8,
+}
+
+/// struct kvm_msr_filter
+#[repr(C)]
+pub struct KvmMsrFilter {
+    pub flags: u32,
+    pub ranges: [KvmMsrFilterRange; KVM_MSR_FILTER_MAX_RANGES],
+}
+
+/// Issue an ioctl that takes an integer argument.
+pub fn ioctl_with_val<F: AsRawFd>(fd: &F, req: c_ulong, arg: c_ulong) -> io::Result<c_int> {
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), req, arg) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Issue an ioctl that reads from `arg`.
+pub fn ioctl_with_ref<F: AsRawFd, T>(fd: &F, req: c_ulong, arg: &T) -> io::Result<c_int> {
+    ioctl_with_ptr(fd, req, arg as *const T)
+}
+
+/// Issue an ioctl that writes into `arg`.
+pub fn ioctl_with_mut_ref<F: AsRawFd, T>(fd: &F, req: c_ulong, arg: &mut T) -> io::Result<c_int> {
+    ioctl_with_ptr(fd, req, arg as *mut T)
+}
+
+/// Issue an ioctl with a raw pointer argument. The caller is responsible for
+/// the pointer being valid for whatever the kernel does with it.
+pub fn ioctl_with_ptr<F: AsRawFd, T>(fd: &F, req: c_ulong, arg: *const T) -> io::Result<c_int> {
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), req, arg) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_numbers() {
+        // Values from linux/kvm.h.
+        assert_eq!(0xae03, KVM_CHECK_EXTENSION);
+        assert_eq!(0x4008_ae48, KVM_SET_IDENTITY_MAP_ADDR);
+        assert_eq!(0xc208_ae62, KVM_GET_IRQCHIP);
+        assert_eq!(0x8208_ae63, KVM_SET_IRQCHIP);
+        assert_eq!(0x4030_ae7b, KVM_SET_CLOCK);
+        assert_eq!(0x8030_ae7c, KVM_GET_CLOCK);
+        assert_eq!(0xae80, KVM_RUN);
+        assert_eq!(0xc008_ae88, KVM_GET_MSRS);
+        assert_eq!(0x4008_ae89, KVM_SET_MSRS);
+        assert_eq!(0xc008_ae91, KVM_GET_CPUID2);
+        assert_eq!(0x8004_ae98, KVM_GET_MP_STATE);
+        assert_eq!(0x4004_ae99, KVM_SET_MP_STATE);
+        assert_eq!(0x4048_ae9b, KVM_SET_GUEST_DEBUG);
+        assert_eq!(0x8070_ae9f, KVM_GET_PIT2);
+        assert_eq!(0x4070_aea0, KVM_SET_PIT2);
+        assert_eq!(0x8040_ae9f, KVM_GET_VCPU_EVENTS);
+        assert_eq!(0x4040_aea0, KVM_SET_VCPU_EVENTS);
+        assert_eq!(0x8080_aea1, KVM_GET_DEBUGREGS);
+        assert_eq!(0x4080_aea2, KVM_SET_DEBUGREGS);
+        assert_eq!(0x4068_aea3, KVM_ENABLE_CAP);
+        assert_eq!(0x9000_aea4, KVM_GET_XSAVE);
+        assert_eq!(0x5000_aea5, KVM_SET_XSAVE);
+        assert_eq!(0x8188_aea6, KVM_GET_XCRS);
+        assert_eq!(0x4188_aea7, KVM_SET_XCRS);
+        assert_eq!(0x4188_aec6, KVM_X86_SET_MSR_FILTER);
+    }
+}
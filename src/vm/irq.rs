@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+
+/// Wrapper around an eventfd.
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Add `v` to the eventfd counter.
+    pub fn write(&self, v: u64) -> io::Result<()> {
+        (&self.file).write_all(&v.to_ne_bytes())
+    }
+
+    /// Read and reset the counter, blocking if it's zero.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        (&self.file).read_exact(&mut buf)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// An interrupt line into the in-kernel irqchip. Cheap to clone and safe to
+/// hand out to devices.
+#[derive(Clone)]
+pub struct IrqLine {
+    gsi: u32,
+    evt: Arc<EventFd>,
+}
+
+impl IrqLine {
+    pub(super) fn new(gsi: u32, evt: Arc<EventFd>) -> Self {
+        IrqLine { gsi, evt }
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    /// Pulse the interrupt.
+    pub fn trigger(&self) -> io::Result<()> {
+        self.evt.write(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eventfd_counter() {
+        let evt = EventFd::new().unwrap();
+        evt.write(2).unwrap();
+        evt.write(3).unwrap();
+        assert_eq!(5, evt.read().unwrap());
+    }
+}
@@ -0,0 +1,55 @@
+//! Local APIC register helpers.
+
+use kvm_bindings::kvm_lapic_state;
+
+pub const APIC_LVT0: usize = 0x350;
+pub const APIC_LVT1: usize = 0x360;
+
+const APIC_MODE_NMI: u32 = 0x4;
+const APIC_MODE_EXTINT: u32 = 0x7;
+
+fn get_reg(lapic: &kvm_lapic_state, offset: usize) -> u32 {
+    let mut bs = [0; 4];
+    for (b, r) in bs.iter_mut().zip(lapic.regs[offset..offset + 4].iter()) {
+        *b = *r as u8;
+    }
+    u32::from_le_bytes(bs)
+}
+
+fn set_reg(lapic: &mut kvm_lapic_state, offset: usize, val: u32) {
+    for (r, b) in lapic.regs[offset..offset + 4]
+        .iter_mut()
+        .zip(val.to_le_bytes().iter())
+    {
+        *r = *b as _;
+    }
+}
+
+/// Replace the delivery mode (bits 8-10) of a local vector table entry.
+fn set_delivery_mode(reg: u32, mode: u32) -> u32 {
+    (reg & !0x700) | (mode << 8)
+}
+
+/// Route LINT0 as external interrupts from the PIC and LINT1 as NMIs, the
+/// virtual wire setup expected by linux.
+pub fn set_lint(lapic: &mut kvm_lapic_state) {
+    let lvt0 = get_reg(lapic, APIC_LVT0);
+    set_reg(lapic, APIC_LVT0, set_delivery_mode(lvt0, APIC_MODE_EXTINT));
+    let lvt1 = get_reg(lapic, APIC_LVT1);
+    set_reg(lapic, APIC_LVT1, set_delivery_mode(lvt1, APIC_MODE_NMI));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint() {
+        let mut lapic = kvm_lapic_state { regs: [0; 1024] };
+        set_reg(&mut lapic, APIC_LVT0, 0x0001_0000 | (0x2 << 8));
+        set_lint(&mut lapic);
+        // Mask bit preserved, delivery mode replaced.
+        assert_eq!(0x0001_0700, get_reg(&lapic, APIC_LVT0));
+        assert_eq!(0x0000_0400, get_reg(&lapic, APIC_LVT1));
+    }
+}
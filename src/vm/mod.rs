@@ -2,14 +2,39 @@ extern crate kvm_bindings;
 extern crate kvm_ioctls;
 extern crate log;
 
-use crate::device::Bus;
+pub mod control;
+pub mod cpuid;
+pub mod exit;
+mod ioctls;
+pub mod irq;
+mod lapic;
+pub mod msr;
+pub mod pod;
+pub mod run;
+pub mod state;
+
+use crate::device::{Bus, Error as BusError};
 use crate::loader;
+use crate::memory::dirty::DirtyBitmap;
 use crate::memory::memorymap::MemoryMmap;
-use crate::memory::{Addressable, Memory, MemoryAddr, Region};
-use log::{debug, error};
+use crate::memory::{Addressable, Memory, MemoryAddr, MemoryRange, Region};
+use cpuid::CpuidTemplate;
+use exit::{ExitHandler, VcpuExit};
+use irq::{EventFd, IrqLine};
+use kvm_bindings::{
+    kvm_cpuid_entry2, kvm_guest_debug, kvm_guest_debug_arch, kvm_msr_entry, kvm_regs, kvm_sregs,
+};
+use log::{debug, error, warn};
+use msr::{MsrBuffer, MsrHandler};
+use run::{KvmExit, KvmRun};
+use state::{VcpuState, VmState};
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::io::Write;
+use std::os::raw::c_ulong;
+use std::os::unix::io::AsRawFd;
 use std::slice;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub enum Error {
@@ -20,12 +45,46 @@ pub enum Error {
     VcpuFailedRun(io::Error),
     VcpuRegs(io::Error),
     VcpuSregs(io::Error),
+    VcpuMpState(io::Error),
+    VcpuCpuid(io::Error),
+    VcpuFpu(io::Error),
+    VcpuLapic(io::Error),
+    VcpuXcrs(io::Error),
+    VcpuXsave(io::Error),
+    VcpuEvents(io::Error),
+    VcpuDebugRegs(io::Error),
+    GuestDebug(io::Error),
+    VcpuRunMap(io::Error),
+    VcpuMsrs(io::Error),
+    MsrNotSet(u32),
+    MsrFilter(io::Error),
+    MsrFilterUnsupported,
+    TooManyMsrFilters,
+    KickSignal(io::Error),
+    SpawnThread(io::Error),
+
+    TssAddr(io::Error),
+    IdentityMapAddr(io::Error),
+    Irqchip(io::Error),
+    Pit(io::Error),
+    Clock(io::Error),
+    Irqfd(io::Error),
     VcpuUnhandled,
     VcpuFailedIO,
+
+    InvalidSlot(u32),
+    ReadonlySlot(u32),
+    DirtyLog(io::Error),
+    ReadonlyMemUnsupported,
+    RegionOverlap,
+    NoFreeSlots,
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Large enough to hold every cpuid leaf reported by recent hosts.
+const MAX_CPUID_ENTRIES: usize = 256;
+
 pub struct KvmContext {
     kvm: kvm_ioctls::Kvm,
 }
@@ -35,32 +94,286 @@ impl KvmContext {
         let kvm = kvm_ioctls::Kvm::new().map_err(Error::Kvm)?;
         Ok(KvmContext { kvm: kvm })
     }
+
+    /// Get the cpuid leaves KVM is able to provide to a guest.
+    pub fn supported_cpuid(&self) -> Result<kvm_ioctls::CpuId> {
+        self.kvm
+            .get_supported_cpuid(MAX_CPUID_ENTRIES)
+            .map_err(Error::Kvm)
+    }
+}
+
+/// A region of guest memory registered with KVM.
+struct MemorySlot {
+    range: MemoryRange,
+    dirty: Arc<DirtyBitmap>,
+    readonly: bool,
 }
 
+/// Guest physical addresses of the pages KVM needs for real mode emulation on
+/// Intel. These sit just below the bios region at the top of 4G.
+const IDENTITY_MAP_ADDR: u64 = 0xfffb_c000;
+const TSS_ADDR: usize = 0xfffb_d000;
+
 pub struct Vm {
     fd: kvm_ioctls::VmFd,
+    max_slots: usize,
+    slots: Mutex<BTreeMap<u32, MemorySlot>>,
+    irqs: Mutex<HashMap<u32, Arc<EventFd>>>,
+    /// MSRs saved with the state of each vcpu, see `msr::saved_msr_indices`.
+    saved_msrs: Vec<u32>,
 }
 
 impl Vm {
+    /// Create a new vm with an in-kernel irqchip (PIC and IOAPIC) and PIT.
     pub fn new(kvm: &KvmContext) -> Result<Self> {
         let fd = kvm.kvm.create_vm().map_err(Error::Kvm)?;
-        Ok(Vm { fd: fd })
+
+        ioctls::ioctl_with_ref(&fd, ioctls::KVM_SET_IDENTITY_MAP_ADDR, &IDENTITY_MAP_ADDR)
+            .map_err(Error::IdentityMapAddr)?;
+        fd.set_tss_address(TSS_ADDR).map_err(Error::TssAddr)?;
+        fd.create_irq_chip().map_err(Error::Irqchip)?;
+        let pit_config = kvm_bindings::kvm_pit_config {
+            flags: kvm_bindings::KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        };
+        fd.create_pit2(pit_config).map_err(Error::Pit)?;
+        let kvm_msrs = kvm.kvm.get_msr_index_list().map_err(Error::Kvm)?;
+
+        Ok(Vm {
+            fd: fd,
+            max_slots: kvm.kvm.get_nr_memslots(),
+            slots: Mutex::new(BTreeMap::new()),
+            irqs: Mutex::new(HashMap::new()),
+            saved_msrs: msr::saved_msr_indices(&kvm_msrs),
+        })
+    }
+
+    /// Get a line for raising the given interrupt, registering an irqfd for
+    /// it if one doesn't already exist.
+    pub fn irq_line(&self, gsi: u32) -> Result<IrqLine> {
+        let mut irqs = self.irqs.lock().expect("failed to acquire mutex");
+        if let Some(evt) = irqs.get(&gsi) {
+            return Ok(IrqLine::new(gsi, evt.clone()));
+        }
+        let evt = Arc::new(EventFd::new().map_err(Error::Irqfd)?);
+        self.fd
+            .register_irqfd(evt.as_raw_fd(), gsi)
+            .map_err(Error::Irqfd)?;
+        irqs.insert(gsi, evt.clone());
+        Ok(IrqLine::new(gsi, evt))
+    }
+
+    /// Inject an edge triggered interrupt into the guest.
+    pub fn inject_irq(&self, gsi: u32) -> Result<()> {
+        self.irq_line(gsi)?.trigger().map_err(Error::Irqfd)
     }
 
-    pub fn init_memory(&mut self, mem: &MemoryMmap, kvm: &KvmContext) -> Result<()> {
+    /// Map the guest's main memory at address zero, returning the slot used.
+    /// Writes to it are tracked (see `dirty_pages`).
+    pub fn init_memory(&mut self, mem: &MemoryMmap, kvm: &KvmContext) -> Result<u32> {
+        self.register_region(MemoryAddr(0), mem, kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES)
+    }
+
+    /// Map memory into the guest as read-only, returning the slot used.
+    ///
+    /// Guest reads are satisfied directly from the memory, while guest writes
+    /// exit to userspace as mmio writes at the written address. A device
+    /// should be placed on the mmio bus over the same range to handle them
+    /// (see `device::rom::Rom`).
+    pub fn add_readonly_region(
+        &self,
+        guest_addr: MemoryAddr,
+        mem: &MemoryMmap,
+        kvm: &KvmContext,
+    ) -> Result<u32> {
+        if !kvm.kvm.check_extension(kvm_ioctls::Cap::ReadonlyMem) {
+            return Err(Error::ReadonlyMemUnsupported);
+        }
+        self.register_region(guest_addr, mem, kvm_bindings::KVM_MEM_READONLY)
+    }
+
+    /// Add memory to the guest, returning the slot used. This may be called
+    /// while vcpus are running.
+    ///
+    /// The memory must be kept alive for as long as the vm. The guest will
+    /// only make use of it once notified, see `device::memhp`.
+    pub fn hotplug_memory(&self, guest_addr: MemoryAddr, mem: &MemoryMmap) -> Result<u32> {
+        self.register_region(guest_addr, mem, kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES)
+    }
+
+    /// Remove memory added with `hotplug_memory` or `add_readonly_region`
+    /// from the guest, after which it may be unmapped.
+    pub fn remove_memory(&self, slot: u32) -> Result<()> {
+        let mut slots = self.slots.lock().expect("failed to acquire mutex");
+        if !slots.contains_key(&slot) {
+            return Err(Error::InvalidSlot(slot));
+        }
+        // A slot is deleted by setting its size to zero.
         let mem_region = kvm_bindings::kvm_userspace_memory_region {
-            slot: 0,
-            guest_phys_addr: 0 as u64,
-            memory_size: mem.len() as u64,
-            userspace_addr: mem.as_ptr() as u64,
-            flags: kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES,
+            slot,
+            guest_phys_addr: 0,
+            memory_size: 0,
+            userspace_addr: 0,
+            flags: 0,
         };
         self.fd
             .set_user_memory_region(mem_region)
             .map_err(Error::Kvm)?;
+        slots.remove(&slot);
+        Ok(())
+    }
+
+    /// Route guest accesses to the given MSRs to userspace, where they're
+    /// handled by the vcpu's `MsrHandler`. Accesses to all other MSRs are
+    /// handled by KVM as usual.
+    ///
+    /// Replaces any previously installed filter.
+    pub fn filter_msrs(&self, kvm: &KvmContext, indices: &[u32]) -> Result<()> {
+        let supported = ioctls::ioctl_with_val(
+            &kvm.kvm,
+            ioctls::KVM_CHECK_EXTENSION,
+            c_ulong::from(ioctls::KVM_CAP_X86_USER_SPACE_MSR),
+        )
+        .map_err(Error::MsrFilter)?;
+        if supported == 0 {
+            return Err(Error::MsrFilterUnsupported);
+        }
+        if indices.len() > ioctls::KVM_MSR_FILTER_MAX_RANGES {
+            return Err(Error::TooManyMsrFilters);
+        }
+
+        let cap = kvm_bindings::kvm_enable_cap {
+            cap: ioctls::KVM_CAP_X86_USER_SPACE_MSR,
+            args: [ioctls::KVM_MSR_EXIT_REASON_FILTER, 0, 0, 0],
+            ..Default::default()
+        };
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_ENABLE_CAP, &cap).map_err(Error::MsrFilter)?;
+
+        // One range per msr, with a cleared bit denying both reads and writes.
+        // Denied accesses exit to userspace because of the cap above.
+        let deny = 0u8;
+        let mut filter = ioctls::KvmMsrFilter {
+            flags: 0,
+            ranges: [ioctls::KvmMsrFilterRange {
+                flags: 0,
+                nmsrs: 0,
+                base: 0,
+                bitmap: std::ptr::null(),
+            }; ioctls::KVM_MSR_FILTER_MAX_RANGES],
+        };
+        for (range, index) in filter.ranges.iter_mut().zip(indices.iter()) {
+            range.flags = ioctls::KVM_MSR_FILTER_READ | ioctls::KVM_MSR_FILTER_WRITE;
+            range.nmsrs = 1;
+            range.base = *index;
+            range.bitmap = &deny;
+        }
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_X86_SET_MSR_FILTER, &filter)
+            .map_err(Error::MsrFilter)?;
+        Ok(())
+    }
+
+    /// Get the state of the in-kernel irqchip and PIT, and the guest clock.
+    /// Vcpus should be paused.
+    pub fn state(&self) -> Result<VmState> {
+        let get_chip = |chip_id| -> Result<kvm_bindings::kvm_irqchip> {
+            let mut chip = kvm_bindings::kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            };
+            ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_IRQCHIP, &mut chip)
+                .map_err(Error::Irqchip)?;
+            Ok(chip)
+        };
+        let mut pit = kvm_bindings::kvm_pit_state2::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_PIT2, &mut pit).map_err(Error::Pit)?;
+        let mut clock = kvm_bindings::kvm_clock_data::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_CLOCK, &mut clock)
+            .map_err(Error::Clock)?;
+        Ok(VmState {
+            pic_master: get_chip(kvm_bindings::KVM_IRQCHIP_PIC_MASTER)?,
+            pic_slave: get_chip(kvm_bindings::KVM_IRQCHIP_PIC_SLAVE)?,
+            ioapic: get_chip(kvm_bindings::KVM_IRQCHIP_IOAPIC)?,
+            pit,
+            clock,
+        })
+    }
+
+    /// Restore state saved with `state`.
+    pub fn set_state(&self, state: &VmState) -> Result<()> {
+        for chip in [&state.pic_master, &state.pic_slave, &state.ioapic].iter() {
+            ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_IRQCHIP, *chip)
+                .map_err(Error::Irqchip)?;
+        }
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_PIT2, &state.pit).map_err(Error::Pit)?;
+        // The flags returned by KVM_GET_CLOCK describe the host clock, and
+        // aren't valid input.
+        let clock = kvm_bindings::kvm_clock_data {
+            clock: state.clock.clock,
+            ..Default::default()
+        };
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_CLOCK, &clock).map_err(Error::Clock)?;
         Ok(())
     }
 
+    fn register_region(&self, guest_addr: MemoryAddr, mem: &MemoryMmap, flags: u32) -> Result<u32> {
+        let range = MemoryRange::new(guest_addr, mem.len());
+        let mut slots = self.slots.lock().expect("failed to acquire mutex");
+        if slots.values().any(|s| s.range.overlaps(&range)) {
+            return Err(Error::RegionOverlap);
+        }
+        let slot = match slots.keys().next_back() {
+            Some(slot) => slot + 1,
+            None => 0,
+        };
+        if slot as usize >= self.max_slots {
+            return Err(Error::NoFreeSlots);
+        }
+
+        let mem_region = kvm_bindings::kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: u64::from(guest_addr),
+            memory_size: mem.len() as u64,
+            userspace_addr: u64::from(mem.host_addr()),
+            flags,
+        };
+        self.fd
+            .set_user_memory_region(mem_region)
+            .map_err(Error::Kvm)?;
+        slots.insert(
+            slot,
+            MemorySlot {
+                range: range,
+                dirty: mem.dirty_bitmap(),
+                readonly: flags & kvm_bindings::KVM_MEM_READONLY != 0,
+            },
+        );
+        Ok(slot)
+    }
+
+    /// Get the pages written to in the given slot since the last call.
+    ///
+    /// The returned bitmap has one bit per page, and includes both writes
+    /// made by the guest (from the KVM dirty log) and writes made through
+    /// `Addressable::write` by device emulation. Both logs are cleared.
+    /// Read-only slots have no dirty log.
+    pub fn dirty_pages(&self, slot: u32) -> Result<Vec<u64>> {
+        let slots = self.slots.lock().expect("failed to acquire mutex");
+        let mem_slot = slots.get(&slot).ok_or(Error::InvalidSlot(slot))?;
+        if mem_slot.readonly {
+            return Err(Error::ReadonlySlot(slot));
+        }
+        let mut bitmap = self
+            .fd
+            .get_dirty_log(slot, mem_slot.range.len())
+            .map_err(Error::DirtyLog)?;
+        let user = mem_slot.dirty.take();
+        for (kvm_word, user_word) in bitmap.iter_mut().zip(user.iter()) {
+            *kvm_word |= user_word;
+        }
+        Ok(bitmap)
+    }
+
     pub fn new_test(kvm: &KvmContext) -> Result<Self> {
         let fd = kvm.kvm.create_vm().map_err(Error::Kvm)?;
         let mem_size = 0x4000;
@@ -152,48 +465,132 @@ impl Vm {
             }
         }
 
-        Ok(Vm { fd })
+        Ok(Vm {
+            fd,
+            max_slots: kvm.kvm.get_nr_memslots(),
+            slots: Mutex::new(BTreeMap::new()),
+            irqs: Mutex::new(HashMap::new()),
+            saved_msrs: msr::saved_msr_indices(&[]),
+        })
     }
 }
 
+/// Initial x87 control word and SSE control/status, matching their values
+/// after reset.
+const FPU_FCW: u16 = 0x37f;
+const FPU_MXCSR: u32 = 0x1f80;
+
+/// XCR0 enabling x87 and SSE state. Linux enables any further xsave features
+/// itself.
+const XCR0_FP_SSE: u64 = 0x3;
+
+/// Everything needed to set up a vcpu for booting a linux kernel.
+pub struct BootConfig<'a> {
+    pub num_vcpus: u8,
+    pub supported_cpuid: &'a kvm_ioctls::CpuId,
+    pub cpuid_template: Option<&'a CpuidTemplate>,
+    pub entry_point: MemoryAddr,
+    pub heap_end: MemoryAddr,
+    /// Boot parameters for the kernel, see `loader::configure_boot_params`.
+    pub boot_params: MemoryAddr,
+}
+
 /// A wrapper around a KVM provided virtual cpu.
 pub struct Vcpu {
+    id: u8,
     fd: kvm_ioctls::VcpuFd,
-    mmio_bus: Option<Bus>,
-    pio_bus: Option<Bus>,
+    run: KvmRun,
+    mmio_bus: Option<Arc<Bus>>,
+    pio_bus: Option<Arc<Bus>>,
+    msr_handler: Option<Box<dyn MsrHandler>>,
+    exit_handlers: Vec<Box<dyn ExitHandler>>,
+    saved_msrs: Vec<u32>,
 }
 
+// The vcpu holds raw pointers to its mmapped kvm_run structure, which is only
+// ever accessed by the thread currently owning the vcpu.
+unsafe impl Send for Vcpu {}
+
 impl Vcpu {
-    /// Create a new virtual cpu with the provided io busses for the given vm.
-    pub fn new(vm: &Vm) -> Result<Self> {
-        let vcpu_fd = vm.fd.create_vcpu(0).map_err(Error::VcpuFd)?;
+    /// Create a new virtual cpu with the given id for the vm. The vcpu with id
+    /// 0 is the bootstrap processor.
+    pub fn new(vm: &Vm, id: u8) -> Result<Self> {
+        let vcpu_fd = vm.fd.create_vcpu(id).map_err(Error::VcpuFd)?;
+        let run = KvmRun::mmap(&vcpu_fd, vm.fd.run_size()).map_err(Error::VcpuRunMap)?;
         Ok(Vcpu {
+            id: id,
             fd: vcpu_fd,
+            run: run,
             mmio_bus: None,
             pio_bus: None,
+            msr_handler: None,
+            exit_handlers: Vec::new(),
+            saved_msrs: vm.saved_msrs.clone(),
         })
     }
 
-    pub fn set_mmio_bus(&mut self, bus: Bus) {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn set_mmio_bus(&mut self, bus: Arc<Bus>) {
         self.mmio_bus = Some(bus);
     }
 
-    pub fn set_pio_bus(&mut self, bus: Bus) {
+    pub fn set_pio_bus(&mut self, bus: Arc<Bus>) {
         self.pio_bus = Some(bus);
     }
 
+    /// Set the handler for msr accesses filtered with `Vm::filter_msrs`.
+    /// Without a handler, filtered accesses inject a #GP into the guest.
+    pub fn set_msr_handler(&mut self, handler: Box<dyn MsrHandler>) {
+        self.msr_handler = Some(handler);
+    }
+
+    /// Direct kicks sent to the current thread at this vcpu. Call from the
+    /// thread running the vcpu before running it.
+    pub fn bind_to_thread(&self) {
+        self.run.set_current();
+    }
+
+    /// Add a handler to be consulted on every exit, in the order added,
+    /// before the vcpu's own handling.
+    pub fn add_exit_handler(&mut self, handler: Box<dyn ExitHandler>) {
+        self.exit_handlers.push(handler);
+    }
+
+    /// Fully configure the vcpu for boot. The bootstrap processor is set up to
+    /// start at the kernel entry point, while application processors wait
+    /// for a SIPI.
+    pub fn configure(&self, vm: &Vm, mem: &mut dyn Memory, config: &BootConfig) -> Result<()> {
+        self.configure_cpuid(
+            config.supported_cpuid,
+            config.num_vcpus,
+            config.cpuid_template,
+        )?;
+        self.configure_msrs()?;
+        self.configure_fpu()?;
+        self.configure_lapic()?;
+        self.configure_xcrs()?;
+        if self.id == 0 {
+            self.configure_kernel_load(vm, mem, config)
+        } else {
+            self.configure_ap()
+        }
+    }
+
     /// Sets the appropriate vcpu regs for booting into a linux kernel.
     pub fn configure_kernel_load(
         &self,
         vm: &Vm,
         mem: &mut Memory,
-        entry_point: MemoryAddr,
-        heap_end: MemoryAddr,
+        config: &BootConfig,
     ) -> Result<()> {
         let regs = kvm_bindings::kvm_regs {
-            rip: entry_point.0 as u64,
-            rsp: heap_end.0 as u64,
-            rbp: heap_end.0 as u64,
+            rip: u64::from(config.entry_point),
+            rsp: u64::from(config.heap_end),
+            rbp: u64::from(config.heap_end),
+            rsi: u64::from(config.boot_params),
             ..Default::default()
         };
         self.fd.set_regs(&regs).map_err(Error::VcpuRegs)?;
@@ -207,47 +604,339 @@ impl Vcpu {
         Ok(())
     }
 
-    /// Run until vcpu exit, handling io exits.
+    /// Set the cpuid leaves for this vcpu, derived from the leaves supported by
+    /// KVM. The optional template may be used to hide additional features.
+    pub fn configure_cpuid(
+        &self,
+        supported: &kvm_ioctls::CpuId,
+        num_vcpus: u8,
+        template: Option<&CpuidTemplate>,
+    ) -> Result<()> {
+        let mut entries = supported.clone().mut_entries_slice().to_vec();
+        cpuid::filter_entries(&mut entries, self.id, num_vcpus, template);
+        self.set_cpuid_entries(&entries)
+    }
+
+    pub fn set_cpuid_entries(&self, entries: &[kvm_cpuid_entry2]) -> Result<()> {
+        let cpuid = kvm_ioctls::CpuId::from_entries(entries);
+        self.fd.set_cpuid2(&cpuid).map_err(Error::VcpuCpuid)?;
+        Ok(())
+    }
+
+    /// Get the cpuid leaves currently set for the vcpu.
+    pub fn cpuid_entries(&self) -> Result<Vec<kvm_cpuid_entry2>> {
+        let mut cpuid = kvm_ioctls::CpuId::new(MAX_CPUID_ENTRIES);
+        ioctls::ioctl_with_ptr(&self.fd, ioctls::KVM_GET_CPUID2, cpuid.as_mut_ptr())
+            .map_err(Error::VcpuCpuid)?;
+        // KVM updates the number of entries.
+        let nent = unsafe { (*cpuid.as_ptr()).nent } as usize;
+        Ok(cpuid.mut_entries_slice()[..nent].to_vec())
+    }
+
+    /// Set the msrs linux expects on entry, see `msr::boot_msr_entries`.
+    pub fn configure_msrs(&self) -> Result<()> {
+        self.set_msrs(&msr::boot_msr_entries())
+    }
+
+    pub fn set_msrs(&self, entries: &[kvm_msr_entry]) -> Result<()> {
+        let msrs = MsrBuffer::new(entries);
+        let set = ioctls::ioctl_with_ptr(&self.fd, ioctls::KVM_SET_MSRS, msrs.as_ptr())
+            .map_err(Error::VcpuMsrs)? as usize;
+        // KVM stops at the first msr it fails to set.
+        if set < entries.len() {
+            return Err(Error::MsrNotSet(entries[set].index));
+        }
+        Ok(())
+    }
+
+    pub fn configure_fpu(&self) -> Result<()> {
+        let fpu = kvm_bindings::kvm_fpu {
+            fcw: FPU_FCW,
+            mxcsr: FPU_MXCSR,
+            ..Default::default()
+        };
+        self.fd.set_fpu(&fpu).map_err(Error::VcpuFpu)?;
+        Ok(())
+    }
+
+    /// Set up the local APIC's LINT pins. Requires the in-kernel irqchip.
+    pub fn configure_lapic(&self) -> Result<()> {
+        let mut state = self.fd.get_lapic().map_err(Error::VcpuLapic)?;
+        lapic::set_lint(&mut state);
+        self.fd.set_lapic(&state).map_err(Error::VcpuLapic)?;
+        Ok(())
+    }
+
+    /// Enable x87 and SSE state in XCR0. Must be called after setting cpuid,
+    /// which determines the xsave features available to the guest.
+    pub fn configure_xcrs(&self) -> Result<()> {
+        let mut entries = [kvm_bindings::kvm_xcr::default(); 16];
+        entries[0] = kvm_bindings::kvm_xcr {
+            xcr: 0,
+            value: XCR0_FP_SSE,
+            ..Default::default()
+        };
+        let xcrs = kvm_bindings::kvm_xcrs {
+            nr_xcrs: 1,
+            xcrs: entries,
+            ..Default::default()
+        };
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_XCRS, &xcrs).map_err(Error::VcpuXcrs)?;
+        Ok(())
+    }
+
+    /// Get the current values of the given msrs.
+    pub fn get_msrs(&self, indices: &[u32]) -> Result<Vec<kvm_msr_entry>> {
+        let entries: Vec<_> = indices
+            .iter()
+            .map(|index| kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs = MsrBuffer::new(&entries);
+        let read = ioctls::ioctl_with_ptr(&self.fd, ioctls::KVM_GET_MSRS, msrs.as_mut_ptr())
+            .map_err(Error::VcpuMsrs)? as usize;
+        if read < indices.len() {
+            return Err(Error::MsrNotSet(indices[read]));
+        }
+        Ok(msrs.entries().to_vec())
+    }
+
+    /// Get the current values of those of the given msrs that the vcpu
+    /// supports, skipping the rest.
+    pub fn get_supported_msrs(&self, indices: &[u32]) -> Result<Vec<kvm_msr_entry>> {
+        let mut entries: Vec<_> = indices
+            .iter()
+            .map(|index| kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+        let mut start = 0;
+        while start < entries.len() {
+            let mut msrs = MsrBuffer::new(&entries[start..]);
+            let read = ioctls::ioctl_with_ptr(&self.fd, ioctls::KVM_GET_MSRS, msrs.as_mut_ptr())
+                .map_err(Error::VcpuMsrs)? as usize;
+            entries[start..start + read].copy_from_slice(&msrs.entries()[..read]);
+            start += read;
+            // KVM stops at the first msr it can't read.
+            if start < entries.len() {
+                debug!(
+                    "vcpu {} doesn't support msr {:#x}",
+                    self.id, entries[start].index
+                );
+                entries.remove(start);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Get the full state of the vcpu. The vcpu must not be running.
+    pub fn state(&self) -> Result<VcpuState> {
+        let mut mp_state = kvm_bindings::kvm_mp_state::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_MP_STATE, &mut mp_state)
+            .map_err(Error::VcpuMpState)?;
+        let regs = self.fd.get_regs().map_err(Error::VcpuRegs)?;
+        let sregs = self.fd.get_sregs().map_err(Error::VcpuSregs)?;
+        let fpu = self.fd.get_fpu().map_err(Error::VcpuFpu)?;
+        let mut xsave = kvm_bindings::kvm_xsave::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_XSAVE, &mut xsave)
+            .map_err(Error::VcpuXsave)?;
+        let mut xcrs = kvm_bindings::kvm_xcrs::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_XCRS, &mut xcrs)
+            .map_err(Error::VcpuXcrs)?;
+        let mut debugregs = kvm_bindings::kvm_debugregs::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_DEBUGREGS, &mut debugregs)
+            .map_err(Error::VcpuDebugRegs)?;
+        let lapic = self.fd.get_lapic().map_err(Error::VcpuLapic)?;
+        let msrs = self.get_supported_msrs(&self.saved_msrs)?;
+        // Events last, so anything pending from the above is included.
+        let mut events = kvm_bindings::kvm_vcpu_events::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_VCPU_EVENTS, &mut events)
+            .map_err(Error::VcpuEvents)?;
+        Ok(VcpuState {
+            regs,
+            sregs,
+            fpu,
+            msrs,
+            lapic,
+            xsave,
+            xcrs,
+            events,
+            debugregs,
+            mp_state,
+        })
+    }
+
+    /// Restore state from `state`. Cpuid should be configured first, as it
+    /// determines which features (e.g. xsave components) may be restored.
+    pub fn set_state(&self, state: &VcpuState) -> Result<()> {
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_MP_STATE, &state.mp_state)
+            .map_err(Error::VcpuMpState)?;
+        self.fd.set_regs(&state.regs).map_err(Error::VcpuRegs)?;
+        self.fd.set_sregs(&state.sregs).map_err(Error::VcpuSregs)?;
+        // The fpu state is part of the xsave area, which takes precedence.
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_XSAVE, &state.xsave)
+            .map_err(Error::VcpuXsave)?;
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_XCRS, &state.xcrs)
+            .map_err(Error::VcpuXcrs)?;
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_DEBUGREGS, &state.debugregs)
+            .map_err(Error::VcpuDebugRegs)?;
+        self.fd.set_lapic(&state.lapic).map_err(Error::VcpuLapic)?;
+        self.set_msrs(&state.msrs)?;
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_VCPU_EVENTS, &state.events)
+            .map_err(Error::VcpuEvents)?;
+        Ok(())
+    }
+
+    pub fn regs(&self) -> Result<kvm_regs> {
+        self.fd.get_regs().map_err(Error::VcpuRegs)
+    }
+
+    pub fn set_regs(&self, regs: &kvm_regs) -> Result<()> {
+        self.fd.set_regs(regs).map_err(Error::VcpuRegs)
+    }
+
+    pub fn sregs(&self) -> Result<kvm_sregs> {
+        self.fd.get_sregs().map_err(Error::VcpuSregs)
+    }
+
+    pub fn set_sregs(&self, sregs: &kvm_sregs) -> Result<()> {
+        self.fd.set_sregs(sregs).map_err(Error::VcpuSregs)
+    }
+
+    /// Enable or disable guest debugging. `control` is a combination of the
+    /// `KVM_GUESTDBG_*` flags, and `debugreg` holds the values of DR0-DR7
+    /// used for hardware breakpoints. While enabled, breakpoints and single
+    /// steps make `run` return `VcpuExit::Debug`.
+    pub fn set_guest_debug(&self, control: u32, debugreg: [u64; 8]) -> Result<()> {
+        let debug = kvm_guest_debug {
+            control,
+            pad: 0,
+            arch: kvm_guest_debug_arch { debugreg },
+        };
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_GUEST_DEBUG, &debug)
+            .map_err(Error::GuestDebug)?;
+        Ok(())
+    }
+
+    /// Deliver exception `vector`, which mustn't take an error code, to the
+    /// guest the next time the vcpu runs. Used to hand back exceptions that
+    /// guest debugging intercepted but that the guest expects to handle.
+    pub fn inject_exception(&self, vector: u8) -> Result<()> {
+        let mut events = kvm_bindings::kvm_vcpu_events::default();
+        ioctls::ioctl_with_mut_ref(&self.fd, ioctls::KVM_GET_VCPU_EVENTS, &mut events)
+            .map_err(Error::VcpuEvents)?;
+        events.exception.injected = 1;
+        events.exception.nr = vector;
+        events.exception.has_error_code = 0;
+        events.exception.error_code = 0;
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_VCPU_EVENTS, &events)
+            .map_err(Error::VcpuEvents)?;
+        Ok(())
+    }
+
+    /// Put an application processor into the wait-for-SIPI state. The
+    /// bootstrap processor will start it once the guest brings up its cpus.
     ///
-    /// Any other vcpu exit is treated as unhandled.
-    pub fn run(&self) -> Result<()> {
-        match self.fd.run().map_err(Error::VcpuFailedRun)? {
-            kvm_ioctls::VcpuExit::IoIn(addr, data) => {
-                debug!("vcpu exit: io in, addr: {}, data: {:?}", addr, data);
-                if let Some(pio_bus) = &self.pio_bus {
-                    let _ = pio_bus.read(MemoryAddr(addr as usize), data);
-                }
-                Ok(())
+    /// Requires the in-kernel local APIC.
+    pub fn configure_ap(&self) -> Result<()> {
+        let mp_state = kvm_bindings::kvm_mp_state {
+            mp_state: kvm_bindings::KVM_MP_STATE_INIT_RECEIVED,
+        };
+        ioctls::ioctl_with_ref(&self.fd, ioctls::KVM_SET_MP_STATE, &mp_state)
+            .map_err(Error::VcpuMpState)?;
+        Ok(())
+    }
+
+    /// Let KVM finish the io or mmio access the vcpu last exited for, without
+    /// entering the guest. The access is only completed by the next KVM_RUN,
+    /// so this must be done before saving the vcpu's state.
+    pub fn complete_io(&mut self) -> Result<()> {
+        self.run.set_immediate_exit();
+        // An access split across several exits takes more than one run.
+        while self.run()? != VcpuExit::Interrupted {}
+        Ok(())
+    }
+
+    /// Run until the next vcpu exit.
+    ///
+    /// Exit handlers get the first chance at handling the exit. Otherwise io
+    /// and msr exits are handled here, and exits needing attention from the
+    /// caller are returned. Any other exit is treated as unhandled.
+    pub fn run(&mut self) -> Result<VcpuExit> {
+        if let Err(e) = ioctls::ioctl_with_val(&self.fd, ioctls::KVM_RUN, 0) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                self.run.clear_immediate_exit();
+                return Ok(VcpuExit::Interrupted);
             }
-            kvm_ioctls::VcpuExit::IoOut(addr, data) => {
-                debug!("vcpu exit: io out, addr: {}", addr);
-                if let Some(pio_bus) = &self.pio_bus {
-                    let _ = pio_bus.write(MemoryAddr(addr as usize), data);
-                }
-                Ok(())
+            return Err(Error::VcpuFailedRun(e));
+        }
+        let mut exit = self.run.exit();
+        for handler in self.exit_handlers.iter_mut() {
+            if let Some(result) = handler.handle(self.id, &mut exit) {
+                return Ok(result);
             }
-            kvm_ioctls::VcpuExit::MmioRead(addr, data) => {
+        }
+        match exit {
+            KvmExit::IoIn(addr, data) => {
+                debug!("vcpu exit: io in, addr: {}", addr);
+                pio_read(self.pio_bus.as_ref(), addr, data);
+                Ok(VcpuExit::Continue)
+            }
+            KvmExit::IoOut(addr, data) => {
+                debug!("vcpu exit: io out, addr: {}, data: {:?}", addr, data);
+                pio_write(self.pio_bus.as_ref(), addr, data);
+                Ok(VcpuExit::Continue)
+            }
+            KvmExit::MmioRead(addr, data) => {
                 debug!("vcpu exit: mmio read, addr: {}", addr);
                 if let Some(mmio_bus) = &self.mmio_bus {
                     mmio_bus
-                        .read(MemoryAddr(addr as usize), data)
+                        .read(MemoryAddr::from(addr), data)
                         .map_err(|_| Error::VcpuFailedIO)?;
                 }
-                Ok(())
+                Ok(VcpuExit::Continue)
             }
-            kvm_ioctls::VcpuExit::MmioWrite(addr, data) => {
+            KvmExit::MmioWrite(addr, data) => {
                 debug!("vcpu exit: mmio write, addr: {}", addr);
                 if let Some(mmio_bus) = &self.mmio_bus {
                     mmio_bus
-                        .write(MemoryAddr(addr as usize), data)
+                        .write(MemoryAddr::from(addr), data)
                         .map_err(|_| Error::VcpuFailedIO)?;
                 }
-                Ok(())
+                Ok(VcpuExit::Continue)
             }
-            kvm_ioctls::VcpuExit::Hlt => {
-                error!("vcpu halt");
-                Err(Error::VcpuUnhandled)
+            KvmExit::RdMsr(msr) => {
+                debug!("vcpu exit: rdmsr, {:?}", msr);
+                match self.msr_handler.as_mut().and_then(|h| h.read(msr.index)) {
+                    Some(data) => msr.data = data,
+                    None => msr.error = 1,
+                }
+                Ok(VcpuExit::Continue)
             }
+            KvmExit::WrMsr(msr) => {
+                debug!("vcpu exit: wrmsr, {:?}", msr);
+                let ok = match self.msr_handler.as_mut() {
+                    Some(h) => h.write(msr.index, msr.data),
+                    None => false,
+                };
+                if !ok {
+                    msr.error = 1;
+                }
+                Ok(VcpuExit::Continue)
+            }
+            KvmExit::Hlt => Ok(VcpuExit::Halt),
+            // KVM reports a triple fault as a shutdown, which resets a real
+            // machine.
+            KvmExit::Shutdown => Ok(VcpuExit::Reset),
+            KvmExit::Debug(debug) => Ok(VcpuExit::Debug(debug)),
+            KvmExit::SystemEvent(kvm_bindings::KVM_SYSTEM_EVENT_SHUTDOWN, _) => {
+                Ok(VcpuExit::Shutdown)
+            }
+            KvmExit::SystemEvent(kvm_bindings::KVM_SYSTEM_EVENT_RESET, _) => Ok(VcpuExit::Reset),
+            KvmExit::SystemEvent(event, flags) => Ok(VcpuExit::SystemEvent(event, flags)),
             e => {
                 error!("unhandled vcpu exit: {:?}", e);
                 Err(Error::VcpuUnhandled)
@@ -256,6 +945,60 @@ impl Vcpu {
     }
 }
 
+/// Value read by the guest from io ports without a device, as if nothing
+/// drove the bus.
+const PIO_UNMAPPED_FILL: u8 = 0xff;
+
+/// Read from an io port. Reads from ports without a working device see all
+/// ones.
+fn pio_read(bus: Option<&Arc<Bus>>, port: u16, data: &mut [u8]) {
+    let addr = MemoryAddr::from(u64::from(port));
+    let err = match bus {
+        Some(bus) => match bus.read(addr, data) {
+            Ok(()) => return,
+            Err(BusError::MissingDevice) => {
+                warn_unmapped_port(bus.record_unmapped(addr), "read", port);
+                None
+            }
+            Err(e) => Some(e),
+        },
+        None => None,
+    };
+    if let Some(e) = err {
+        warn!("io port {:#x} read failed: {:?}", port, e);
+    }
+    for b in data.iter_mut() {
+        *b = PIO_UNMAPPED_FILL;
+    }
+}
+
+/// Write to an io port. Writes to ports without a working device are dropped.
+fn pio_write(bus: Option<&Arc<Bus>>, port: u16, data: &[u8]) {
+    let bus = match bus {
+        Some(bus) => bus,
+        None => return,
+    };
+    let addr = MemoryAddr::from(u64::from(port));
+    match bus.write(addr, data) {
+        Ok(()) => (),
+        Err(BusError::MissingDevice) => {
+            warn_unmapped_port(bus.record_unmapped(addr), "write", port)
+        }
+        Err(e) => warn!("io port {:#x} write failed: {:?}", port, e),
+    }
+}
+
+/// Warn about accesses to unmapped ports, backing off exponentially so a
+/// guest polling a port doesn't flood the log.
+fn warn_unmapped_port(count: u64, access: &str, port: u16) {
+    if count.is_power_of_two() {
+        warn!(
+            "{} of unmapped io port {:#x} ({} accesses)",
+            access, port, count
+        );
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -270,8 +1013,441 @@ mod test {
     #[test]
     fn new_vcpu() {
         let vm = new_test_vm();
-        let pio_bus = Bus::new();
-        let mmio_bus = Bus::new();
-        Vcpu::new(&vm, mmio_bus, pio_bus).unwrap();
+        let vcpu = Vcpu::new(&vm, 1).unwrap();
+        assert_eq!(1, vcpu.id());
+    }
+
+    #[test]
+    fn configure_cpuid() {
+        let kvm = KvmContext::new().unwrap();
+        let vm = Vm::new(&kvm).unwrap();
+        let supported = kvm.supported_cpuid().unwrap();
+        let vcpu = Vcpu::new(&vm, 0).unwrap();
+        vcpu.configure_cpuid(&supported, 1, Some(&CpuidTemplate::baseline()))
+            .unwrap();
+    }
+
+    #[test]
+    fn configure_msrs() {
+        let kvm = KvmContext::new().unwrap();
+        let vm = Vm::new(&kvm).unwrap();
+        let vcpu = Vcpu::new(&vm, 0).unwrap();
+        vcpu.configure_cpuid(&kvm.supported_cpuid().unwrap(), 1, None)
+            .unwrap();
+        vcpu.configure_msrs().unwrap();
+        let msrs = vcpu
+            .get_msrs(&[msr::MSR_IA32_MISC_ENABLE, msr::MSR_LSTAR])
+            .unwrap();
+        assert_eq!(1, msrs[0].data & 1);
+        assert_eq!(0, msrs[1].data);
+    }
+
+    /// Create a vcpu that will execute the given code in real mode at 0x1000.
+    pub(super) fn real_mode_vcpu(kvm: &KvmContext, vm: &mut Vm, code: &[u8]) -> (MemoryMmap, Vcpu) {
+        let mut mem = MemoryMmap::new(0x10000).unwrap();
+        mem.write(code, MemoryAddr(0x1000)).unwrap();
+        vm.init_memory(&mem, kvm).unwrap();
+
+        let vcpu = Vcpu::new(vm, 0).unwrap();
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+        let regs = kvm_bindings::kvm_regs {
+            rip: 0x1000,
+            rflags: 2,
+            ..Default::default()
+        };
+        vcpu.fd.set_regs(&regs).unwrap();
+        (mem, vcpu)
+    }
+
+    /// Switch a vcpu from `real_mode_vcpu` to flat 32-bit protected mode,
+    /// without paging or an IDT.
+    pub(super) fn flat_protected_mode(vcpu: &Vcpu) {
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        let flat = kvm_bindings::kvm_segment {
+            base: 0,
+            limit: 0xffff_ffff,
+            selector: 0x8,
+            type_: 0xb,
+            present: 1,
+            dpl: 0,
+            db: 1,
+            s: 1,
+            l: 0,
+            g: 1,
+            ..Default::default()
+        };
+        sregs.cs = flat;
+        sregs.ds = kvm_bindings::kvm_segment {
+            selector: 0x10,
+            type_: 0x3,
+            ..flat
+        };
+        sregs.ss = sregs.ds;
+        sregs.idt.limit = 0;
+        sregs.cr0 |= 1;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+    }
+
+    #[test]
+    fn filtered_msr_exit() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [
+            0x66, 0xb9, 0x81, 0x00, 0x00, 0xc0, /* mov $0xc0000081, %ecx */
+            0x0f, 0x32, /* rdmsr */
+            0xe6, 0x10, /* out %al, $0x10 */
+        ];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+        vm.filter_msrs(&kvm, &[msr::MSR_STAR]).unwrap();
+
+        let port = Arc::new(Mutex::new(MemoryMmap::new(4096).unwrap()));
+        let pio_bus = Arc::new(Bus::new());
+        pio_bus
+            .insert(MemoryRange::new(MemoryAddr(0x10), 1), port.clone())
+            .unwrap();
+        vcpu.set_pio_bus(pio_bus);
+        vcpu.set_msr_handler(Box::new(msr::MsrStore::new(&[(msr::MSR_STAR, 0x42)])));
+
+        // One exit for the rdmsr, one for the out.
+        assert_eq!(VcpuExit::Continue, vcpu.run().unwrap());
+        assert_eq!(VcpuExit::Continue, vcpu.run().unwrap());
+        let buf = &mut [0; 1];
+        port.lock().unwrap().read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!(0x42, buf[0]);
+    }
+
+    #[test]
+    fn unmapped_port_reads_ones() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [
+            0xe4, 0x90, /* in $0x90, %al */
+            0xe6, 0x10, /* out %al, $0x10 */
+        ];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+
+        let port = Arc::new(Mutex::new(MemoryMmap::new(4096).unwrap()));
+        let pio_bus = Arc::new(Bus::new());
+        pio_bus
+            .insert(MemoryRange::new(MemoryAddr(0x10), 1), port.clone())
+            .unwrap();
+        vcpu.set_pio_bus(pio_bus.clone());
+
+        vcpu.run().unwrap();
+        vcpu.run().unwrap();
+        let buf = &mut [0; 1];
+        port.lock().unwrap().read(buf, MemoryAddr(0)).unwrap();
+        assert_eq!(PIO_UNMAPPED_FILL, buf[0]);
+        assert_eq!(Some(&1), pio_bus.unmapped_accesses().get(&MemoryAddr(0x90)));
+    }
+
+    #[test]
+    fn complete_io() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [0xe4, 0x10 /* in $0x10, %al */];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+
+        let mut port = MemoryMmap::new(4096).unwrap();
+        port.write(&[0x42], MemoryAddr(0)).unwrap();
+        let pio_bus = Arc::new(Bus::new());
+        pio_bus
+            .insert(
+                MemoryRange::new(MemoryAddr(0x10), 1),
+                Arc::new(Mutex::new(port)),
+            )
+            .unwrap();
+        vcpu.set_pio_bus(pio_bus);
+
+        // The value read isn't in the registers until KVM completes the in.
+        assert_eq!(VcpuExit::Continue, vcpu.run().unwrap());
+        assert_eq!(0x1000, vcpu.regs().unwrap().rip);
+        vcpu.complete_io().unwrap();
+        let regs = vcpu.regs().unwrap();
+        assert_eq!(0x42, regs.rax & 0xff);
+        assert_eq!(0x1002, regs.rip);
+    }
+
+    /// Shuts down the vcpu on any write to its port.
+    pub(super) struct PowerOff(pub u16);
+
+    impl ExitHandler for PowerOff {
+        fn handle(&mut self, _vcpu_id: u8, exit: &mut run::KvmExit) -> Option<VcpuExit> {
+            match exit {
+                run::KvmExit::IoOut(port, _) if *port == self.0 => Some(VcpuExit::Shutdown),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn exit_handler_intercepts() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [
+            0xe6, 0x10, /* out %al, $0x10 */
+            0xe6, 0x30, /* out %al, $0x30 */
+        ];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+        vcpu.add_exit_handler(Box::new(PowerOff(0x30)));
+
+        assert_eq!(VcpuExit::Continue, vcpu.run().unwrap());
+        assert_eq!(VcpuExit::Shutdown, vcpu.run().unwrap());
+    }
+
+    #[test]
+    fn guest_debug_exits() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mut mem = MemoryMmap::new(1 << 21).unwrap();
+        let code = [0x90, 0x90, 0x90, 0x90 /* nop */];
+        mem.write(&code, MemoryAddr(0x10_0000)).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+        let supported = kvm.supported_cpuid().unwrap();
+        let config = BootConfig {
+            num_vcpus: 1,
+            supported_cpuid: &supported,
+            cpuid_template: None,
+            entry_point: MemoryAddr(0x10_0000),
+            heap_end: MemoryAddr(0x9_0000),
+            boot_params: MemoryAddr(0),
+        };
+        let mut vcpu = Vcpu::new(&vm, 0).unwrap();
+        vcpu.configure(&vm, &mut mem, &config).unwrap();
+
+        let control = kvm_bindings::KVM_GUESTDBG_ENABLE | kvm_bindings::KVM_GUESTDBG_SINGLESTEP;
+        vcpu.set_guest_debug(control, [0; 8]).unwrap();
+        match vcpu.run().unwrap() {
+            VcpuExit::Debug(debug) => assert_eq!((1, 0x10_0001), (debug.exception, debug.pc)),
+            exit => panic!("expected a single step, got {:?}", exit),
+        }
+        // An execution breakpoint in DR0.
+        let control = kvm_bindings::KVM_GUESTDBG_ENABLE | kvm_bindings::KVM_GUESTDBG_USE_HW_BP;
+        let debugreg = [0x10_0003, 0, 0, 0, 0, 0, 0, 0x401];
+        vcpu.set_guest_debug(control, debugreg).unwrap();
+        match vcpu.run().unwrap() {
+            VcpuExit::Debug(debug) => {
+                assert_eq!((1, 0x10_0003), (debug.exception, debug.pc));
+                assert_eq!(1, debug.dr6 & 0xf);
+            }
+            exit => panic!("expected a breakpoint, got {:?}", exit),
+        }
+        assert_eq!(0x10_0003, vcpu.regs().unwrap().rip);
+    }
+
+    #[test]
+    fn triple_fault_resets() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [0x0f, 0x0b /* ud2 */];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+        // With an empty IDT, the #UD can't be delivered.
+        flat_protected_mode(&vcpu);
+
+        assert_eq!(VcpuExit::Reset, vcpu.run().unwrap());
+    }
+
+    #[test]
+    fn guest_poweroff() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let code = [
+            0xba, 0x00, 0x06, /* mov $0x600, %dx */
+            0xb0, 0x34, /* mov $(5 << 2 | 1 << 5), %al */
+            0xee, /* out %al, (%dx) */
+        ];
+        let (_mem, mut vcpu) = real_mode_vcpu(&kvm, &mut vm, &code);
+        vcpu.add_exit_handler(Box::new(crate::device::sleep::SleepControl::new()));
+
+        assert_eq!(VcpuExit::Shutdown, vcpu.run().unwrap());
+    }
+
+    #[test]
+    fn configure_boot() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mut mem = MemoryMmap::new(1 << 20).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+        let supported = kvm.supported_cpuid().unwrap();
+        let config = BootConfig {
+            num_vcpus: 2,
+            supported_cpuid: &supported,
+            cpuid_template: None,
+            entry_point: MemoryAddr(0x10_0000),
+            heap_end: MemoryAddr(0x9_0000),
+            boot_params: MemoryAddr(0),
+        };
+        let bsp = Vcpu::new(&vm, 0).unwrap();
+        bsp.configure(&vm, &mut mem, &config).unwrap();
+        let ap = Vcpu::new(&vm, 1).unwrap();
+        ap.configure(&vm, &mut mem, &config).unwrap();
+
+        assert_eq!(FPU_FCW, bsp.fd.get_fpu().unwrap().fcw);
+        let mut xcrs = kvm_bindings::kvm_xcrs::default();
+        ioctls::ioctl_with_mut_ref(&bsp.fd, ioctls::KVM_GET_XCRS, &mut xcrs).unwrap();
+        assert_eq!(XCR0_FP_SSE, xcrs.xcrs[0].value);
+        let state = ap.fd.get_lapic().unwrap();
+        assert_eq!(0x700, lapic_reg(&state, lapic::APIC_LVT0) & 0x700);
+        assert_eq!(0x400, lapic_reg(&state, lapic::APIC_LVT1) & 0x700);
+    }
+
+    fn lapic_reg(state: &kvm_bindings::kvm_lapic_state, offset: usize) -> u32 {
+        let regs = &state.regs[offset..offset + 4];
+        u32::from_le_bytes([regs[0] as u8, regs[1] as u8, regs[2] as u8, regs[3] as u8])
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let kvm = KvmContext::new().unwrap();
+        let supported = kvm.supported_cpuid().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mut mem = MemoryMmap::new(1 << 20).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+        let config = BootConfig {
+            num_vcpus: 1,
+            supported_cpuid: &supported,
+            cpuid_template: None,
+            entry_point: MemoryAddr(0x10_0000),
+            heap_end: MemoryAddr(0x9_0000),
+            boot_params: MemoryAddr(0),
+        };
+        let vcpu = Vcpu::new(&vm, 0).unwrap();
+        vcpu.configure(&vm, &mut mem, &config).unwrap();
+        let state = vcpu.state().unwrap();
+        // Paravirtual and MTRR msrs are saved along with the boot msrs.
+        for index in [0x4b56_4d01, msr::MSR_MTRR_PHYS_BASE0, msr::MSR_LSTAR].iter() {
+            assert!(state.msrs.iter().any(|e| e.index == *index));
+        }
+        let mut buf = Vec::new();
+        state.serialize(&mut buf).unwrap();
+
+        let other_vm = Vm::new(&kvm).unwrap();
+        let other = Vcpu::new(&other_vm, 0).unwrap();
+        other.configure_cpuid(&supported, 1, None).unwrap();
+        other
+            .set_state(&VcpuState::deserialize(&mut &buf[..]).unwrap())
+            .unwrap();
+        let restored = other.state().unwrap();
+        assert_eq!(0x10_0000, restored.regs.rip);
+        assert_eq!(state.sregs.cr0, restored.sregs.cr0);
+        assert_eq!(state.sregs.gdt, restored.sregs.gdt);
+        assert_eq!(state.xcrs.xcrs[0].value, restored.xcrs.xcrs[0].value);
+        assert_eq!(state.mp_state, restored.mp_state);
+    }
+
+    #[test]
+    fn cpuid_entries() {
+        let kvm = KvmContext::new().unwrap();
+        let vm = Vm::new(&kvm).unwrap();
+        let vcpu = Vcpu::new(&vm, 0).unwrap();
+        vcpu.configure_cpuid(&kvm.supported_cpuid().unwrap(), 1, None)
+            .unwrap();
+        let entries = vcpu.cpuid_entries().unwrap();
+        assert!(entries.iter().any(|e| e.function == 0x4000_0000));
+    }
+
+    #[test]
+    fn vm_state_roundtrip() {
+        let kvm = KvmContext::new().unwrap();
+        let vm = Vm::new(&kvm).unwrap();
+        vm.inject_irq(4).unwrap();
+        let state = vm.state().unwrap();
+        let mut buf = Vec::new();
+        state.serialize(&mut buf).unwrap();
+
+        let other = Vm::new(&kvm).unwrap();
+        other
+            .set_state(&VmState::deserialize(&mut &buf[..]).unwrap())
+            .unwrap();
+        let restored = other.state().unwrap();
+        assert_eq!(
+            pod::as_bytes(&state.ioapic),
+            pod::as_bytes(&restored.ioapic)
+        );
+        assert_eq!(state.pit.channels[0].mode, restored.pit.channels[0].mode);
+        assert!(restored.clock.clock >= state.clock.clock);
+    }
+
+    #[test]
+    fn configure_ap() {
+        let vm = new_test_vm();
+        let vcpu = Vcpu::new(&vm, 1).unwrap();
+        vcpu.configure_ap().unwrap();
+    }
+
+    #[test]
+    fn inject_irq() {
+        let vm = new_test_vm();
+        let line = vm.irq_line(4).unwrap();
+        assert_eq!(4, line.gsi());
+        line.trigger().unwrap();
+        vm.inject_irq(4).unwrap();
+        vm.inject_irq(5).unwrap();
+    }
+
+    #[test]
+    fn dirty_pages_includes_userspace_writes() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mut mem = MemoryMmap::new(4096 * 4).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+
+        mem.write(&[1, 2, 3], MemoryAddr(4096 * 2)).unwrap();
+        assert_eq!(vec![0b100], vm.dirty_pages(0).unwrap());
+        assert_eq!(vec![0], vm.dirty_pages(0).unwrap());
+    }
+
+    #[test]
+    fn readonly_region_slot() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mem = MemoryMmap::new(4096 * 4).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+
+        let rom = MemoryMmap::new(4096).unwrap();
+        let slot = vm
+            .add_readonly_region(MemoryAddr(0xffff_0000), &rom, &kvm)
+            .unwrap();
+        assert_eq!(1, slot);
+        match vm.dirty_pages(slot) {
+            Err(Error::ReadonlySlot(1)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn hotplug_overlap() {
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(&kvm).unwrap();
+        let mem = MemoryMmap::new(4096 * 4).unwrap();
+        vm.init_memory(&mem, &kvm).unwrap();
+
+        let extra = MemoryMmap::new(4096 * 4).unwrap();
+        match vm.hotplug_memory(MemoryAddr(4096), &extra) {
+            Err(Error::RegionOverlap) => (),
+            _ => panic!("expected overlap"),
+        }
+        let slot = vm.hotplug_memory(MemoryAddr(4096 * 4), &extra).unwrap();
+        assert_eq!(1, slot);
+
+        // Removed memory frees its range.
+        vm.remove_memory(slot).unwrap();
+        match vm.remove_memory(slot) {
+            Err(Error::InvalidSlot(1)) => (),
+            _ => panic!("expected invalid slot"),
+        }
+        vm.hotplug_memory(MemoryAddr(4096 * 4), &extra).unwrap();
+    }
+
+    #[test]
+    fn dirty_pages_invalid_slot() {
+        let vm = new_test_vm();
+        match vm.dirty_pages(3) {
+            Err(Error::InvalidSlot(3)) => (),
+            _ => panic!("expected invalid slot"),
+        }
     }
 }
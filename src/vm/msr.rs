@@ -0,0 +1,229 @@
+//! Model specific registers.
+
+use kvm_bindings::{kvm_msr_entry, kvm_msrs};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+pub const MSR_IA32_TSC: u32 = 0x0000_0010;
+pub const MSR_IA32_FEATURE_CONTROL: u32 = 0x0000_003a;
+pub const MSR_IA32_SYSENTER_CS: u32 = 0x0000_0174;
+pub const MSR_IA32_SYSENTER_ESP: u32 = 0x0000_0175;
+pub const MSR_IA32_SYSENTER_EIP: u32 = 0x0000_0176;
+pub const MSR_IA32_MISC_ENABLE: u32 = 0x0000_01a0;
+pub const MSR_MTRR_PHYS_BASE0: u32 = 0x0000_0200;
+pub const MSR_MTRR_FIX_64K_00000: u32 = 0x0000_0250;
+pub const MSR_MTRR_FIX_16K_80000: u32 = 0x0000_0258;
+pub const MSR_MTRR_FIX_16K_A0000: u32 = 0x0000_0259;
+pub const MSR_MTRR_FIX_4K_C0000: u32 = 0x0000_0268;
+pub const MSR_IA32_CR_PAT: u32 = 0x0000_0277;
+pub const MSR_MTRR_DEF_TYPE: u32 = 0x0000_02ff;
+pub const MSR_IA32_TSC_DEADLINE: u32 = 0x0000_06e0;
+pub const MSR_STAR: u32 = 0xc000_0081;
+pub const MSR_LSTAR: u32 = 0xc000_0082;
+pub const MSR_CSTAR: u32 = 0xc000_0083;
+pub const MSR_SYSCALL_MASK: u32 = 0xc000_0084;
+pub const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const MISC_ENABLE_FAST_STRING: u64 = 1 << 0;
+const MTRR_ENABLE: u64 = 1 << 11;
+const MTRR_MEM_TYPE_WB: u64 = 0x6;
+/// Number of variable range MTRRs KVM reports in MTRRcap.
+const MTRR_VARIABLE_RANGES: u32 = 8;
+/// Number of 4K fixed range MTRRs, covering 0xc0000 to 0x100000.
+const MTRR_FIX_4K_RANGES: u32 = 8;
+
+/// MSR values expected by linux when entering the kernel.
+pub fn boot_msr_entries() -> Vec<kvm_msr_entry> {
+    let entry = |index, data| kvm_msr_entry {
+        index,
+        data,
+        ..Default::default()
+    };
+    vec![
+        entry(MSR_IA32_SYSENTER_CS, 0),
+        entry(MSR_IA32_SYSENTER_ESP, 0),
+        entry(MSR_IA32_SYSENTER_EIP, 0),
+        entry(MSR_STAR, 0),
+        entry(MSR_CSTAR, 0),
+        entry(MSR_KERNEL_GS_BASE, 0),
+        entry(MSR_SYSCALL_MASK, 0),
+        entry(MSR_LSTAR, 0),
+        entry(MSR_IA32_TSC, 0),
+        entry(MSR_IA32_MISC_ENABLE, MISC_ENABLE_FAST_STRING),
+        entry(MSR_MTRR_DEF_TYPE, MTRR_ENABLE | MTRR_MEM_TYPE_WB),
+    ]
+}
+
+/// MSRs to save as part of a vcpu's state, given those reported by
+/// KVM_GET_MSR_INDEX_LIST. KVM leaves the MTRRs out of that list, so they're
+/// added here. Not all of the MSRs may be supported by a particular vcpu.
+///
+/// The indices are sorted, which also puts the TSC before the TSC deadline
+/// that depends on it.
+pub fn saved_msr_indices(kvm_indices: &[u32]) -> Vec<u32> {
+    let mut indices: Vec<_> = boot_msr_entries().iter().map(|e| e.index).collect();
+    indices.push(MSR_IA32_CR_PAT);
+    indices.push(MSR_IA32_TSC_DEADLINE);
+    // Each variable range has a base and mask register.
+    indices.extend(MSR_MTRR_PHYS_BASE0..MSR_MTRR_PHYS_BASE0 + 2 * MTRR_VARIABLE_RANGES);
+    indices.push(MSR_MTRR_FIX_64K_00000);
+    indices.push(MSR_MTRR_FIX_16K_80000);
+    indices.push(MSR_MTRR_FIX_16K_A0000);
+    indices.extend(MSR_MTRR_FIX_4K_C0000..MSR_MTRR_FIX_4K_C0000 + MTRR_FIX_4K_RANGES);
+    indices.extend_from_slice(kvm_indices);
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// MSRs emulated in userspace, with their fixed values.
+///
+/// FEATURE_CONTROL is locked with VMX disabled, as firmware would leave it,
+/// so the guest doesn't try to enable a feature it isn't offered.
+pub fn emulated_msrs() -> Vec<(u32, u64)> {
+    vec![(MSR_IA32_FEATURE_CONTROL, FEATURE_CONTROL_LOCKED)]
+}
+
+/// A kvm_msrs structure along with its trailing array of entries.
+pub struct MsrBuffer {
+    // Header followed by two words per entry. Using u64 keeps the entries
+    // correctly aligned.
+    buf: Vec<u64>,
+}
+
+impl MsrBuffer {
+    pub fn new(entries: &[kvm_msr_entry]) -> Self {
+        const WORDS_PER_ENTRY: usize = size_of::<kvm_msr_entry>() / size_of::<u64>();
+        let mut buf = vec![0; 1 + entries.len() * WORDS_PER_ENTRY];
+        buf[0] = entries.len() as u64;
+        let mut msrs = MsrBuffer { buf };
+        msrs.entries_mut().copy_from_slice(entries);
+        msrs
+    }
+
+    pub fn as_ptr(&self) -> *const kvm_msrs {
+        self.buf.as_ptr() as *const kvm_msrs
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut kvm_msrs {
+        self.buf.as_mut_ptr() as *mut kvm_msrs
+    }
+
+    pub fn len(&self) -> usize {
+        // nmsrs is the low half of the first word.
+        self.buf[0] as u32 as usize
+    }
+
+    pub fn entries(&self) -> &[kvm_msr_entry] {
+        unsafe { std::slice::from_raw_parts(self.buf[1..].as_ptr() as *const _, self.len()) }
+    }
+
+    pub fn entries_mut(&mut self) -> &mut [kvm_msr_entry] {
+        let len = self.len();
+        unsafe { std::slice::from_raw_parts_mut(self.buf[1..].as_mut_ptr() as *mut _, len) }
+    }
+}
+
+/// Emulates MSR accesses that have been routed to userspace with
+/// `Vm::filter_msrs`.
+pub trait MsrHandler: Send {
+    /// Read the MSR, returning `None` if it should fault.
+    fn read(&mut self, index: u32) -> Option<u64>;
+
+    /// Write the MSR, returning false if it should fault.
+    fn write(&mut self, index: u32, data: u64) -> bool;
+}
+
+/// A simple handler that stores written values and returns them on read.
+/// Accesses to MSRs it wasn't created with fault.
+pub struct MsrStore {
+    values: BTreeMap<u32, u64>,
+    read_only: bool,
+}
+
+impl MsrStore {
+    /// Create a store for the given MSRs with their initial values.
+    pub fn new(msrs: &[(u32, u64)]) -> Self {
+        MsrStore {
+            values: msrs.iter().cloned().collect(),
+            read_only: false,
+        }
+    }
+
+    /// Like `new`, but all writes fault. Having no state of its own, a
+    /// read-only store needn't be saved along with the vcpu.
+    pub fn read_only(msrs: &[(u32, u64)]) -> Self {
+        MsrStore {
+            read_only: true,
+            ..MsrStore::new(msrs)
+        }
+    }
+}
+
+impl MsrHandler for MsrStore {
+    fn read(&mut self, index: u32) -> Option<u64> {
+        self.values.get(&index).cloned()
+    }
+
+    fn write(&mut self, index: u32, data: u64) -> bool {
+        match self.values.get_mut(&index) {
+            Some(_) if self.read_only => false,
+            Some(v) => {
+                *v = data;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_layout() {
+        let msrs = MsrBuffer::new(&boot_msr_entries());
+        let header = unsafe { &*msrs.as_ptr() };
+        assert_eq!(boot_msr_entries().len() as u32, header.nmsrs);
+        assert_eq!(MSR_IA32_SYSENTER_CS, msrs.entries()[0].index);
+        assert_eq!(
+            MTRR_ENABLE | MTRR_MEM_TYPE_WB,
+            msrs.entries()[msrs.len() - 1].data
+        );
+    }
+
+    #[test]
+    fn saved_indices() {
+        let indices = saved_msr_indices(&[0x4b56_4d01, MSR_IA32_TSC, 0x3b]);
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        for index in [
+            MSR_IA32_TSC,
+            0x3b,
+            0x4b56_4d01,
+            MSR_MTRR_DEF_TYPE,
+            0x20f,
+            0x26f,
+        ]
+        .iter()
+        {
+            assert!(indices.contains(index));
+        }
+        assert!(!indices.contains(&0x210));
+    }
+
+    #[test]
+    fn store() {
+        let mut store = MsrStore::new(&[(0x10, 1)]);
+        assert_eq!(Some(1), store.read(0x10));
+        assert!(store.write(0x10, 5));
+        assert_eq!(Some(5), store.read(0x10));
+        assert_eq!(None, store.read(0x11));
+        assert!(!store.write(0x11, 5));
+
+        let mut store = MsrStore::read_only(&[(0x10, 1)]);
+        assert!(!store.write(0x10, 5));
+        assert_eq!(Some(1), store.read(0x10));
+    }
+}
@@ -0,0 +1,100 @@
+//! Raw serialization of plain old data, such as the state structures shared
+//! with KVM.
+//!
+//! Values are written in native byte order, so serialized state can only be
+//! read back on the same architecture.
+
+use kvm_bindings::*;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::slice;
+
+/// Types that may be viewed as raw bytes and created from any bytes.
+///
+/// Implementors must be `repr(C)`, contain no pointers or references, and be
+/// valid for every bit pattern.
+pub unsafe trait Pod: Copy + Default {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for kvm_regs {}
+unsafe impl Pod for kvm_sregs {}
+unsafe impl Pod for kvm_fpu {}
+unsafe impl Pod for kvm_msr_entry {}
+unsafe impl Pod for kvm_lapic_state {}
+unsafe impl Pod for kvm_xsave {}
+unsafe impl Pod for kvm_xcrs {}
+unsafe impl Pod for kvm_vcpu_events {}
+unsafe impl Pod for kvm_debugregs {}
+unsafe impl Pod for kvm_mp_state {}
+unsafe impl Pod for kvm_cpuid_entry2 {}
+unsafe impl Pod for kvm_irqchip {}
+unsafe impl Pod for kvm_pit_state2 {}
+unsafe impl Pod for kvm_clock_data {}
+
+pub fn as_bytes<T: Pod>(val: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T: Pod>(val: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(val as *mut T as *mut u8, size_of::<T>()) }
+}
+
+pub fn write<W: Write, T: Pod>(w: &mut W, val: &T) -> io::Result<()> {
+    w.write_all(as_bytes(val))
+}
+
+pub fn read<R: Read, T: Pod>(r: &mut R) -> io::Result<T> {
+    let mut val = T::default();
+    r.read_exact(as_bytes_mut(&mut val))?;
+    Ok(val)
+}
+
+/// Write a slice prefixed by its length.
+pub fn write_slice<W: Write, T: Pod>(w: &mut W, vals: &[T]) -> io::Result<()> {
+    write(w, &(vals.len() as u64))?;
+    for val in vals.iter() {
+        write(w, val)?;
+    }
+    Ok(())
+}
+
+/// Read a slice written by `write_slice`, failing if it has more than `max`
+/// elements.
+pub fn read_vec<R: Read, T: Pod>(r: &mut R, max: usize) -> io::Result<Vec<T>> {
+    let len: u64 = read(r)?;
+    if len > max as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("too many elements: {}", len),
+        ));
+    }
+    (0..len).map(|_| read(r)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let regs = kvm_regs {
+            rip: 0x1000,
+            r15: 7,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write(&mut buf, &regs).unwrap();
+        write_slice(&mut buf, &[1u32, 2, 3]).unwrap();
+        assert_eq!(size_of::<kvm_regs>() + 8 + 12, buf.len());
+
+        let mut r = &buf[..];
+        assert_eq!(regs, read::<_, kvm_regs>(&mut r).unwrap());
+        assert_eq!(vec![1u32, 2, 3], read_vec::<_, u32>(&mut r, 3).unwrap());
+        assert!(read::<_, u32>(&mut r).is_err());
+
+        let mut r = &buf[size_of::<kvm_regs>()..];
+        assert!(read_vec::<_, u32>(&mut r, 2).is_err());
+    }
+}
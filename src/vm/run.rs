@@ -0,0 +1,219 @@
+//! Access to the kvm_run structure shared with the kernel.
+//!
+//! kvm-ioctls doesn't know about newer exit reasons (and panics when it sees
+//! them), so vcpus are run and their exits decoded here instead.
+
+use super::exit::DebugExit;
+use kvm_bindings::*;
+use std::cell::Cell;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Once;
+
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+/// The msr member of the kvm_run exit union.
+#[repr(C)]
+pub struct MsrExit {
+    /// Set to non-zero to inject a #GP into the guest.
+    pub error: u8,
+    pad: [u8; 7],
+    pub reason: u32,
+    pub index: u32,
+    pub data: u64,
+}
+
+/// The reason for a vcpu exit, along with any data associated with it.
+#[derive(Debug)]
+pub enum KvmExit<'a> {
+    IoIn(u16, &'a mut [u8]),
+    IoOut(u16, &'a [u8]),
+    MmioRead(u64, &'a mut [u8]),
+    MmioWrite(u64, &'a [u8]),
+    Hlt,
+    Shutdown,
+    Debug(DebugExit),
+    SystemEvent(u32, u64),
+    RdMsr(&'a mut MsrExit),
+    WrMsr(&'a mut MsrExit),
+    FailEntry(u64),
+    InternalError(u32),
+    Other(u32),
+}
+
+impl std::fmt::Debug for MsrExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "msr {:#x} data {:#x}", self.index, self.data)
+    }
+}
+
+extern "C" {
+    // SIGRTMIN isn't a constant since glibc reserves some real-time signals
+    // for itself.
+    fn __libc_current_sigrtmin() -> c_int;
+}
+
+thread_local! {
+    /// The kvm_run structure of the vcpu running on this thread, if any.
+    static CURRENT: Cell<*mut kvm_run> = Cell::new(ptr::null_mut());
+}
+
+/// The signal used to kick vcpu threads out of KVM_RUN.
+pub fn kick_signal() -> c_int {
+    unsafe { __libc_current_sigrtmin() }
+}
+
+extern "C" fn handle_kick(_signum: c_int) {
+    // If the signal arrives while the vcpu is outside KVM_RUN, this makes the
+    // next KVM_RUN return immediately instead of the kick being lost.
+    let run = CURRENT.with(|c| c.get());
+    if !run.is_null() {
+        unsafe { ptr::write_volatile(&mut (*run).immediate_exit, 1) };
+    }
+}
+
+/// Install the handler for `kick_signal`. Safe to call more than once.
+pub fn register_kick_handler() -> io::Result<()> {
+    static REGISTER: Once = Once::new();
+    let mut result = Ok(());
+    REGISTER.call_once(|| unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = handle_kick as extern "C" fn(c_int) as usize;
+        // No SA_RESTART, so KVM_RUN fails with EINTR.
+        act.sa_flags = 0;
+        libc::sigemptyset(&mut act.sa_mask);
+        if libc::sigaction(kick_signal(), &act, ptr::null_mut()) < 0 {
+            result = Err(io::Error::last_os_error());
+        }
+    });
+    result
+}
+
+/// A vcpu's mapping of its kvm_run structure.
+pub struct KvmRun {
+    run: *mut kvm_run,
+    size: usize,
+}
+
+impl KvmRun {
+    pub fn mmap<F: AsRawFd>(vcpu: &F, size: usize) -> io::Result<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(KvmRun {
+            run: addr as *mut kvm_run,
+            size,
+        })
+    }
+
+    /// Make this the target of kicks delivered to the current thread. Must be
+    /// called from the thread that will run the vcpu.
+    pub fn set_current(&self) {
+        CURRENT.with(|c| c.set(self.run));
+    }
+
+    /// Make the next KVM_RUN return without entering the guest, as if
+    /// kicked.
+    pub fn set_immediate_exit(&mut self) {
+        unsafe { ptr::write_volatile(&mut (*self.run).immediate_exit, 1) };
+    }
+
+    /// Clear a pending kick, allowing KVM_RUN to enter the guest again.
+    pub fn clear_immediate_exit(&mut self) {
+        unsafe { ptr::write_volatile(&mut (*self.run).immediate_exit, 0) };
+    }
+
+    fn as_mut(&mut self) -> &mut kvm_run {
+        unsafe { &mut *self.run }
+    }
+
+    /// Decode the exit reason left by the last KVM_RUN.
+    pub fn exit(&mut self) -> KvmExit<'_> {
+        let run_start = self.run as *mut u8;
+        let run = self.as_mut();
+        match run.exit_reason {
+            KVM_EXIT_IO => {
+                let io = unsafe { run.__bindgen_anon_1.io };
+                let size = io.count as usize * io.size as usize;
+                // The data lives within the mapping, at an offset given by the
+                // kernel.
+                let data = unsafe {
+                    std::slice::from_raw_parts_mut(run_start.add(io.data_offset as usize), size)
+                };
+                if u32::from(io.direction) == KVM_EXIT_IO_IN {
+                    KvmExit::IoIn(io.port, data)
+                } else {
+                    KvmExit::IoOut(io.port, data)
+                }
+            }
+            KVM_EXIT_MMIO => {
+                let mmio = unsafe { &mut run.__bindgen_anon_1.mmio };
+                let len = mmio.len as usize;
+                if mmio.is_write != 0 {
+                    KvmExit::MmioWrite(mmio.phys_addr, &mmio.data[..len])
+                } else {
+                    KvmExit::MmioRead(mmio.phys_addr, &mut mmio.data[..len])
+                }
+            }
+            KVM_EXIT_HLT => KvmExit::Hlt,
+            KVM_EXIT_SHUTDOWN => KvmExit::Shutdown,
+            KVM_EXIT_DEBUG => {
+                let debug = unsafe { run.__bindgen_anon_1.debug.arch };
+                KvmExit::Debug(DebugExit {
+                    exception: debug.exception,
+                    pc: debug.pc,
+                    dr6: debug.dr6,
+                    dr7: debug.dr7,
+                })
+            }
+            KVM_EXIT_SYSTEM_EVENT => {
+                let event = unsafe { run.__bindgen_anon_1.system_event };
+                KvmExit::SystemEvent(event.type_, event.flags)
+            }
+            KVM_EXIT_X86_RDMSR | KVM_EXIT_X86_WRMSR => {
+                let msr = unsafe { &mut *(&mut run.__bindgen_anon_1 as *mut _ as *mut MsrExit) };
+                if run.exit_reason == KVM_EXIT_X86_RDMSR {
+                    KvmExit::RdMsr(msr)
+                } else {
+                    KvmExit::WrMsr(msr)
+                }
+            }
+            KVM_EXIT_FAIL_ENTRY => {
+                let fail = unsafe { run.__bindgen_anon_1.fail_entry };
+                KvmExit::FailEntry(fail.hardware_entry_failure_reason)
+            }
+            KVM_EXIT_INTERNAL_ERROR => {
+                let internal = unsafe { run.__bindgen_anon_1.internal };
+                KvmExit::InternalError(internal.suberror)
+            }
+            r => KvmExit::Other(r),
+        }
+    }
+}
+
+impl Drop for KvmRun {
+    fn drop(&mut self) {
+        let run = self.run;
+        CURRENT.with(|c| {
+            if c.get() == run {
+                c.set(ptr::null_mut());
+            }
+        });
+        unsafe {
+            libc::munmap(self.run as *mut libc::c_void, self.size);
+        }
+    }
+}
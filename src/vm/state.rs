@@ -0,0 +1,255 @@
+//! Saved state of vcpus and the vm.
+
+use super::pod;
+use kvm_bindings::*;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Identifies the serialized vcpu state format.
+const STATE_VERSION: u32 = 1;
+
+/// Upper bound on the number of msrs accepted when deserializing.
+const MAX_MSRS: usize = 256;
+
+/// State of the in-kernel devices, and the guest clock.
+#[derive(Clone)]
+pub struct VmState {
+    pub pic_master: kvm_irqchip,
+    pub pic_slave: kvm_irqchip,
+    pub ioapic: kvm_irqchip,
+    pub pit: kvm_pit_state2,
+    pub clock: kvm_clock_data,
+}
+
+impl VmState {
+    pub fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        pod::write(w, &STATE_VERSION)?;
+        pod::write(w, &self.pic_master)?;
+        pod::write(w, &self.pic_slave)?;
+        pod::write(w, &self.ioapic)?;
+        pod::write(w, &self.pit)?;
+        pod::write(w, &self.clock)?;
+        Ok(())
+    }
+
+    pub fn deserialize<R: Read>(r: &mut R) -> io::Result<Self> {
+        read_version(r)?;
+        Ok(VmState {
+            pic_master: pod::read(r)?,
+            pic_slave: pod::read(r)?,
+            ioapic: pod::read(r)?,
+            pit: pod::read(r)?,
+            clock: pod::read(r)?,
+        })
+    }
+}
+
+fn read_version<R: Read>(r: &mut R) -> io::Result<()> {
+    let version: u32 = pod::read(r)?;
+    if version != STATE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported state version: {}", version),
+        ));
+    }
+    Ok(())
+}
+
+/// Everything needed to save and restore a vcpu, other than its cpuid.
+#[derive(Clone)]
+pub struct VcpuState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub fpu: kvm_fpu,
+    pub msrs: Vec<kvm_msr_entry>,
+    pub lapic: kvm_lapic_state,
+    pub xsave: kvm_xsave,
+    pub xcrs: kvm_xcrs,
+    pub events: kvm_vcpu_events,
+    pub debugregs: kvm_debugregs,
+    pub mp_state: kvm_mp_state,
+}
+
+impl VcpuState {
+    pub fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        pod::write(w, &STATE_VERSION)?;
+        pod::write(w, &self.regs)?;
+        pod::write(w, &self.sregs)?;
+        pod::write(w, &self.fpu)?;
+        pod::write_slice(w, &self.msrs)?;
+        pod::write(w, &self.lapic)?;
+        pod::write(w, &self.xsave)?;
+        pod::write(w, &self.xcrs)?;
+        pod::write(w, &self.events)?;
+        pod::write(w, &self.debugregs)?;
+        pod::write(w, &self.mp_state)?;
+        Ok(())
+    }
+
+    pub fn deserialize<R: Read>(r: &mut R) -> io::Result<Self> {
+        read_version(r)?;
+        Ok(VcpuState {
+            regs: pod::read(r)?,
+            sregs: pod::read(r)?,
+            fpu: pod::read(r)?,
+            msrs: pod::read_vec(r, MAX_MSRS)?,
+            lapic: pod::read(r)?,
+            xsave: pod::read(r)?,
+            xcrs: pod::read(r)?,
+            events: pod::read(r)?,
+            debugregs: pod::read(r)?,
+            mp_state: pod::read(r)?,
+        })
+    }
+}
+
+fn fmt_segment(f: &mut fmt::Formatter, name: &str, seg: &kvm_segment) -> fmt::Result {
+    writeln!(
+        f,
+        "{:<4} sel={:#06x} base={:#018x} limit={:#010x} type={:#x} p={} dpl={} db={} s={} l={} g={}",
+        name,
+        seg.selector,
+        seg.base,
+        seg.limit,
+        seg.type_,
+        seg.present,
+        seg.dpl,
+        seg.db,
+        seg.s,
+        seg.l,
+        seg.g
+    )
+}
+
+/// Multi-line dump of the state, for debugging crashed guests.
+impl fmt::Display for VcpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.regs;
+        writeln!(
+            f,
+            "rip={:#018x} rsp={:#018x} rflags={:#010x}",
+            r.rip, r.rsp, r.rflags
+        )?;
+        writeln!(
+            f,
+            "rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "rsi={:#018x} rdi={:#018x} rbp={:#018x}",
+            r.rsi, r.rdi, r.rbp
+        )?;
+        writeln!(
+            f,
+            "r8 ={:#018x} r9 ={:#018x} r10={:#018x} r11={:#018x}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "r12={:#018x} r13={:#018x} r14={:#018x} r15={:#018x}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+
+        let s = &self.sregs;
+        writeln!(
+            f,
+            "cr0={:#018x} cr2={:#018x} cr3={:#018x} cr4={:#018x} cr8={:#x}",
+            s.cr0, s.cr2, s.cr3, s.cr4, s.cr8
+        )?;
+        writeln!(f, "efer={:#x} apic_base={:#x}", s.efer, s.apic_base)?;
+        fmt_segment(f, "cs", &s.cs)?;
+        fmt_segment(f, "ds", &s.ds)?;
+        fmt_segment(f, "es", &s.es)?;
+        fmt_segment(f, "fs", &s.fs)?;
+        fmt_segment(f, "gs", &s.gs)?;
+        fmt_segment(f, "ss", &s.ss)?;
+        fmt_segment(f, "tr", &s.tr)?;
+        fmt_segment(f, "ldt", &s.ldt)?;
+        writeln!(
+            f,
+            "gdt base={:#018x} limit={:#06x} idt base={:#018x} limit={:#06x}",
+            s.gdt.base, s.gdt.limit, s.idt.base, s.idt.limit
+        )?;
+
+        let d = &self.debugregs;
+        writeln!(
+            f,
+            "dr0={:#x} dr1={:#x} dr2={:#x} dr3={:#x} dr6={:#x} dr7={:#x}",
+            d.db[0], d.db[1], d.db[2], d.db[3], d.dr6, d.dr7
+        )?;
+
+        let e = &self.events;
+        writeln!(
+            f,
+            "mp_state={} exception(injected={} pending={} nr={} error_code={:#x}) \
+             interrupt(injected={} nr={}) nmi(injected={} pending={} masked={})",
+            self.mp_state.mp_state,
+            e.exception.injected,
+            e.exception.pending,
+            e.exception.nr,
+            e.exception.error_code,
+            e.interrupt.injected,
+            e.interrupt.nr,
+            e.nmi.injected,
+            e.nmi.pending,
+            e.nmi.masked
+        )?;
+        writeln!(
+            f,
+            "fcw={:#x} fsw={:#x} mxcsr={:#x} xcr0={:#x}",
+            self.fpu.fcw, self.fpu.fsw, self.fpu.mxcsr, self.xcrs.xcrs[0].value
+        )?;
+        for msr in self.msrs.iter() {
+            writeln!(f, "msr {:#010x}={:#x}", msr.index, msr.data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> VcpuState {
+        VcpuState {
+            regs: kvm_regs {
+                rip: 0xfff0,
+                ..Default::default()
+            },
+            sregs: Default::default(),
+            fpu: Default::default(),
+            msrs: vec![kvm_msr_entry {
+                index: 0x10,
+                data: 5,
+                ..Default::default()
+            }],
+            lapic: Default::default(),
+            xsave: Default::default(),
+            xcrs: Default::default(),
+            events: Default::default(),
+            debugregs: Default::default(),
+            mp_state: Default::default(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let state = test_state();
+        let mut buf = Vec::new();
+        state.serialize(&mut buf).unwrap();
+        let restored = VcpuState::deserialize(&mut &buf[..]).unwrap();
+        assert_eq!(state.regs, restored.regs);
+        assert_eq!(state.msrs, restored.msrs);
+
+        buf[0] = 0xff;
+        assert!(VcpuState::deserialize(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let dump = test_state().to_string();
+        assert!(dump.contains("rip=0x000000000000fff0"));
+        assert!(dump.contains("msr 0x00000010=0x5"));
+    }
+}
@@ -0,0 +1,490 @@
+//! ELF core dumps of a guest, for analysis with `crash` or gdb.
+//!
+//! The dump follows the layout of QEMU's `dump-guest-memory`: a PT_NOTE
+//! segment holding an NT_PRSTATUS note and a QEMU cpu state note for every
+//! vcpu, followed by a PT_LOAD segment for every region of guest memory,
+//! addressed by guest physical address.
+
+use crate::memory::memorymap::MemoryMmap;
+use crate::memory::{Addressable, Error as MemError, MemoryAddr, Region};
+use crate::vm::pod::{self, Pod};
+use kvm_bindings::{kvm_regs, kvm_segment, kvm_sregs};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Memory(MemError),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EV_CURRENT: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+/// Type of QEMU's cpu state notes, which are told apart by their name.
+const NT_QEMU: u32 = 0;
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+
+/// Memory segments start page aligned in the file.
+const PAGE_SIZE: usize = 4096;
+
+/// A vcpu's registers at the time of the dump.
+pub struct CpuState {
+    pub id: u8,
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub kernel_gs_base: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+/// struct elf_prstatus from linux/elfcore.h, for x86-64.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ElfPrstatus {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+    pr_cursig: i16,
+    _pad0: i16,
+    pr_sigpend: u64,
+    pr_sighold: u64,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    /// pr_utime, pr_stime, pr_cutime and pr_cstime.
+    pr_times: [u64; 8],
+    /// struct user_regs_struct.
+    pr_reg: [u64; 27],
+    pr_fpvalid: i32,
+    _pad1: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct QemuCpuSegment {
+    selector: u32,
+    limit: u32,
+    flags: u32,
+    _pad: u32,
+    base: u64,
+}
+
+/// QEMUCPUState from QEMU's target/i386/arch_dump.c.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct QemuCpuState {
+    version: u32,
+    size: u32,
+    /// rax, rbx, rcx, rdx, rsi, rdi, rsp, rbp, r8-r15
+    gprs: [u64; 16],
+    rip: u64,
+    rflags: u64,
+    /// cs, ds, es, fs, gs, ss, ldt, tr, gdt, idt
+    segments: [QemuCpuSegment; 10],
+    cr: [u64; 5],
+    kernel_gs_base: u64,
+}
+
+unsafe impl Pod for Elf64Ehdr {}
+unsafe impl Pod for Elf64Phdr {}
+unsafe impl Pod for Elf64Nhdr {}
+unsafe impl Pod for ElfPrstatus {}
+unsafe impl Pod for QemuCpuState {}
+
+impl ElfPrstatus {
+    fn new(cpu: &CpuState) -> Self {
+        let r = &cpu.regs;
+        let s = &cpu.sregs;
+        ElfPrstatus {
+            pr_pid: i32::from(cpu.id) + 1,
+            pr_reg: [
+                r.r15,
+                r.r14,
+                r.r13,
+                r.r12,
+                r.rbp,
+                r.rbx,
+                r.r11,
+                r.r10,
+                r.r9,
+                r.r8,
+                r.rax,
+                r.rcx,
+                r.rdx,
+                r.rsi,
+                r.rdi,
+                r.rax,
+                r.rip,
+                u64::from(s.cs.selector),
+                r.rflags,
+                r.rsp,
+                u64::from(s.ss.selector),
+                s.fs.base,
+                s.gs.base,
+                u64::from(s.ds.selector),
+                u64::from(s.es.selector),
+                u64::from(s.fs.selector),
+                u64::from(s.gs.selector),
+            ],
+            ..Default::default()
+        }
+    }
+}
+
+impl QemuCpuSegment {
+    fn new(seg: &kvm_segment) -> Self {
+        // Attributes sit where they do in the high word of a descriptor.
+        let flags = u32::from(seg.type_) << 8
+            | u32::from(seg.s) << 12
+            | u32::from(seg.dpl) << 13
+            | u32::from(seg.present) << 15
+            | u32::from(seg.avl) << 20
+            | u32::from(seg.l) << 21
+            | u32::from(seg.db) << 22
+            | u32::from(seg.g) << 23;
+        QemuCpuSegment {
+            selector: u32::from(seg.selector),
+            limit: seg.limit,
+            flags,
+            _pad: 0,
+            base: seg.base,
+        }
+    }
+
+    fn table(base: u64, limit: u16) -> Self {
+        QemuCpuSegment {
+            limit: u32::from(limit),
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+impl QemuCpuState {
+    fn new(cpu: &CpuState) -> Self {
+        let r = &cpu.regs;
+        let s = &cpu.sregs;
+        QemuCpuState {
+            version: QEMU_CPU_STATE_VERSION,
+            size: size_of::<QemuCpuState>() as u32,
+            gprs: [
+                r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rsp, r.rbp, r.r8, r.r9, r.r10, r.r11,
+                r.r12, r.r13, r.r14, r.r15,
+            ],
+            rip: r.rip,
+            rflags: r.rflags,
+            segments: [
+                QemuCpuSegment::new(&s.cs),
+                QemuCpuSegment::new(&s.ds),
+                QemuCpuSegment::new(&s.es),
+                QemuCpuSegment::new(&s.fs),
+                QemuCpuSegment::new(&s.gs),
+                QemuCpuSegment::new(&s.ss),
+                QemuCpuSegment::new(&s.ldt),
+                QemuCpuSegment::new(&s.tr),
+                QemuCpuSegment::table(s.gdt.base, s.gdt.limit),
+                QemuCpuSegment::table(s.idt.base, s.idt.limit),
+            ],
+            cr: [s.cr0, 0, s.cr2, s.cr3, s.cr4],
+            kernel_gs_base: cpu.kernel_gs_base,
+        }
+    }
+}
+
+/// Round up to a multiple of 4, as note names and descriptions are padded.
+fn note_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn note_size<T: Pod>(name: &[u8]) -> usize {
+    size_of::<Elf64Nhdr>() + note_align(name.len() + 1) + note_align(size_of::<T>())
+}
+
+fn write_note<W: Write, T: Pod>(w: &mut W, name: &[u8], n_type: u32, desc: &T) -> io::Result<()> {
+    let hdr = Elf64Nhdr {
+        n_namesz: name.len() as u32 + 1,
+        n_descsz: size_of::<T>() as u32,
+        n_type,
+    };
+    pod::write(w, &hdr)?;
+    let mut padded = name.to_vec();
+    padded.resize(note_align(name.len() + 1), 0);
+    w.write_all(&padded)?;
+    pod::write(w, desc)?;
+    let padding = note_align(size_of::<T>()) - size_of::<T>();
+    w.write_all(&[0; 4][..padding])
+}
+
+/// Write a core dump of the vcpus and guest memory, where each region of
+/// memory is given with its guest physical address.
+pub fn write<W: Write>(
+    w: &mut W,
+    cpus: &[CpuState],
+    regions: &[(MemoryAddr, &MemoryMmap)],
+) -> Result<()> {
+    let phnum = 1 + regions.len();
+    let notes_offset = size_of::<Elf64Ehdr>() + phnum * size_of::<Elf64Phdr>();
+    let notes_len =
+        cpus.len() * (note_size::<ElfPrstatus>(b"CORE") + note_size::<QemuCpuState>(b"QEMU"));
+    let data_offset = (notes_offset + notes_len).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    let mut ident = [0; 16];
+    ident[..4].copy_from_slice(b"\x7fELF");
+    ident[4] = ELFCLASS64;
+    ident[5] = ELFDATA2LSB;
+    ident[6] = EV_CURRENT;
+    let ehdr = Elf64Ehdr {
+        e_ident: ident,
+        e_type: ET_CORE,
+        e_machine: EM_X86_64,
+        e_version: u32::from(EV_CURRENT),
+        e_phoff: size_of::<Elf64Ehdr>() as u64,
+        e_ehsize: size_of::<Elf64Ehdr>() as u16,
+        e_phentsize: size_of::<Elf64Phdr>() as u16,
+        e_phnum: phnum as u16,
+        ..Default::default()
+    };
+    pod::write(w, &ehdr)?;
+
+    pod::write(
+        w,
+        &Elf64Phdr {
+            p_type: PT_NOTE,
+            p_offset: notes_offset as u64,
+            p_filesz: notes_len as u64,
+            p_memsz: notes_len as u64,
+            ..Default::default()
+        },
+    )?;
+    let mut offset = data_offset as u64;
+    for (addr, mem) in regions.iter() {
+        let len = mem.len() as u64;
+        pod::write(
+            w,
+            &Elf64Phdr {
+                p_type: PT_LOAD,
+                p_flags: PF_R | PF_W | PF_X,
+                p_offset: offset,
+                p_paddr: addr.0 as u64,
+                p_filesz: len,
+                p_memsz: len,
+                ..Default::default()
+            },
+        )?;
+        offset += len;
+    }
+
+    for cpu in cpus.iter() {
+        write_note(w, b"CORE", NT_PRSTATUS, &ElfPrstatus::new(cpu))?;
+        write_note(w, b"QEMU", NT_QEMU, &QemuCpuState::new(cpu))?;
+    }
+    w.write_all(&vec![0; data_offset - notes_offset - notes_len])?;
+
+    let mut page = vec![0; PAGE_SIZE];
+    for (_, mem) in regions.iter() {
+        for offset in (0..mem.len()).step_by(PAGE_SIZE) {
+            let n = mem
+                .read(&mut page, MemoryAddr(offset))
+                .map_err(Error::Memory)?;
+            w.write_all(&page[..n])?;
+        }
+    }
+    Ok(())
+}
+
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_dump_signal(_signum: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Request a core dump when the process receives SIGUSR1, see
+/// `dump_requested`. Safe to call more than once.
+pub fn register_signal() -> io::Result<()> {
+    static REGISTER: Once = Once::new();
+    let mut result = Ok(());
+    REGISTER.call_once(|| unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = handle_dump_signal as extern "C" fn(libc::c_int) as usize;
+        act.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut act.sa_mask);
+        if libc::sigaction(libc::SIGUSR1, &act, std::ptr::null_mut()) < 0 {
+            result = Err(io::Error::last_os_error());
+        }
+    });
+    result
+}
+
+/// Request a core dump, as if the signal was received.
+pub fn request_dump() {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Check for, and clear, a pending request for a core dump.
+pub fn dump_requested() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(b: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([b[i], b[i + 1]])
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        let mut v = [0; 4];
+        v.copy_from_slice(&b[i..i + 4]);
+        u32::from_le_bytes(v)
+    }
+
+    fn u64_at(b: &[u8], i: usize) -> u64 {
+        let mut v = [0; 8];
+        v.copy_from_slice(&b[i..i + 8]);
+        u64::from_le_bytes(v)
+    }
+
+    #[test]
+    fn struct_sizes() {
+        assert_eq!(64, size_of::<Elf64Ehdr>());
+        assert_eq!(56, size_of::<Elf64Phdr>());
+        assert_eq!(336, size_of::<ElfPrstatus>());
+        assert_eq!(440, size_of::<QemuCpuState>());
+    }
+
+    #[test]
+    fn layout() {
+        let mut low = MemoryMmap::new(2 * PAGE_SIZE).unwrap();
+        low.write(b"low", MemoryAddr(0x1000)).unwrap();
+        let mut high = MemoryMmap::new(PAGE_SIZE).unwrap();
+        high.write(b"high", MemoryAddr(0x10)).unwrap();
+        let cpus: Vec<_> = (0..2)
+            .map(|id| CpuState {
+                id,
+                regs: kvm_regs {
+                    rip: 0xffff_ffff_8100_0000 + u64::from(id),
+                    ..Default::default()
+                },
+                sregs: kvm_sregs {
+                    cr3: 0x1000,
+                    ..Default::default()
+                },
+                kernel_gs_base: 0,
+            })
+            .collect();
+        let mut core = Vec::new();
+        write(
+            &mut core,
+            &cpus,
+            &[(MemoryAddr(0), &low), (MemoryAddr(0x1_0000_0000), &high)],
+        )
+        .unwrap();
+
+        assert_eq!(b"\x7fELF", &core[..4]);
+        assert_eq!(ET_CORE, u16_at(&core, 16));
+        assert_eq!(EM_X86_64, u16_at(&core, 18));
+        assert_eq!(3, u16_at(&core, 56));
+
+        let phdr = |i: usize| &core[64 + i * 56..64 + (i + 1) * 56];
+        assert_eq!(PT_NOTE, u32_at(phdr(0), 0));
+        let notes = u64_at(phdr(0), 8) as usize;
+        let notes_len = u64_at(phdr(0), 32) as usize;
+        // Both notes for the second vcpu, with its pid and rip.
+        let second = notes + notes_len / 2;
+        assert_eq!(NT_PRSTATUS, u32_at(&core, second + 8));
+        assert_eq!(b"CORE\0", &core[second + 12..second + 17]);
+        let prstatus = second + 20;
+        assert_eq!(2, u32_at(&core, prstatus + 32));
+        assert_eq!(
+            0xffff_ffff_8100_0001,
+            u64_at(&core, prstatus + 112 + 16 * 8)
+        );
+        let qemu = prstatus + 336;
+        assert_eq!(b"QEMU\0", &core[qemu + 12..qemu + 17]);
+        // cr3, after the header, registers and segments.
+        assert_eq!(0x1000, u64_at(&core, qemu + 20 + 8 + 18 * 8 + 240 + 3 * 8));
+
+        for (i, (addr, data)) in [(0x1000u64, &b"low"[..]), (0x1_0000_0010, b"high")]
+            .iter()
+            .enumerate()
+        {
+            let phdr = phdr(i + 1);
+            assert_eq!(PT_LOAD, u32_at(phdr, 0));
+            let offset = u64_at(phdr, 8) as usize;
+            let paddr = u64_at(phdr, 24);
+            assert_eq!(0, offset % PAGE_SIZE);
+            let start = offset + (addr - paddr) as usize;
+            assert_eq!(*data, &core[start..start + data.len()]);
+        }
+        assert_eq!(PAGE_SIZE * 4, core.len());
+    }
+
+    #[test]
+    fn requests() {
+        assert!(!dump_requested());
+        request_dump();
+        assert!(dump_requested());
+        assert!(!dump_requested());
+    }
+}